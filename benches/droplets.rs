@@ -0,0 +1,47 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use glam::Vec2;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use terrain_gen::config::TerrainConfig;
+use terrain_gen::terrain::{
+    step_parallel, step_sequential, Droplet, Elevation, ErosionParams, Meander,
+};
+
+// one step of 10000 droplets on a 512 x 512 terrain, one thread against all of them
+fn droplets(c: &mut Criterion) {
+    let config = TerrainConfig::new(512);
+    let noise = config.noise.build(0, &config.fractal);
+    let elevation = Elevation::new(&config, noise.as_ref(), 0);
+    let (params, meander) = (ErosionParams::default(), Meander::default());
+    let rain = || {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..10_000)
+            .map(|order| {
+                let pos = Vec2::new(rng.gen::<f32>() * 512., rng.gen::<f32>() * 512.);
+                Droplet::new(pos, order)
+            })
+            .collect::<Vec<Droplet>>()
+    };
+    c.bench_function("step sequential", |b| {
+        b.iter_batched(
+            rain,
+            |mut droplets| {
+                let mut refs: Vec<&mut Droplet> = droplets.iter_mut().collect();
+                step_sequential(&mut refs, &elevation, &params, &meander, 0.)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    c.bench_function("step parallel", |b| {
+        b.iter_batched(
+            rain,
+            |mut droplets| {
+                let mut refs: Vec<&mut Droplet> = droplets.iter_mut().collect();
+                step_parallel(&mut refs, &elevation, &params, &meander, 0.)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, droplets);
+criterion_main!(benches);
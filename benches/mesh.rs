@@ -0,0 +1,82 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use glam::Vec2;
+use terrain_gen::config::TerrainConfig;
+use terrain_gen::mesh::{compute_normals, mesh_positions, Chunk};
+use terrain_gen::terrain::{Elevation, Kernel};
+
+fn terrain() -> Elevation {
+    let config = TerrainConfig::new(512);
+    let noise = config.noise.build(0, &config.fractal);
+    Elevation::new(&config, noise.as_ref(), 0)
+}
+
+// the 3D view on a 512 x 512 terrain split in the default 128 x 128 chunks: rebuilding
+// every vertex, checking the chunks on a frame where nothing changed once the erosion
+// settled, and rebuilding the chunks a single deposit touched
+fn redraw(c: &mut Criterion) {
+    let mut elevation = terrain();
+    let chunks = Chunk::split(512, 128);
+    c.bench_function("rebuild every vertex", |b| {
+        b.iter(|| {
+            (
+                mesh_positions(&elevation, 0., 60.),
+                compute_normals(&elevation, 0., 60.),
+            )
+        })
+    });
+    let drawn = elevation.edits();
+    c.bench_function("idle frame", |b| {
+        b.iter(|| {
+            chunks
+                .iter()
+                .filter(|chunk| chunk.touched_since(&elevation, drawn))
+                .count()
+        })
+    });
+    elevation.deposit(Vec2::new(300., 200.), 0.01, &Kernel::default());
+    c.bench_function("rebuild the chunks of a deposit", |b| {
+        b.iter(|| {
+            chunks
+                .iter()
+                .filter(|chunk| chunk.touched_since(&elevation, drawn))
+                .map(|chunk| chunk.vertices(&elevation, 0., 60., 3.))
+                .collect::<Vec<_>>()
+        })
+    });
+}
+
+// every chunk built in full against every chunk built with every 8th cell, as they are
+// once the perspective camera is pulled back far enough
+fn lod(c: &mut Criterion) {
+    let elevation = terrain();
+    let at_stride = |stride: usize| -> Vec<Chunk> {
+        Chunk::split(512, 128)
+            .into_iter()
+            .map(|chunk| Chunk { stride, ..chunk })
+            .collect()
+    };
+    for stride in [1, 8].iter() {
+        let chunks = at_stride(*stride);
+        let vertices: usize = chunks
+            .iter()
+            .map(|chunk| chunk.vertex_cells(512).len())
+            .sum();
+        println!("stride {}: {} vertices", stride, vertices);
+        c.bench_function(&format!("build the chunks at stride {}", stride), |b| {
+            b.iter(|| {
+                chunks
+                    .iter()
+                    .map(|chunk| {
+                        (
+                            chunk.vertices(&elevation, 0., 60., 3.),
+                            chunk.indices(elevation.grid),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+        });
+    }
+}
+
+criterion_group!(benches, redraw, lod);
+criterion_main!(benches);
@@ -0,0 +1,180 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::env;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::str::FromStr;
+use terrain_gen::biome::BiomeThresholds;
+use terrain_gen::config::{DrawConfig, Seed, TerrainConfig};
+use terrain_gen::palette::Palette;
+use terrain_gen::presets::Preset;
+use terrain_gen::terrain::{ErosionParams, FractalParams, IslandShape, Meander, NoiseKind};
+
+// returns the value following `name` on the command line, if any
+pub fn arg<T: FromStr>(name: &str) -> Option<T>
+where
+    T::Err: Debug,
+{
+    args(name).into_iter().next()
+}
+
+// returns the values following every occurrence of `name` on the command line
+pub fn args<T: FromStr>(name: &str) -> Vec<T>
+where
+    T::Err: Debug,
+{
+    let args: Vec<String> = env::args().collect();
+    args.windows(2)
+        .filter(|w| w[0] == name)
+        .map(|w| {
+            w[1].parse()
+                .unwrap_or_else(|e| panic!("invalid value {:?} for {}: {:?}", w[1], name, e))
+        })
+        .collect()
+}
+
+// whether `name` is present on the command line
+pub fn flag(name: &str) -> bool {
+    env::args().any(|a| a == name)
+}
+
+// the library's configurations read from the command line, the library itself never reads it
+pub trait FromArgs {
+    fn from_args() -> Self;
+}
+
+impl FromArgs for Seed {
+    // `--seed`, or a seed from the OS entropy when it's missing, the only random draw
+    // that doesn't come from a Seed
+    fn from_args() -> Self {
+        let seed = Seed(arg("--seed").unwrap_or_else(|| StdRng::from_entropy().gen()));
+        println!("seed {}", seed.0);
+        seed
+    }
+}
+
+impl FromArgs for TerrainConfig {
+    fn from_args() -> Self {
+        let default = TerrainConfig::default();
+        let mut config = TerrainConfig {
+            size: arg("--size").unwrap_or(default.size).max(2),
+            grid: arg("--grid").unwrap_or(default.grid),
+            topology: arg("--topology").unwrap_or(default.topology),
+            noise: arg("--noise").unwrap_or(NoiseKind::Fbm),
+            fractal: FractalParams {
+                octaves: arg("--octaves"),
+                frequency: arg("--frequency"),
+                lacunarity: arg("--lacunarity"),
+                persistence: arg("--persistence"),
+            },
+            sea_level: arg("--sea-level").unwrap_or(default.sea_level),
+            island: IslandShape::from_args(),
+            warp_strength: arg("--warp-strength").unwrap_or(default.warp_strength),
+            beaches: arg::<f32>("--beaches").filter(|width| *width > 0.),
+            hardness: arg("--hardness").unwrap_or(default.hardness),
+            precipitation: arg("--precipitation").unwrap_or(default.precipitation),
+            rain_shadow: arg("--rain-shadow"),
+            heightmap: arg("--heightmap"),
+            heightmap_falloff: flag("--heightmap-falloff"),
+            load: arg("--load"),
+            compare: flag("--compare"),
+            preset: None,
+        };
+        if let Some(preset) = arg::<Preset>("--preset") {
+            preset.shape(&mut config);
+        }
+        config
+    }
+}
+
+impl FromArgs for IslandShape {
+    fn from_args() -> Self {
+        let default = IslandShape::default();
+        IslandShape {
+            enabled: !flag("--no-falloff"),
+            strength: arg("--island-strength").unwrap_or(default.strength),
+            radius: arg("--island-radius").unwrap_or(default.radius),
+            invert: flag("--falloff-invert"),
+            islands: arg("--islands").unwrap_or(default.islands).max(1),
+        }
+    }
+}
+
+impl FromArgs for BiomeThresholds {
+    // the defaults overridden by the `--biome-<name> value` flags
+    fn from_args() -> Self {
+        let default = BiomeThresholds::default();
+        let get = |name: &str, default: f32| arg(&format!("--biome-{}", name)).unwrap_or(default);
+        BiomeThresholds {
+            beach_height: get("beach-height", default.beach_height),
+            frozen: get("frozen", default.frozen),
+            cold: get("cold", default.cold),
+            hot: get("hot", default.hot),
+            dry: get("dry", default.dry),
+            wet: get("wet", default.wet),
+            humid: get("humid", default.humid),
+            lapse_rate: get("lapse-rate", default.lapse_rate),
+            latitude_cooling: get("latitude-cooling", default.latitude_cooling),
+        }
+    }
+}
+
+impl FromArgs for DrawConfig {
+    fn from_args() -> Self {
+        let default = DrawConfig::default();
+        DrawConfig {
+            posterize: arg("--posterize").unwrap_or(default.posterize),
+            lakes: flag("--lakes"),
+            rivers: arg("--rivers"),
+            sobel: flag("--sobel"),
+            chunk_size: arg("--chunk-size").unwrap_or(default.chunk_size).max(1),
+            show_hardness: flag("--show-hardness"),
+            show_precipitation: flag("--show-precipitation"),
+            show_transport: flag("--show-transport"),
+            show_erosion: flag("--show-erosion"),
+            show_aspect: flag("--show-aspect"),
+            show_curvature: flag("--show-curvature"),
+            hillshade: flag("--hillshade"),
+            contours: arg::<f32>("--contours").filter(|interval| *interval > 0.),
+            biomes: if flag("--biomes") {
+                Some(BiomeThresholds::from_args())
+            } else {
+                None
+            },
+            show_grid: flag("--show-grid"),
+            grid_spacing: arg("--grid-spacing").unwrap_or(default.grid_spacing).max(2),
+        }
+    }
+}
+
+// `--palette`, or the built-in one when it's missing
+pub fn palette(sea_level: f32) -> Palette {
+    match arg::<PathBuf>("--palette") {
+        Some(path) => Palette::load(&path, sea_level)
+            .unwrap_or_else(|e| panic!("couldn't load palette {}: {}", path.display(), e)),
+        None => Palette::default(),
+    }
+}
+
+pub fn meander(seed: u32) -> Meander {
+    Meander::new(
+        arg("--meander-amplitude").unwrap_or(0.),
+        arg("--meander-frequency").unwrap_or(0.05),
+        seed.wrapping_add(1),
+    )
+}
+
+// params with the ones given on the command line as `<prefix><name>` overridden
+pub fn erosion_params(params: &ErosionParams, prefix: &str) -> ErosionParams {
+    let get = |name: &str, default: f32| arg(&format!("{}{}", prefix, name)).unwrap_or(default);
+    ErosionParams {
+        evaporation: get("evaporation", params.evaporation),
+        inertia: get("inertia", params.inertia),
+        min_slope: get("min-slope", params.min_slope),
+        capacity: get("capacity", params.capacity),
+        deposition: get("deposition", params.deposition),
+        erosion: get("erosion", params.erosion),
+        radius: arg(&format!("{}radius", prefix)).unwrap_or(params.radius),
+        max_velocity: get("max-velocity", params.max_velocity),
+        max_water: get("max-water", params.max_water),
+    }
+}
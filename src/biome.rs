@@ -0,0 +1,150 @@
+use crate::palette::Palette;
+
+// Whittaker-style classification of the land by temperature and moisture
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Biome {
+    Ocean,
+    Beach,
+    Desert,
+    Grassland,
+    Forest,
+    Rainforest,
+    Tundra,
+    Snow,
+    Rock,
+}
+
+impl Biome {
+    pub fn color(&self, palette: &Palette) -> [f32; 3] {
+        match self {
+            Biome::Ocean => palette.deep_water,
+            Biome::Beach => palette.beach,
+            Biome::Desert => [0.87, 0.78, 0.52],
+            Biome::Grassland => [0.55, 0.75, 0.3],
+            Biome::Forest => [0.15, 0.5, 0.2],
+            Biome::Rainforest => [0.05, 0.35, 0.15],
+            Biome::Tundra => [0.6, 0.62, 0.52],
+            Biome::Snow => palette.snow,
+            Biome::Rock => palette.rock,
+        }
+    }
+}
+
+// where the biomes change, temperatures and moistures are in [0, 1]
+#[derive(Clone, PartialEq, Debug)]
+pub struct BiomeThresholds {
+    // height above the sea level under which the shore is sand
+    pub beach_height: f32,
+    // below it the ground is frozen, snow when there's some moisture and bare rock otherwise
+    pub frozen: f32,
+    // below it the ground is tundra
+    pub cold: f32,
+    // above it the wettest ground is rainforest
+    pub hot: f32,
+    // below it the ground is desert
+    pub dry: f32,
+    // above it the ground is forest rather than grassland
+    pub wet: f32,
+    // above it the hot ground is rainforest
+    pub humid: f32,
+    // temperature lost per unit of height above the sea level
+    pub lapse_rate: f32,
+    // temperature lost from the middle row to the first and last ones
+    pub latitude_cooling: f32,
+}
+
+impl Default for BiomeThresholds {
+    fn default() -> Self {
+        BiomeThresholds {
+            beach_height: 0.03,
+            frozen: 0.15,
+            cold: 0.3,
+            hot: 0.7,
+            dry: 0.2,
+            wet: 0.45,
+            humid: 0.75,
+            lapse_rate: 1.,
+            latitude_cooling: 0.5,
+        }
+    }
+}
+
+impl BiomeThresholds {
+    // temperature in [0, 1] of ground of the given height, the latitude going from 0 on the
+    // middle row to 1 on the first and last ones
+    pub fn temperature(&self, height: f32, latitude: f32, sea_level: f32) -> f32 {
+        let altitude = (height - sea_level).max(0.);
+        (1. - self.latitude_cooling * latitude - self.lapse_rate * altitude).clamp(0., 1.)
+    }
+}
+
+pub fn classify_biome(
+    height: f32,
+    temp: f32,
+    moisture: f32,
+    sea_level: f32,
+    thresholds: &BiomeThresholds,
+) -> Biome {
+    if height < sea_level {
+        Biome::Ocean
+    } else if height < sea_level + thresholds.beach_height {
+        Biome::Beach
+    } else if temp < thresholds.frozen {
+        if moisture < thresholds.dry {
+            Biome::Rock
+        } else {
+            Biome::Snow
+        }
+    } else if temp < thresholds.cold {
+        Biome::Tundra
+    } else if moisture < thresholds.dry {
+        Biome::Desert
+    } else if moisture < thresholds.wet {
+        Biome::Grassland
+    } else if temp >= thresholds.hot && moisture >= thresholds.humid {
+        Biome::Rainforest
+    } else {
+        Biome::Forest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn biomes_of_height_temperature_and_moisture() {
+        let t = BiomeThresholds::default();
+        for &(height, temp, moisture, biome) in [
+            (-0.1, 0.5, 0.5, Biome::Ocean),
+            (0.01, 0.5, 0.5, Biome::Beach),
+            (0.5, 0.1, 0.5, Biome::Snow),
+            (0.5, 0.1, 0.1, Biome::Rock),
+            (0.5, 0.2, 0.5, Biome::Tundra),
+            (0.5, 0.5, 0.1, Biome::Desert),
+            (0.5, 0.5, 0.3, Biome::Grassland),
+            (0.5, 0.5, 0.6, Biome::Forest),
+            (0.5, 0.8, 0.6, Biome::Forest),
+            (0.5, 0.8, 0.9, Biome::Rainforest),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                classify_biome(height, temp, moisture, 0., &t),
+                biome,
+                "{:?}",
+                (height, temp, moisture)
+            );
+        }
+    }
+
+    #[test]
+    fn it_gets_colder_up_high_and_towards_the_poles() {
+        let t = BiomeThresholds::default();
+        assert_eq!(t.temperature(0., 0., 0.), 1.);
+        assert!(t.temperature(0.4, 0., 0.) < t.temperature(0.2, 0., 0.));
+        assert!(t.temperature(0.2, 1., 0.) < t.temperature(0.2, 0.5, 0.));
+        // the sea floor is as warm as the shore
+        assert_eq!(t.temperature(-0.5, 0., 0.), 1.);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::args::arg;
+use crate::erosion::{Compared, Source, TerrainId};
+use crate::input::{Action, KeyBindings};
+use crate::pick::Cursor;
+use bevy::prelude::*;
+use itertools::iproduct;
+use std::collections::HashMap;
+use terrain_gen::config::TerrainConfig;
+use terrain_gen::terrain::{coords, Elevation, Kernel, Topology};
+
+pub struct Brush {
+    // cells around the cursor that are sculpted
+    pub radius: usize,
+    // height added per second, spread over the brush
+    pub strength: f32,
+}
+
+impl Brush {
+    pub fn from_args() -> Self {
+        Brush {
+            radius: arg("--brush-radius").unwrap_or(4),
+            strength: arg("--brush-strength").unwrap_or(2.),
+        }
+    }
+}
+
+// sources painted with the mouse
+pub struct SourceBrush {
+    // droplets per frame of the new sources
+    pub flux: f32,
+}
+
+impl SourceBrush {
+    pub fn from_args() -> Self {
+        SourceBrush {
+            flux: arg("--source-flux").unwrap_or(0.01),
+        }
+    }
+}
+
+// strokes that can be undone, the oldest are forgotten past it
+const UNDO_DEPTH: usize = 32;
+
+// the cells a stroke reached as they were before it, or after it once the stroke is undone
+struct Patch {
+    cells: Vec<(usize, f32, f32)>,
+}
+
+impl Patch {
+    // swaps the cells with the ones on the terrain, so the patch can be put back
+    fn swap(&mut self, elevation: &mut Elevation) {
+        for (i, height, sediment) in self.cells.iter_mut() {
+            let old = (elevation.data[*i], elevation.sediment[*i]);
+            elevation.set(*i, *height, *sediment);
+            *height = old.0;
+            *sediment = old.1;
+        }
+    }
+}
+
+// the sculpting strokes that can be undone and redone, the rest of the terrain (the erosion
+// included) is left as it is, only the cells the strokes reached are put back
+#[derive(Default)]
+pub struct History {
+    undo: Vec<Patch>,
+    redo: Vec<Patch>,
+    // the heights and sediment of the cells the stroke in progress reached, before it started
+    stroke: HashMap<usize, (f32, f32)>,
+}
+
+impl History {
+    pub fn clear(&mut self) {
+        *self = History::default();
+    }
+
+    // the brush is about to change the cells within radius of pos
+    fn touch(&mut self, elevation: &Elevation, pos: Vec2, radius: usize) {
+        let size = elevation.size() as i32;
+        // hex deposits spread over the direct neighbors
+        let r = radius.max(1) as i32 + 1;
+        let (x, y) = coords(elevation.index(pos), size as usize);
+        let (x, y) = (x as i32, y as i32);
+        let toroidal = elevation.topology == Topology::Toroidal;
+        for (dx, dy) in iproduct!(-r..=r, -r..=r) {
+            let (cx, cy) = (x + dx, y + dy);
+            // the kernel wraps around the edges of a torus
+            let (cx, cy) = if toroidal {
+                (cx.rem_euclid(size), cy.rem_euclid(size))
+            } else if cx < 0 || cy < 0 || cx >= size || cy >= size {
+                continue;
+            } else {
+                (cx, cy)
+            };
+            let i = (cx + cy * size) as usize;
+            self.stroke
+                .entry(i)
+                .or_insert((elevation.data[i], elevation.sediment[i]));
+        }
+    }
+
+    // keeps the cells the finished stroke reached as they were before it
+    fn end_stroke(&mut self) {
+        if self.stroke.is_empty() {
+            return;
+        }
+        self.undo.push(Patch {
+            cells: self
+                .stroke
+                .drain()
+                .map(|(i, (height, sediment))| (i, height, sediment))
+                .collect(),
+        });
+        if self.undo.len() > UNDO_DEPTH {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+}
+
+// cells around the cursor in which shift click removes the sources
+const SOURCE_PICK_RADIUS: f32 = 3.;
+
+// while the source key is held, left click adds a source under the cursor
+// and shift click removes the ones around it
+fn paint_sources(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    buttons: Res<Input<MouseButton>>,
+    bindings: Res<KeyBindings>,
+    brush: Res<SourceBrush>,
+    cursor: Res<Cursor>,
+    terrain: Res<TerrainConfig>,
+    query_sources: Query<(Entity, &Source)>,
+) {
+    let pos = match cursor.0 {
+        Some(pos)
+            if bindings.pressed(&keys, Action::PaintSources)
+                && !bindings.pressed(&keys, Action::Sculpt) =>
+        {
+            pos
+        }
+        _ => return,
+    };
+    if !buttons.just_pressed(MouseButton::Left) {
+        return;
+    }
+    if keys.pressed(KeyCode::LShift) || keys.pressed(KeyCode::RShift) {
+        for (entity, source) in query_sources.iter() {
+            if source.pos.distance(pos) < SOURCE_PICK_RADIUS {
+                commands.entity(entity).despawn();
+            }
+        }
+    } else {
+        // the compared terrain gets the same sources
+        let terrains = if terrain.compare { 2 } else { 1 };
+        for id in 0..terrains {
+            commands
+                .spawn()
+                .insert(Source::new(pos, brush.flux))
+                .insert(TerrainId(id));
+        }
+    }
+}
+
+// while the sculpt key is held, left click raises the terrain under the cursor and right click lowers it
+fn sculpt(
+    keys: Res<Input<KeyCode>>,
+    buttons: Res<Input<MouseButton>>,
+    bindings: Res<KeyBindings>,
+    brush: Res<Brush>,
+    cursor: Res<Cursor>,
+    time: Res<Time>,
+    mut history: ResMut<History>,
+    mut kernel: Local<Kernel>,
+    mut query_elevation: Query<&mut Elevation, Without<Compared>>,
+) {
+    // holding both brush keys does neither, like the click query does with either
+    let sculpting =
+        bindings.pressed(&keys, Action::Sculpt) && !bindings.pressed(&keys, Action::PaintSources);
+    let sign = if sculpting && buttons.pressed(MouseButton::Left) {
+        1.
+    } else if sculpting && buttons.pressed(MouseButton::Right) {
+        -1.
+    } else {
+        // a stroke lasts from the click to the release
+        history.end_stroke();
+        return;
+    };
+    if let (Some(pos), Ok(mut elevation)) = (cursor.0, query_elevation.single_mut()) {
+        if kernel.radius() != brush.radius {
+            *kernel = Kernel::new(brush.radius);
+        }
+        history.touch(&elevation, pos, brush.radius);
+        elevation.add(pos, sign * brush.strength * time.delta_seconds(), &kernel);
+    }
+}
+
+// while the sculpt key is held, the undo and redo keys take back the last stroke or put it back
+fn undo(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    mut history: ResMut<History>,
+    mut query_elevation: Query<&mut Elevation, Without<Compared>>,
+) {
+    if !bindings.pressed(&keys, Action::Sculpt) {
+        return;
+    }
+    let history = &mut *history;
+    let (from, to) = if bindings.just_pressed(&keys, Action::Undo) {
+        (&mut history.undo, &mut history.redo)
+    } else if bindings.just_pressed(&keys, Action::Redo) {
+        (&mut history.redo, &mut history.undo)
+    } else {
+        return;
+    };
+    if let (Ok(mut elevation), Some(mut patch)) = (query_elevation.single_mut(), from.pop()) {
+        patch.swap(&mut elevation);
+        to.push(patch);
+    }
+}
+
+pub struct Sculpt;
+
+impl Plugin for Sculpt {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(Brush::from_args())
+            .insert_resource(SourceBrush::from_args())
+            .init_resource::<History>()
+            .add_system(sculpt.system().after("pick"))
+            .add_system(undo.system())
+            .add_system(paint_sources.system().after("pick"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_and_redo_put_the_stroke_back() {
+        let size = 32;
+        let data = (0..size * size).map(|i| (i % 7) as f32 * 0.1).collect();
+        let mut elevation = Elevation::from_data(data, size);
+        elevation.deposit(Vec2::new(10., 10.), 0.05, &Kernel::new(2));
+        let before = (elevation.data.clone(), elevation.sediment.clone());
+        let (mut history, kernel) = (History::default(), Kernel::new(2));
+        for pos in [Vec2::new(8., 8.), Vec2::new(11., 9.), Vec2::new(0.5, 31.)].iter() {
+            history.touch(&elevation, *pos, kernel.radius());
+            elevation.add(*pos, -0.3, &kernel);
+        }
+        history.end_stroke();
+        let after = (elevation.data.clone(), elevation.sediment.clone());
+        assert_ne!(after, before);
+        // only the cells around the strokes are kept
+        assert!(history.undo[0].cells.len() < size * size / 4);
+        let mut patch = history.undo.pop().unwrap();
+        patch.swap(&mut elevation);
+        assert_eq!((elevation.data.clone(), elevation.sediment.clone()), before);
+        patch.swap(&mut elevation);
+        assert_eq!((elevation.data.clone(), elevation.sediment.clone()), after);
+    }
+}
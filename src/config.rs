@@ -0,0 +1,215 @@
+use crate::biome::{classify_biome, Biome, BiomeThresholds};
+use crate::palette::{terrain_color, Palette};
+use crate::presets::Preset;
+use crate::terrain::{Elevation, FractalParams, Grid, IslandShape, NoiseKind, Topology};
+use glam::Vec2;
+use serde::Serialize;
+use std::path::PathBuf;
+
+// seed of every random draw, so a run can be reproduced
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+pub struct Seed(pub u32);
+
+#[derive(Clone, Serialize)]
+pub struct TerrainConfig {
+    // number of cells per side
+    pub size: usize,
+    pub grid: Grid,
+    pub topology: Topology,
+    pub noise: NoiseKind,
+    pub fractal: FractalParams,
+    // height under which cells are water
+    pub sea_level: f32,
+    pub island: IslandShape,
+    // how far the noise is sampled from each cell following a second noise, 0 doesn't warp
+    pub warp_strength: f32,
+    // width in cells of the beaches flattened along the shore after the generation
+    pub beaches: Option<f32>,
+    // hardness of the hardest rock, 0 erodes everything the same
+    pub hardness: f32,
+    // how much drier the driest regions are than the wettest, 0 rains the same everywhere
+    pub precipitation: f32,
+    // direction the prevailing wind blows towards in degrees clockwise from north,
+    // its rain shadow dries the leeward side of the ridges
+    pub rain_shadow: Option<f32>,
+    // grayscale image used instead of the noise
+    pub heightmap: Option<PathBuf>,
+    // shape the loaded heightmap into an island like the noise
+    pub heightmap_falloff: bool,
+    // state saved with F9 to resume from
+    pub load: Option<PathBuf>,
+    // erode a copy of the terrain with other parameters next to it
+    pub compare: bool,
+    // the last preset applied, it overrides the noise, island and sea level flags
+    pub preset: Option<Preset>,
+}
+
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        TerrainConfig::new(512)
+    }
+}
+
+impl TerrainConfig {
+    // a fbm island of size x size cells, without any of the optional passes
+    pub fn new(size: usize) -> Self {
+        TerrainConfig {
+            size: size.max(2),
+            grid: Grid::Square,
+            topology: Topology::Clamped,
+            noise: NoiseKind::Fbm,
+            fractal: FractalParams::default(),
+            sea_level: 0.,
+            island: IslandShape::default(),
+            warp_strength: 0.,
+            beaches: None,
+            hardness: 0.,
+            precipitation: 0.,
+            rain_shadow: None,
+            heightmap: None,
+            heightmap_falloff: false,
+            load: None,
+            compare: false,
+            preset: None,
+        }
+    }
+
+    // whether it rains more on some cells than others
+    pub fn varied_rain(&self) -> bool {
+        self.precipitation > 0. || self.rain_shadow.is_some()
+    }
+}
+
+pub struct DrawConfig {
+    // number of levels per color channel, 0 or 1 keeps full color
+    pub posterize: u32,
+    // shade the water filling closed basins
+    pub lakes: bool,
+    // tint the cells draining at least this many cells in 2D
+    pub rivers: Option<u32>,
+    // color slopes from the Sobel gradient instead of the plain one
+    pub sobel: bool,
+    // cells per side of the meshes the 3D terrain is split into
+    pub chunk_size: usize,
+    // draw the rock hardness in 2D instead of the terrain
+    pub show_hardness: bool,
+    // draw the precipitation map in 2D instead of the terrain
+    pub show_precipitation: bool,
+    // draw how far the sediment deposited on each cell came from in 2D instead of the terrain
+    pub show_transport: bool,
+    // draw the direction each cell faces or the curvature in 2D instead of the terrain
+    pub show_aspect: bool,
+    pub show_curvature: bool,
+    // draw how much the droplets changed each cell over the run instead of the terrain
+    pub show_erosion: bool,
+    // shade the 2D relief as if lit from the palette's light direction
+    pub hillshade: bool,
+    // height between two contour lines drawn over the 2D land
+    pub contours: Option<f32>,
+    // color the land by biome instead of by height
+    pub biomes: Option<BiomeThresholds>,
+    // draw a grid over the 2D view every grid_spacing cells, with a scale bar as long
+    pub show_grid: bool,
+    pub grid_spacing: usize,
+}
+
+impl Default for DrawConfig {
+    fn default() -> Self {
+        DrawConfig {
+            posterize: 0,
+            lakes: false,
+            rivers: None,
+            sobel: false,
+            chunk_size: 128,
+            show_hardness: false,
+            show_precipitation: false,
+            show_transport: false,
+            show_aspect: false,
+            show_curvature: false,
+            show_erosion: false,
+            hillshade: false,
+            contours: None,
+            biomes: None,
+            show_grid: false,
+            grid_spacing: 32,
+        }
+    }
+}
+
+impl DrawConfig {
+    // color of cell i by biome, or by height and slope, the sea keeps its depth either way
+    pub fn color(
+        &self,
+        palette: &Palette,
+        elevation: &Elevation,
+        i: usize,
+        slope: f32,
+        sea_level: f32,
+    ) -> [f32; 3] {
+        let height = elevation.data[i];
+        let thresholds = match &self.biomes {
+            Some(thresholds) if height >= sea_level => thresholds,
+            _ => return terrain_color(palette, height, slope, sea_level),
+        };
+        let size = elevation.size();
+        let latitude = (2. * (i / size) as f32 / size as f32 - 1.).abs();
+        let temp = thresholds.temperature(height, latitude, sea_level);
+        let biome = classify_biome(
+            height,
+            temp,
+            elevation.precipitation[i],
+            sea_level,
+            thresholds,
+        );
+        match biome {
+            Biome::Ocean => terrain_color(palette, height, slope, sea_level),
+            _ => biome.color(palette),
+        }
+    }
+
+    // gradient of cell i used to color the terrain by slope
+    pub fn grad(&self, elevation: &Elevation, i: usize) -> Vec2 {
+        if self.sobel {
+            elevation.grad_sobel(i)
+        } else {
+            elevation.grad(i)
+        }
+    }
+}
+
+// quantize a [0, 1] color channel to the given number of levels
+pub fn posterize(c: f32, levels: u32) -> f32 {
+    if levels < 2 {
+        return c;
+    }
+    let steps = (levels - 1) as f32;
+    (c.clamp(0., 1.) * steps).round() / steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_coast_follows_the_sea_level() {
+        let p = Palette::default();
+        let sea_level = 0.3;
+        // the first row just under the sea and the rest just above
+        let data = (0..16).map(|i| if i < 4 { 0.299 } else { 0.301 }).collect();
+        let elevation = Elevation::from_data(data, 4);
+        let biomes = DrawConfig {
+            biomes: Some(BiomeThresholds::default()),
+            ..DrawConfig::default()
+        };
+        for (config, coast) in [
+            (DrawConfig::default(), p.beach),
+            (biomes, Biome::Beach.color(&p)),
+        ]
+        .iter()
+        {
+            let color = |i: usize| config.color(&p, &elevation, i, 0., sea_level);
+            assert_eq!(color(1), p.shallow_water);
+            assert_eq!(color(9), *coast);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::brush::History;
+use crate::erosion::{start_generation, ErosionStats, SimControl, SimRng, Source, Trails};
+use crate::input::{Action, KeyBindings};
+use bevy::prelude::*;
+use bevy::tasks::AsyncComputeTaskPool;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use terrain_gen::config::{Seed, TerrainConfig};
+use terrain_gen::terrain::{Droplet, Elevation, Meander};
+
+fn pause(keys: Res<Input<KeyCode>>, bindings: Res<KeyBindings>, mut control: ResMut<SimControl>) {
+    if bindings.just_pressed(&keys, Action::Pause) {
+        control.paused = !control.paused;
+    }
+    if control.paused && bindings.just_pressed(&keys, Action::Step) {
+        control.step = true;
+    }
+}
+
+// starts over from a new terrain generated from a fresh seed, drawn from the simulation rng
+// so the seeds following a reset are reproducible too
+fn reset(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    pool: Res<AsyncComputeTaskPool>,
+    config: Res<TerrainConfig>,
+    mut seed: ResMut<Seed>,
+    mut rng: ResMut<SimRng>,
+    mut meander: ResMut<Meander>,
+    mut stats: ResMut<ErosionStats>,
+    mut trails: ResMut<Trails>,
+    mut history: ResMut<History>,
+    query: Query<Entity, Or<(With<Droplet>, With<Source>, With<Elevation>)>>,
+) {
+    if !bindings.just_pressed(&keys, Action::Reset) {
+        return;
+    }
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+    *seed = reseed(&mut rng, &mut meander);
+    println!("seed {}", seed.0);
+    *stats = ErosionStats::default();
+    trails.visits.clear();
+    history.clear();
+    start_generation(&mut commands, &pool, &config, *seed);
+}
+
+// the seed of the terrain following a reset, the simulation rng starts over from it
+fn reseed(rng: &mut SimRng, meander: &mut Meander) -> Seed {
+    let seed = Seed(rng.0.gen());
+    // drawn before reseeding so the simulation draws match a start from the new seed
+    meander.reseed(rng.0.gen());
+    rng.0 = StdRng::seed_from_u64(seed.0 as u64);
+    seed
+}
+
+// forgets where the droplets changed the terrain so far, the terrain itself stays as it is
+fn reset_erosion_map(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    mut query: Query<&mut Elevation>,
+) {
+    if !bindings.just_pressed(&keys, Action::ResetErosionMap) {
+        return;
+    }
+    for mut elevation in query.iter_mut() {
+        for a in elevation.erosion_accum.iter_mut() {
+            *a = 0.;
+        }
+    }
+}
+
+pub struct Control;
+
+impl Plugin for Control {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system(pause.system())
+            .add_system(reset.system())
+            .add_system(reset_erosion_map.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the resources the reset reads but the keys, without the erosion systems drawing from the rng
+    fn app(seed: u32) -> AppBuilder {
+        let mut app = App::build();
+        app.insert_resource(Seed(seed))
+            .insert_resource(TerrainConfig::new(16))
+            .add_plugins(MinimalPlugins)
+            .init_resource::<SimRng>()
+            .insert_resource(Meander::default())
+            .init_resource::<SimControl>()
+            .init_resource::<ErosionStats>()
+            .init_resource::<Trails>()
+            .init_resource::<History>()
+            .init_resource::<KeyBindings>()
+            .add_plugin(Control);
+        app
+    }
+
+    fn draws(app: &mut AppBuilder) -> Vec<u64> {
+        let mut rng = app.app.world.get_resource_mut::<SimRng>().unwrap();
+        (0..100).map(|_| rng.0.gen()).collect()
+    }
+
+    #[test]
+    fn apps_with_the_same_seed_draw_the_same_numbers() {
+        let (mut a, mut b) = (app(7), app(7));
+        assert_eq!(draws(&mut a), draws(&mut b));
+        assert_ne!(draws(&mut app(7)), draws(&mut app(8)));
+        // and again from the seed both reset to
+        for built in [&mut a, &mut b].iter_mut() {
+            let mut keys = Input::<KeyCode>::default();
+            keys.press(KeyBindings::default().key(Action::Reset));
+            built.app.world.insert_resource(keys);
+            built.app.update();
+        }
+        let seed = |app: &AppBuilder| app.app.world.get_resource::<Seed>().unwrap().0;
+        assert_eq!(seed(&a), seed(&b));
+        assert_ne!(seed(&a), 7);
+        assert_eq!(draws(&mut a), draws(&mut b));
+    }
+
+    #[test]
+    fn the_seeds_after_a_reset_follow_the_first_one() {
+        let seeds = |seed: u64| -> Vec<u32> {
+            let mut rng = SimRng(StdRng::seed_from_u64(seed));
+            let mut meander = Meander::default();
+            (0..5).map(|_| reseed(&mut rng, &mut meander).0).collect()
+        };
+        assert_eq!(seeds(1), seeds(1));
+        assert_ne!(seeds(1), seeds(2));
+        // the simulation then draws what it would starting from the new seed
+        let mut rng = SimRng(StdRng::seed_from_u64(1));
+        let seed = reseed(&mut rng, &mut Meander::default());
+        let mut fresh = StdRng::seed_from_u64(seed.0 as u64);
+        assert_eq!(rng.0.gen::<u64>(), fresh.gen::<u64>());
+    }
+}
@@ -1,7 +1,28 @@
-use crate::erosion::{unroll, Droplet, Elevation, Source};
-use crate::SIZE;
+use crate::erosion::{Compared, Source, TerrainId, Trails};
+use crate::input::{Action, KeyBindings};
+use crate::view::{ViewCamera, ViewMode};
 use bevy::prelude::*;
 use bevy::render::texture::{Extent3d, TextureDimension, TextureFormat};
+use terrain_gen::config::{posterize, DrawConfig, TerrainConfig};
+use terrain_gen::hydrology::{fill_depressions, flow_accumulation};
+use terrain_gen::palette::{erosion_colors, hillshade, hue, sediment_color, Palette};
+use terrain_gen::terrain::{Droplet, Elevation};
+
+// sediment that traveled at least this many cells is drawn fully red with --show-transport
+const TRANSPORT_RED: f32 = 100.;
+// deposits thinner than this are too little to tell where they came from
+const TRANSPORT_MIN_SEDIMENT: f32 = 0.001;
+
+// curvature drawn fully red (ridges) or blue (valleys) with --show-curvature
+const CURVATURE_FULL: f32 = 0.01;
+
+// how much a single droplet passing over a cell tints it
+const TRAIL_OPACITY: f32 = 0.15;
+
+// cells between the scale bar and the lower left corner of the 2D view
+const SCALE_BAR_MARGIN: usize = 8;
+// thickness in cells of the scale bar
+const SCALE_BAR_WIDTH: usize = 2;
 
 fn new_tex(width: usize, height: usize) -> Texture {
     Texture::new(
@@ -16,23 +37,146 @@ fn setup_draw2d(
     mut commands: Commands,
     mut textures: ResMut<Assets<Texture>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
+    terrain: Res<TerrainConfig>,
+    view: Res<State<ViewMode>>,
+) {
+    let tex = new_tex(terrain.size, terrain.size);
+    commands
+        .spawn_bundle(OrthographicCameraBundle::new_2d())
+        .insert(ViewCamera(ViewMode::TwoD));
+    commands
+        .spawn_bundle(SpriteBundle {
+            material: materials.add(ColorMaterial::texture(textures.add(tex).into())),
+            visible: Visible {
+                is_visible: *view.current() == ViewMode::TwoD,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .insert(Terrain2d);
+}
+
+// the sprite showing the terrain texture
+pub struct Terrain2d;
+
+fn show_2d(mut query: Query<&mut Visible, With<Terrain2d>>) {
+    for mut visible in query.iter_mut() {
+        visible.is_visible = true;
+    }
+}
+
+fn hide_2d(mut query: Query<&mut Visible, With<Terrain2d>>) {
+    for mut visible in query.iter_mut() {
+        visible.is_visible = false;
+    }
+}
+
+// land cells whose height band differs from the one of their right or lower neighbor,
+// a band being the heights between two multiples of the interval
+fn contours(elevation: &Elevation, interval: f32, sea_level: f32) -> Vec<bool> {
+    let size = elevation.size();
+    let band = |i: usize| (elevation.data[i] / interval).floor() as i32;
+    (0..size * size)
+        .map(|i| {
+            let (x, y) = (i % size, i / size);
+            let land = |n: usize| elevation.data[n] >= sea_level;
+            let crosses = |n: usize| land(n) && band(n) != band(i);
+            land(i) && ((x + 1 < size && crosses(i + 1)) || (y + 1 < size && crosses(i + size)))
+        })
+        .collect()
+}
+
+// darkens every spacing-th row and column and draws a bar spacing cells long in the lower
+// left corner, brightened towards white so the terrain still shows through
+fn grid(data: &mut [u8], size: usize, spacing: usize) {
+    for i in 0..size * size {
+        let (x, y) = (i % size, i / size);
+        if x % spacing == 0 || y % spacing == 0 {
+            for c in 0..3 {
+                data[i * 4 + c] /= 2;
+            }
+        }
+    }
+    let length = spacing.min(size.saturating_sub(2 * SCALE_BAR_MARGIN));
+    let bottom = size.saturating_sub(SCALE_BAR_MARGIN);
+    for y in bottom.saturating_sub(SCALE_BAR_WIDTH)..bottom {
+        for x in SCALE_BAR_MARGIN..SCALE_BAR_MARGIN + length {
+            let i = x + y * size;
+            for c in 0..3 {
+                data[i * 4 + c] = data[i * 4 + c] / 4 + 191;
+            }
+        }
+    }
+}
+
+fn toggle_hillshade(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    mut config: ResMut<DrawConfig>,
 ) {
-    let tex = new_tex(SIZE, SIZE);
-    commands.spawn_bundle(OrthographicCameraBundle::new_2d());
-    commands.spawn_bundle(SpriteBundle {
-        material: materials.add(ColorMaterial::texture(textures.add(tex).into())),
-        ..Default::default()
-    });
+    if bindings.just_pressed(&keys, Action::ToggleHillshade) {
+        config.hillshade = !config.hillshade;
+    }
 }
 
+fn toggle_grid(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    mut config: ResMut<DrawConfig>,
+) {
+    if bindings.just_pressed(&keys, Action::ToggleGrid) {
+        config.show_grid = !config.show_grid;
+    }
+}
+
+fn toggle_erosion_map(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    mut config: ResMut<DrawConfig>,
+) {
+    if bindings.just_pressed(&keys, Action::ToggleErosionMap) {
+        config.show_erosion = !config.show_erosion;
+    }
+}
+
+fn toggle_trails(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    mut trails: ResMut<Trails>,
+) {
+    // with the sculpt key held it's the redo key
+    if bindings.just_pressed(&keys, Action::ToggleTrails)
+        && !bindings.pressed(&keys, Action::Sculpt)
+    {
+        trails.enabled = !trails.enabled;
+        trails.visits.clear();
+    }
+}
+
+// only redraws the texture on the frames the terrain, the droplets or the config changed
 fn draw2d(
-    query_elevation: Query<&Elevation>,
-    query_sources: Query<&Source>,
-    query_droplets: Query<&Droplet>,
+    query_elevation: Query<&Elevation, Without<Compared>>,
+    query_sources: Query<(&Source, &TerrainId)>,
+    query_droplets: Query<(&Droplet, &TerrainId)>,
+    changed_elevation: Query<Entity, Changed<Elevation>>,
+    changed_droplets: Query<Entity, Changed<Droplet>>,
+    removed_droplets: RemovedComponents<Droplet>,
     query_mat: Query<&Handle<ColorMaterial>>,
     materials: Res<Assets<ColorMaterial>>,
     mut textures: ResMut<Assets<Texture>>,
+    config: Res<DrawConfig>,
+    palette: Res<Palette>,
+    terrain: Res<TerrainConfig>,
+    trails: Res<Trails>,
 ) {
+    if !config.is_changed()
+        && !trails.is_changed()
+        && changed_elevation.iter().next().is_none()
+        && changed_droplets.iter().next().is_none()
+        && removed_droplets.iter().next().is_none()
+    {
+        return;
+    }
     if let Ok(elevation) = query_elevation.single() {
         if let Ok(mat_handle) = query_mat.single() {
             let data = &mut *textures
@@ -48,39 +192,129 @@ fn draw2d(
                 .unwrap()
                 .data;
 
+            let water = if config.lakes {
+                fill_depressions(elevation, terrain.sea_level)
+            } else {
+                Vec::new()
+            };
+            let heat = if config.show_erosion {
+                erosion_colors(&elevation.erosion_accum)
+            } else {
+                Vec::new()
+            };
+            let aspect = if config.show_aspect {
+                elevation.aspect()
+            } else {
+                Vec::new()
+            };
+            let curvature = if config.show_curvature {
+                elevation.curvature()
+            } else {
+                Vec::new()
+            };
             for (i, v) in elevation.data.iter().enumerate() {
-                if *v < 0. {
-                    data[i * 4] = 0;
-                    data[i * 4 + 1] = 0;
-                    data[i * 4 + 2] = 0;
-                    data[i * 4 + 3] = 255;
+                let color = if config.show_erosion {
+                    heat[i]
+                } else if config.show_aspect {
+                    // the hue goes around with the compass, flat ground is gray
+                    if elevation.grad(i).length() > 0. {
+                        hue(aspect[i] / 360.)
+                    } else {
+                        [0.5, 0.5, 0.5]
+                    }
+                } else if config.show_curvature {
+                    // ridges are red, valleys blue, flat or evenly sloped ground white
+                    let k = (curvature[i] / CURVATURE_FULL).clamp(-1., 1.);
+                    [1. - k.max(0.), 1. - k.abs(), 1. + k.min(0.)]
+                } else if config.show_hardness {
+                    // soft rock is dark, hard rock is bright
+                    let h = elevation.hardness[i];
+                    [h, h, h]
+                } else if config.show_precipitation {
+                    // dry regions are dark, wet ones bright blue
+                    let p = elevation.precipitation[i];
+                    [p * 0.3, p * 0.6, p]
+                } else if config.show_transport {
+                    // sediment from nearby is green, from far away red, bare rock is dark
+                    if elevation.sediment[i] > TRANSPORT_MIN_SEDIMENT {
+                        let t = (elevation.transport[i] / TRANSPORT_RED).min(1.);
+                        [t, 1. - t, 0.]
+                    } else {
+                        [0.1, 0.1, 0.1]
+                    }
+                } else if config.lakes && water[i] > *v && *v >= terrain.sea_level {
+                    palette.lake
                 } else {
-                    let vu = (*v * 255.) as u8;
-
-                    if elevation.grad(i).length() > 0.008 {
-                        // draw rocks
-                        data[i * 4] = vu;
-                        data[i * 4 + 1] = vu / 2;
-                        data[i * 4 + 2] = vu / 3;
-                        data[i * 4 + 3] = 255;
+                    let grad = config.grad(elevation, i);
+                    let color =
+                        config.color(&palette, elevation, i, grad.length(), terrain.sea_level);
+                    let color = sediment_color(
+                        &palette,
+                        color,
+                        *v,
+                        elevation.sediment[i],
+                        terrain.sea_level,
+                    );
+                    if config.hillshade && *v >= terrain.sea_level {
+                        let shade = hillshade(&palette, grad);
+                        [color[0] * shade, color[1] * shade, color[2] * shade]
                     } else {
-                        // draw herb
-                        data[i * 4] = vu / 4;
-                        data[i * 4 + 1] = vu;
-                        data[i * 4 + 2] = vu / 3;
-                        data[i * 4 + 3] = 255;
+                        color
+                    }
+                };
+                for c in 0..3 {
+                    data[i * 4 + c] = (color[c] * 255.) as u8;
+                }
+                data[i * 4 + 3] = 255;
+            }
+            if config.posterize > 1 {
+                for (i, c) in data.iter_mut().enumerate() {
+                    if i % 4 != 3 {
+                        *c = (posterize(*c as f32 / 255., config.posterize) * 255.) as u8;
                     }
                 }
             }
-            for source in query_sources.iter() {
-                let i = unroll(source.pos, SIZE);
+            if let Some(interval) = config.contours {
+                for (i, _) in contours(elevation, interval, terrain.sea_level)
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, line)| *line)
+                {
+                    for c in 0..3 {
+                        data[i * 4 + c] /= 3;
+                    }
+                }
+            }
+            if let Some(threshold) = config.rivers {
+                for (i, flow) in flow_accumulation(elevation).into_iter().enumerate() {
+                    if flow >= threshold {
+                        data[i * 4] /= 4;
+                        data[i * 4 + 1] /= 2;
+                        data[i * 4 + 2] = 255;
+                    }
+                }
+            }
+            // every visit tints the cell a bit more, so the busiest paths stand out
+            for (i, tick) in trails.visits.iter() {
+                let k = TRAIL_OPACITY * trails.recency(*tick).max(0.);
+                for (c, blue) in [0., 0.4, 1.].iter().enumerate() {
+                    let v = data[i * 4 + c] as f32;
+                    data[i * 4 + c] = (v * (1. - k) + 255. * blue * k) as u8;
+                }
+            }
+            if config.show_grid {
+                grid(data, elevation.size(), config.grid_spacing);
+            }
+            // only the main terrain is drawn in 2D
+            for (source, _) in query_sources.iter().filter(|(_, id)| id.0 == 0) {
+                let i = elevation.index(source.pos);
                 data[i * 4] = 255;
                 data[i * 4 + 1] = 0;
                 data[i * 4 + 2] = 0;
             }
-            for droplet in query_droplets.iter() {
-                let i = unroll(droplet.pos, SIZE);
-                if data[i * 4] > 0 {
+            for (droplet, _) in query_droplets.iter().filter(|(_, id)| id.0 == 0) {
+                let i = elevation.index(droplet.pos);
+                if elevation.data[i] >= terrain.sea_level {
                     let w = (255. * droplet.water) as u8;
                     let v = (data[i * 4] as f32 * (1. - droplet.water)) as u8;
                     data[i * 4] = v;
@@ -97,6 +331,32 @@ pub struct Draw2d;
 impl Plugin for Draw2d {
     fn build(&self, app: &mut AppBuilder) {
         app.add_startup_system(setup_draw2d.system())
-            .add_system(draw2d.system());
+            .add_system(toggle_hillshade.system())
+            .add_system(toggle_grid.system())
+            .add_system(toggle_erosion_map.system())
+            .add_system(toggle_trails.system())
+            .add_system_set(SystemSet::on_enter(ViewMode::TwoD).with_system(show_2d.system()))
+            .add_system_set(SystemSet::on_exit(ViewMode::TwoD).with_system(hide_2d.system()))
+            .add_system_set(SystemSet::on_update(ViewMode::TwoD).with_system(draw2d.system()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contours_of_a_ramp_are_evenly_spaced() {
+        // an eighth of the interval per column
+        let ramp =
+            Elevation::from_data((0..64 * 64).map(|i| (i % 64) as f32 * 0.125).collect(), 64);
+        let lines = contours(&ramp, 1., 0.);
+        for i in 0..64 * 64 {
+            let x = i % 64;
+            assert_eq!(lines[i], x % 8 == 7 && x < 63, "column {}", x);
+        }
+        // none under the sea
+        let lines = contours(&ramp, 1., 4.);
+        assert!((0..64 * 64).all(|i| !lines[i] || i % 64 >= 32));
     }
 }
@@ -1,24 +1,52 @@
-use crate::erosion::Elevation;
-use crate::SIZE;
+use crate::erosion::TerrainId;
+use crate::export::Screenshots;
+use crate::input::{Action, KeyBindings};
+use crate::view::{ViewCamera, ViewMode};
+use bevy::input::mouse::MouseMotion;
 use bevy::math::f32;
 use bevy::{
     prelude::*,
+    reflect::TypeUuid,
     render::{
-        camera::Camera,
+        camera::{Camera, OrthographicProjection, PerspectiveProjection},
         mesh::Indices,
-        pipeline::{PipelineDescriptor, PrimitiveTopology, RenderPipeline},
+        pipeline::{
+            CompareFunction, PipelineDescriptor, PolygonMode, PrimitiveTopology, RenderPipeline,
+        },
+        render_graph::{base, AssetRenderResourcesNode, RenderGraph},
+        renderer::RenderResources,
         shader::{ShaderStage, ShaderStages},
     },
 };
 
+use crate::args::arg;
+use image::RgbaImage;
 use std::ops::Rem;
-const HEIGHTMULT: f32 = 60.;
+use terrain_gen::config::{DrawConfig, Seed, TerrainConfig};
+use terrain_gen::hydrology::fill_depressions;
+use terrain_gen::mesh::{grid_indices, Chunk};
+use terrain_gen::palette::{erosion_colors, sediment_color, Palette};
+use terrain_gen::raster::{Frame, Lighting, Shaded};
+use terrain_gen::terrain::{coords, Elevation, Grid};
+// height of the skirts hanging from the chunk borders, in units of height
+const SKIRT_DEPTH: f32 = 0.05;
+// cells between the main terrain and the compared one
+const COMPARE_GAP: f32 = 16.;
+// factor by which the height scale changes per second while its key is held
+const HEIGHT_SCALE_SPEED: f32 = 1.5;
+// radians of rotation per pixel of mouse motion
+const MOUSE_SENSITIVITY: f32 = 0.003;
+// keeps the fly camera from flipping over when looking straight up or down
+const MAX_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
 use itertools::iproduct;
 const VERTEX_SHADER: &str = r"
 #version 450
 layout(location = 0) in vec3 Vertex_Position;
 layout(location = 1) in vec3 Vertex_Color;
+layout(location = 2) in vec3 Vertex_Normal;
 layout(location = 1) out vec3 v_Color;
+layout(location = 2) out vec3 v_Normal;
+layout(location = 3) out vec3 v_WorldPos;
 layout(set = 0, binding = 0) uniform CameraViewProj {
     mat4 ViewProj;
 };
@@ -27,113 +55,982 @@ layout(set = 1, binding = 0) uniform Transform {
 };
 void main() {
     v_Color = Vertex_Color;
-    gl_Position = ViewProj * Model * vec4(Vertex_Position, 1.0);
+    v_Normal = mat3(Model) * Vertex_Normal;
+    v_WorldPos = (Model * vec4(Vertex_Position, 1.0)).xyz;
+    gl_Position = ViewProj * vec4(v_WorldPos, 1.0);
 }
 ";
 
 const FRAGMENT_SHADER: &str = r"
 #version 450
 layout(location = 1) in vec3 v_Color;
+layout(location = 2) in vec3 v_Normal;
+layout(location = 3) in vec3 v_WorldPos;
 layout(location = 0) out vec4 o_Target;
+layout(set = 0, binding = 1) uniform CameraPosition {
+    vec4 CameraPos;
+};
+layout(set = 2, binding = 0) uniform Fog_color {
+    vec4 FogColor;
+};
+layout(set = 2, binding = 1) uniform Fog_density {
+    float FogDensity;
+};
+layout(set = 3, binding = 0) uniform SunLight_direction {
+    vec3 SunDirection;
+};
+layout(set = 3, binding = 1) uniform SunLight_color {
+    vec4 SunColor;
+};
+layout(set = 3, binding = 2) uniform SunLight_ambient {
+    float SunAmbient;
+};
+const float POSTERIZE = POSTERIZE_LEVELS;
+void main() {
+    // lambert from the sun's direction, the ambient light keeps the night side visible
+    float lambert = max(dot(normalize(v_Normal), normalize(SunDirection)), 0.0);
+    vec3 color = v_Color * (SunAmbient + (1.0 - SunAmbient) * lambert * SunColor.rgb);
+    if (POSTERIZE > 1.0) {
+        color = round(clamp(color, 0.0, 1.0) * (POSTERIZE - 1.0)) / (POSTERIZE - 1.0);
+    }
+    // squared exponential fog, exactly 0 at density 0
+    float fog = 1.0 - exp(-pow(FogDensity * distance(v_WorldPos, CameraPos.xyz), 2.0));
+    o_Target = vec4(mix(color, FogColor.rgb, fog), 1.0);
+}
+";
+
+// vertical exaggeration of the 3D terrain, in world units per unit of height
+pub struct HeightScale(pub f32);
+
+impl HeightScale {
+    // a zero or negative scale would flatten or flip the terrain
+    const MIN: f32 = 1.;
+    const MAX: f32 = 500.;
+
+    pub fn from_args() -> Self {
+        HeightScale(
+            arg("--height-scale")
+                .unwrap_or(60.)
+                .clamp(HeightScale::MIN, HeightScale::MAX),
+        )
+    }
+}
+
+fn adjust_height_scale(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    time: Res<Time>,
+    mut scale: ResMut<HeightScale>,
+) {
+    let factor = HEIGHT_SCALE_SPEED.powf(time.delta_seconds());
+    // only touch the resource while a key is held so the meshes aren't rebuilt every frame
+    if bindings.pressed(&keys, Action::HeightScaleUp) {
+        scale.0 = (scale.0 * factor).min(HeightScale::MAX);
+    } else if bindings.pressed(&keys, Action::HeightScaleDown) {
+        scale.0 = (scale.0 / factor).max(HeightScale::MIN);
+    }
+}
+
+// same lighting as the terrain but see-through
+const WATER_FRAGMENT_SHADER: &str = r"
+#version 450
+layout(location = 1) in vec3 v_Color;
+layout(location = 2) in vec3 v_Normal;
+layout(location = 3) in vec3 v_WorldPos;
+layout(location = 0) out vec4 o_Target;
+layout(set = 0, binding = 1) uniform CameraPosition {
+    vec4 CameraPos;
+};
+layout(set = 2, binding = 0) uniform Fog_color {
+    vec4 FogColor;
+};
+layout(set = 2, binding = 1) uniform Fog_density {
+    float FogDensity;
+};
+layout(set = 3, binding = 0) uniform SunLight_direction {
+    vec3 SunDirection;
+};
+layout(set = 3, binding = 1) uniform SunLight_color {
+    vec4 SunColor;
+};
+layout(set = 3, binding = 2) uniform SunLight_ambient {
+    float SunAmbient;
+};
+const float ALPHA = WATER_ALPHA;
+void main() {
+    // lambert from the sun's direction, the ambient light keeps the night side visible
+    float lambert = max(dot(normalize(v_Normal), normalize(SunDirection)), 0.0);
+    vec3 color = v_Color * (SunAmbient + (1.0 - SunAmbient) * lambert * SunColor.rgb);
+    float fog = 1.0 - exp(-pow(FogDensity * distance(v_WorldPos, CameraPos.xyz), 2.0));
+    o_Target = vec4(mix(color, FogColor.rgb, fog), ALPHA);
+}
+";
+
+// a quad covering the screen at the far plane, v_Dir is the direction seen through each corner
+const SKY_VERTEX_SHADER: &str = r"
+#version 450
+layout(location = 0) in vec3 Vertex_Position;
+layout(location = 0) out vec3 v_Dir;
+layout(set = 0, binding = 0) uniform CameraViewProj {
+    mat4 ViewProj;
+};
+layout(set = 0, binding = 1) uniform CameraPosition {
+    vec4 CameraPos;
+};
+void main() {
+    vec4 far = inverse(ViewProj) * vec4(Vertex_Position.xy, 1.0, 1.0);
+    v_Dir = far.xyz / far.w - CameraPos.xyz;
+    gl_Position = vec4(Vertex_Position.xy, 1.0, 1.0);
+}
+";
+
+const SKY_FRAGMENT_SHADER: &str = r"
+#version 450
+layout(location = 0) in vec3 v_Dir;
+layout(location = 0) out vec4 o_Target;
+layout(set = 2, binding = 0) uniform Sky_zenith {
+    vec4 Zenith;
+};
+layout(set = 2, binding = 1) uniform Sky_horizon {
+    vec4 Horizon;
+};
 void main() {
-    o_Target = vec4(v_Color, 1.0);
+    // the horizon color goes on below the horizon, where the fog hides the end of the terrain
+    float up = max(normalize(v_Dir).y, 0.0);
+    o_Target = vec4(mix(Horizon.rgb, Zenith.rgb, sqrt(up)), 1.0);
 }
 ";
 
+// the sky uniforms, set from the palette
+#[derive(RenderResources, Default, TypeUuid)]
+#[uuid = "c3e7a4d2-1b6f-4e0a-8d95-7f2b3c6a9e14"]
+pub struct Sky {
+    pub zenith: Color,
+    pub horizon: Color,
+}
+
+// haze the terrain and water fade into with the distance to the camera
+pub struct FogSettings {
+    pub color: [f32; 3],
+    // 0 is no fog at all
+    pub density: f32,
+}
+
+impl FogSettings {
+    pub fn from_args() -> Self {
+        FogSettings {
+            color: [0.7, 0.8, 0.9],
+            density: arg("--fog").unwrap_or(0.001f32).max(0.),
+        }
+    }
+}
+
+// the fog uniforms the 3D shaders read, shared by every mesh
+#[derive(RenderResources, Default, TypeUuid)]
+#[uuid = "5b1a3a0e-8f0c-4c47-9a4e-2f6f1d3c8e21"]
+pub struct Fog {
+    pub color: Color,
+    pub density: f32,
+}
+
+pub struct FogHandle(Handle<Fog>);
+
+fn update_fog(settings: Res<FogSettings>, handle: Res<FogHandle>, mut fogs: ResMut<Assets<Fog>>) {
+    if !settings.is_changed() {
+        return;
+    }
+    if let Some(fog) = fogs.get_mut(&handle.0) {
+        let [r, g, b] = settings.color;
+        fog.color = Color::rgb(r, g, b);
+        fog.density = settings.density;
+    }
+}
+
+// the terrain is drawn with the solid pipeline or the one only drawing the triangle edges
+pub struct TerrainPipelines {
+    solid: Handle<PipelineDescriptor>,
+    wireframe: Handle<PipelineDescriptor>,
+}
+
+#[derive(Default)]
+pub struct WireframeMode {
+    pub enabled: bool,
+}
+
+// swaps the pipeline of the chunks, their meshes stay as they are
+fn toggle_wireframe(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    pipelines: Res<TerrainPipelines>,
+    mut mode: ResMut<WireframeMode>,
+    mut query: Query<&mut RenderPipelines, With<Chunk>>,
+) {
+    if !bindings.just_pressed(&keys, Action::ToggleWireframe) {
+        return;
+    }
+    mode.enabled = !mode.enabled;
+    let pipeline = if mode.enabled {
+        &pipelines.wireframe
+    } else {
+        &pipelines.solid
+    };
+    for mut render_pipelines in query.iter_mut() {
+        *render_pipelines =
+            RenderPipelines::from_pipelines(vec![RenderPipeline::new(pipeline.clone())]);
+    }
+}
+
+// cells further than distance from the perspective camera are drawn every 2 cells,
+// every 4 cells twice as far, and so on up to MAX_STRIDE
+pub struct Lod {
+    distance: f32,
+}
+
+impl Lod {
+    const MAX_STRIDE: usize = 8;
+
+    pub fn from_args() -> Self {
+        Lod {
+            distance: arg("--lod").unwrap_or(256f32).max(0.),
+        }
+    }
+
+    fn stride(&self, distance: f32) -> usize {
+        if self.distance <= 0. {
+            return 1;
+        }
+        let level = (distance / self.distance).floor().min(8.) as u32;
+        (1 << level).min(Lod::MAX_STRIDE)
+    }
+}
+
+// vertices of the 3D meshes, to see what the level of detail saves
+#[derive(Default)]
+pub struct MeshStats {
+    pub vertices: usize,
+}
+
+// the chunks only get a new stride here, draw3d rebuilds their meshes
+fn update_lod(
+    lod: Res<Lod>,
+    cameras: Query<(&Camera, &GlobalTransform), With<PerspectiveProjection>>,
+    mut chunks: Query<(&mut TerrainChunk, &GlobalTransform)>,
+) {
+    let eye = cameras
+        .iter()
+        .find(|(camera, _)| camera.name.as_deref() == Some(base::camera::CAMERA_3D))
+        .map(|(_, transform)| transform.translation);
+    for (mut chunk, transform) in chunks.iter_mut() {
+        // the orthographic cameras see the whole terrain at once, it's all drawn in full
+        let stride = eye.map_or(1, |eye| {
+            let chunk = &chunk.chunk;
+            let center = Vec3::new(
+                (chunk.x0 + chunk.width / 2) as f32,
+                0.,
+                (chunk.y0 + chunk.height / 2) as f32,
+            );
+            lod.stride((transform.translation + center - eye).length())
+        });
+        if chunk.chunk.stride != stride {
+            chunk.chunk.stride = stride;
+        }
+    }
+}
+
+// the light of the 3D view, its angles are in degrees like the palette's 2D light
+pub struct Sun {
+    // clockwise from north (towards the first row) and above the horizon
+    pub azimuth: f32,
+    pub altitude: f32,
+    pub color: [f32; 3],
+    // part of the light reaching the faces turned away from the sun
+    pub ambient: f32,
+    // seconds for the sun to go all the way around, 0 keeps it still
+    pub day_length: f32,
+}
+
+impl Sun {
+    pub fn from_args() -> Self {
+        Sun {
+            azimuth: 117.,
+            altitude: arg("--sun-altitude").unwrap_or(66.),
+            color: [1., 1., 1.],
+            ambient: 0.3,
+            day_length: arg("--day-length").unwrap_or(0f32).max(0.),
+        }
+    }
+
+    // unit vector towards the sun, y is up and north is -z
+    fn direction(&self) -> Vec3 {
+        let (azimuth, altitude) = (self.azimuth.to_radians(), self.altitude.to_radians());
+        Vec3::new(
+            altitude.cos() * azimuth.sin(),
+            altitude.sin(),
+            -altitude.cos() * azimuth.cos(),
+        )
+    }
+}
+
+// the sun uniforms the 3D shaders read, shared by every mesh
+#[derive(RenderResources, Default, TypeUuid)]
+#[uuid = "c0a7f5d2-3b4e-4f61-8d2a-7e9b0c1f4a56"]
+pub struct SunLight {
+    pub direction: Vec3,
+    pub color: Color,
+    pub ambient: f32,
+}
+
+pub struct SunHandle(Handle<SunLight>);
+
+// a day goes by as the sun goes over the sky and under the ground
+fn update_sun(
+    time: Res<Time>,
+    mut sun: ResMut<Sun>,
+    handle: Res<SunHandle>,
+    mut lights: ResMut<Assets<SunLight>>,
+) {
+    if sun.day_length > 0. {
+        sun.altitude = (sun.altitude + 360. * time.delta_seconds() / sun.day_length).rem(360.);
+    }
+    if !sun.is_changed() {
+        return;
+    }
+    if let Some(light) = lights.get_mut(&handle.0) {
+        let [r, g, b] = sun.color;
+        light.direction = sun.direction();
+        light.color = Color::rgb(r, g, b);
+        light.ambient = sun.ambient;
+    }
+}
+
+// quads per side of the water plane, the waves are too smooth to need one per cell
+const WATER_RES: usize = 64;
+// height of the waves in world units and their speed in radians per second
+const WAVE_AMPLITUDE: f32 = 0.2;
+const WAVE_SPEED: f32 = 0.8;
+// opacity of the water, it's blended over the terrain under it
+const WATER_ALPHA: f32 = 0.6;
+// the water floats above the flattened sea floor so they don't z-fight, even in the troughs
+const WATER_OFFSET: f32 = 0.3;
+
+// the animated sea, only seen where the terrain is below it
+pub struct Water;
+
+// vertex positions and normals of the water plane at time t
+fn water_vertices(extent: Vec2, height: f32, t: f32) -> (Vec<[f32; 3]>, Vec<[f32; 3]>) {
+    let step = (extent - Vec2::ONE) / WATER_RES as f32;
+    iproduct!(0..=WATER_RES, 0..=WATER_RES)
+        .map(|(y, x)| Vec2::new(x as f32 * step.x, y as f32 * step.y))
+        .map(|p| {
+            let a = p.x * 0.15 + t * WAVE_SPEED;
+            let b = p.y * 0.1 + t * WAVE_SPEED * 0.7;
+            let y = height + WATER_OFFSET + WAVE_AMPLITUDE * a.sin() * b.cos();
+            let dx = WAVE_AMPLITUDE * 0.15 * a.cos() * b.cos();
+            let dz = -WAVE_AMPLITUDE * 0.1 * a.sin() * b.sin();
+            let normal: [f32; 3] = Vec3::new(-dx, 1., -dz).normalize().into();
+            ([p.x, y, p.y], normal)
+        })
+        .unzip()
+}
+
+// the mesh of a chunk of the terrain, with what it was last built from
+pub struct TerrainChunk {
+    chunk: Chunk,
+    drawn_stride: usize,
+    // the changes the terrain had gone through then, None until it's first built
+    drawn_edits: Option<u64>,
+}
+
+impl TerrainChunk {
+    fn dirty(&self, elevation: &Elevation) -> bool {
+        self.chunk.stride != self.drawn_stride
+            || self
+                .drawn_edits
+                .map_or(true, |edits| self.chunk.touched_since(elevation, edits))
+    }
+}
+
 fn setup_draw3d(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut pipelines: ResMut<Assets<PipelineDescriptor>>,
     mut shaders: ResMut<Assets<Shader>>,
+    mut fogs: ResMut<Assets<Fog>>,
+    mut lights: ResMut<Assets<SunLight>>,
+    mut skies: ResMut<Assets<Sky>>,
+    mut render_graph: ResMut<RenderGraph>,
+    fog: Res<FogSettings>,
+    sun: Res<Sun>,
+    config: Res<DrawConfig>,
+    palette: Res<Palette>,
+    terrain: Res<TerrainConfig>,
+    scale: Res<HeightScale>,
+    view: Res<State<ViewMode>>,
 ) {
-    let pipeline_handle = pipelines.add(PipelineDescriptor::default_config(ShaderStages {
+    let fragment_shader = FRAGMENT_SHADER.replace(
+        "POSTERIZE_LEVELS",
+        &format!("{:.1}", config.posterize as f32),
+    );
+    let vertex_shader = shaders.add(Shader::from_glsl(ShaderStage::Vertex, VERTEX_SHADER));
+    let terrain_shaders = ShaderStages {
         // Vertex shaders are run once for every vertex in the mesh.
         // Each vertex can have attributes associated to it (e.g. position,
         // color, texture mapping). The output of a shader is per-vertex.
-        vertex: shaders.add(Shader::from_glsl(ShaderStage::Vertex, VERTEX_SHADER)),
+        vertex: vertex_shader.clone(),
         // Fragment shaders are run for each pixel belonging to a triangle on
         // the screen. Their output is per-pixel.
-        fragment: Some(shaders.add(Shader::from_glsl(ShaderStage::Fragment, FRAGMENT_SHADER))),
+        fragment: Some(shaders.add(Shader::from_glsl(ShaderStage::Fragment, &fragment_shader))),
+    };
+    let mut wireframe = PipelineDescriptor::default_config(terrain_shaders.clone());
+    wireframe.primitive.polygon_mode = PolygonMode::Line;
+    let pipeline_handle = pipelines.add(PipelineDescriptor::default_config(terrain_shaders));
+    commands.insert_resource(TerrainPipelines {
+        solid: pipeline_handle.clone(),
+        wireframe: pipelines.add(wireframe),
+    });
+    let water_shader = WATER_FRAGMENT_SHADER.replace("WATER_ALPHA", &format!("{:.2}", WATER_ALPHA));
+    let water_pipeline = pipelines.add(PipelineDescriptor::default_config(ShaderStages {
+        vertex: vertex_shader,
+        fragment: Some(shaders.add(Shader::from_glsl(ShaderStage::Fragment, &water_shader))),
     }));
-    // Create the mesh
-    let size = SIZE as u32;
+    render_graph.add_system_node("fog", AssetRenderResourcesNode::<Fog>::new(true));
+    render_graph
+        .add_node_edge("fog", base::node::MAIN_PASS)
+        .unwrap();
+    let [r, g, b] = fog.color;
+    let fog = fogs.add(Fog {
+        color: Color::rgb(r, g, b),
+        density: fog.density,
+    });
+    commands.insert_resource(FogHandle(fog.clone()));
+    render_graph.add_system_node("sun", AssetRenderResourcesNode::<SunLight>::new(true));
+    render_graph
+        .add_node_edge("sun", base::node::MAIN_PASS)
+        .unwrap();
+    let [r, g, b] = sun.color;
+    let light = lights.add(SunLight {
+        direction: sun.direction(),
+        color: Color::rgb(r, g, b),
+        ambient: sun.ambient,
+    });
+    commands.insert_resource(SunHandle(light.clone()));
+    // the sky is only drawn where nothing else was, at the depth the buffer is cleared to
+    let mut sky_pipeline = PipelineDescriptor::default_config(ShaderStages {
+        vertex: shaders.add(Shader::from_glsl(ShaderStage::Vertex, SKY_VERTEX_SHADER)),
+        fragment: Some(shaders.add(Shader::from_glsl(
+            ShaderStage::Fragment,
+            SKY_FRAGMENT_SHADER,
+        ))),
+    });
+    if let Some(depth) = sky_pipeline.depth_stencil.as_mut() {
+        depth.depth_compare = CompareFunction::LessEqual;
+        depth.depth_write_enabled = false;
+    }
+    render_graph.add_system_node("sky", AssetRenderResourcesNode::<Sky>::new(true));
+    render_graph
+        .add_node_edge("sky", base::node::MAIN_PASS)
+        .unwrap();
+    let (zenith, horizon) = (palette.sky_zenith, palette.sky_horizon);
+    let sky = skies.add(Sky {
+        zenith: Color::rgb(zenith[0], zenith[1], zenith[2]),
+        horizon: Color::rgb(horizon[0], horizon[1], horizon[2]),
+    });
     let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
-    let v_pos = iproduct!(0..size, 0..size)
-        .map(|(x, y)| [x as f32, 0., y as f32])
-        .collect::<Vec<[f32; 3]>>();
-    mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 1.0, 0.0]; v_pos.len()]);
-    mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0, 0.0]; v_pos.len()]);
-    mesh.set_attribute("Vertex_Color", vec![[0., 0., 0.]; v_pos.len()]);
-    mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, v_pos);
-
-    mesh.set_indices(Some(Indices::U32(
-        iproduct!(0..size - 1, 0..size - 1)
-            .map(|(x, y)| x % size + y * size)
-            .flat_map(|i| {
-                IntoIterator::into_iter([i, i + 1, i + size, i + 1, i + 1 + size, i + size])
+    mesh.set_attribute(
+        Mesh::ATTRIBUTE_POSITION,
+        vec![[-1., -1., 0.], [1., -1., 0.], [1., 1., 0.], [-1., 1., 0.]],
+    );
+    mesh.set_indices(Some(Indices::U32(vec![0, 1, 2, 0, 2, 3])));
+    commands
+        .spawn_bundle(MeshBundle {
+            mesh: meshes.add(mesh),
+            render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
+                pipelines.add(sky_pipeline),
+            )]),
+            visible: Visible {
+                is_visible: *view.current() == ViewMode::ThreeD,
+                is_transparent: false,
+            },
+            ..Default::default()
+        })
+        .insert(sky)
+        .insert(Terrain3d);
+    let size = terrain.size;
+    let extent = terrain.grid.extent(size);
+    let terrains = if terrain.compare { 2 } else { 1 };
+    for id in 0..terrains {
+        // the compared terrain is laid out to the right of the main one
+        let transform = Transform::from_xyz(id as f32 * (extent.x + COMPARE_GAP), 0., 0.);
+        // Create one mesh per chunk
+        for chunk in Chunk::split(size, config.chunk_size) {
+            let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+            let v_pos = chunk
+                .cells(size)
+                .into_iter()
+                .map(|i| coords(i, size))
+                .map(|(x, y)| [x as f32, 0., y as f32])
+                .collect::<Vec<[f32; 3]>>();
+            mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 1.0, 0.0]; v_pos.len()]);
+            mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0, 0.0]; v_pos.len()]);
+            mesh.set_attribute("Vertex_Color", vec![[0., 0., 0.]; v_pos.len()]);
+            mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, v_pos);
+            mesh.set_indices(Some(Indices::U32(grid_indices(
+                chunk.width,
+                chunk.height,
+                chunk.y0,
+                terrain.grid,
+            ))));
+            let chunk = TerrainChunk {
+                chunk: chunk,
+                drawn_stride: 0,
+                drawn_edits: None,
+            };
+            commands
+                .spawn_bundle(MeshBundle {
+                    mesh: meshes.add(mesh),
+                    render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
+                        pipeline_handle.clone(),
+                    )]),
+                    visible: Visible {
+                        is_visible: *view.current() == ViewMode::ThreeD,
+                        is_transparent: false,
+                    },
+                    transform: transform,
+                    ..Default::default()
+                })
+                .insert(chunk)
+                .insert(TerrainId(id))
+                .insert(fog.clone())
+                .insert(light.clone())
+                .insert(Terrain3d);
+        }
+        // the water plane, drawn after the terrain since it's transparent
+        let (positions, normals) = water_vertices(extent, terrain.sea_level * scale.0, 0.);
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0, 0.0]; positions.len()]);
+        mesh.set_attribute("Vertex_Color", vec![palette.shallow_water; positions.len()]);
+        mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+        mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.set_indices(Some(Indices::U32(grid_indices(
+            WATER_RES + 1,
+            WATER_RES + 1,
+            0,
+            Grid::Square,
+        ))));
+        commands
+            .spawn_bundle(MeshBundle {
+                mesh: meshes.add(mesh),
+                render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
+                    water_pipeline.clone(),
+                )]),
+                visible: Visible {
+                    is_visible: *view.current() == ViewMode::ThreeD,
+                    is_transparent: true,
+                },
+                transform: transform,
+                ..Default::default()
             })
-            .collect(),
-    )));
-    commands.spawn_bundle(MeshBundle {
-        mesh: meshes.add(mesh),
-        render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
-            pipeline_handle,
-        )]),
-        ..Default::default()
-    });
-    commands.spawn_bundle(PerspectiveCameraBundle {
-        transform: Transform::from_xyz((SIZE / 2) as f32, 50., -100.)
-            .looking_at(Vec3::new((SIZE / 2) as f32, 0., (SIZE / 2) as f32), Vec3::Y),
+            .insert(Water)
+            .insert(fog.clone())
+            .insert(light.clone())
+            .insert(Terrain3d);
+    }
+    commands
+        .spawn_bundle(perspective_camera(terrain.size))
+        .insert(FlyCamera::default())
+        .insert(ViewCamera(ViewMode::ThreeD));
+    commands.insert_resource(FlySpeed(terrain.size as f32 / 4.));
+}
+
+fn perspective_camera(size: usize) -> PerspectiveCameraBundle {
+    PerspectiveCameraBundle {
+        transform: Transform::from_xyz((size / 2) as f32, 50., -100.)
+            .looking_at(Vec3::new((size / 2) as f32, 0., (size / 2) as f32), Vec3::Y),
         ..Default::default()
-    });
+    }
+}
+
+// orthographic camera looking at the center of the terrain from the given direction
+fn orthographic_camera(size: usize, from: Vec3, up: Vec3) -> OrthographicCameraBundle {
+    let size = size as f32;
+    let center = Vec3::new(size / 2., 0., size / 2.);
+    let mut camera = OrthographicCameraBundle::new_3d();
+    // the projection spans scale units above and below the center of the screen
+    camera.orthographic_projection.scale = size * 0.6;
+    camera.orthographic_projection.far = size * 3.;
+    camera.transform =
+        Transform::from_translation(center + from.normalize() * size).looking_at(center, up);
+    camera
+}
+
+// how the 3D camera looks at the terrain, the orbit and fly camera only move the perspective one
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CameraPreset {
+    TopDown,
+    Isometric,
+    Perspective,
+}
+
+fn switch_camera(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    terrain: Res<TerrainConfig>,
+    mut preset: ResMut<CameraPreset>,
+    mut query: Query<(Entity, &mut FlyCamera)>,
+) {
+    let next = match [
+        (Action::CameraTopDown, CameraPreset::TopDown),
+        (Action::CameraIsometric, CameraPreset::Isometric),
+        (Action::CameraPerspective, CameraPreset::Perspective),
+    ]
+    .iter()
+    .find(|(action, _)| bindings.just_pressed(&keys, *action))
+    {
+        Some((_, next)) if *next != *preset => *next,
+        _ => return,
+    };
+    *preset = next;
+    for (entity, mut fly) in query.iter_mut() {
+        fly.enabled = false;
+        // the camera is swapped on the same entity, removing Camera makes bevy
+        // recompute the projection matrix when it's inserted back
+        let mut camera = commands.entity(entity);
+        camera
+            .remove::<Camera>()
+            .remove::<PerspectiveProjection>()
+            .remove::<OrthographicProjection>();
+        match next {
+            CameraPreset::TopDown => {
+                camera.insert_bundle(orthographic_camera(terrain.size, Vec3::Y, -Vec3::Z))
+            }
+            // 45° around the vertical and 35.26° above the horizon
+            CameraPreset::Isometric => camera.insert_bundle(orthographic_camera(
+                terrain.size,
+                Vec3::new(1., 2f32.sqrt() * 35.264f32.to_radians().tan(), 1.),
+                Vec3::Y,
+            )),
+            CameraPreset::Perspective => camera.insert_bundle(perspective_camera(terrain.size)),
+        };
+    }
 }
 
+// the terrain meshes and the water
+pub struct Terrain3d;
+
+fn show_3d(mut query: Query<&mut Visible, With<Terrain3d>>) {
+    for mut visible in query.iter_mut() {
+        visible.is_visible = true;
+    }
+}
+
+fn hide_3d(mut query: Query<&mut Visible, With<Terrain3d>>) {
+    for mut visible in query.iter_mut() {
+        visible.is_visible = false;
+    }
+}
+
+// free camera moved with the keyboard and turned with the mouse, the orbit takes over when disabled
+#[derive(Default)]
+pub struct FlyCamera {
+    pub enabled: bool,
+    yaw: f32,
+    pitch: f32,
+}
+
+// fly camera speed in cells per second
+pub struct FlySpeed(pub f32);
+
+fn toggle_fly_cam(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    preset: Res<CameraPreset>,
+    mut query: Query<(&mut FlyCamera, &Transform)>,
+) {
+    if *preset != CameraPreset::Perspective
+        || !bindings.just_pressed(&keys, Action::ToggleFlyCamera)
+    {
+        return;
+    }
+    for (mut fly, transform) in query.iter_mut() {
+        fly.enabled = !fly.enabled;
+        if fly.enabled {
+            // start looking where the orbit was looking
+            let forward = transform.rotation * -Vec3::Z;
+            fly.yaw = (-forward.x).atan2(-forward.z);
+            fly.pitch = forward.y.asin().clamp(-MAX_PITCH, MAX_PITCH);
+        }
+    }
+}
+
+fn fly_cam(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    time: Res<Time>,
+    speed: Res<FlySpeed>,
+    preset: Res<CameraPreset>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    mut query: Query<(&mut FlyCamera, &mut Transform)>,
+) {
+    let motion = mouse_motion
+        .iter()
+        .fold(Vec2::ZERO, |acc, event| acc + event.delta);
+    for (mut fly, mut transform) in query.iter_mut() {
+        if !fly.enabled || *preset != CameraPreset::Perspective {
+            continue;
+        }
+        fly.yaw -= motion.x * MOUSE_SENSITIVITY;
+        fly.pitch = (fly.pitch - motion.y * MOUSE_SENSITIVITY).clamp(-MAX_PITCH, MAX_PITCH);
+        let forward = Vec3::new(-fly.yaw.sin(), 0., -fly.yaw.cos());
+        let right = Vec3::new(fly.yaw.cos(), 0., -fly.yaw.sin());
+        let mut dir = Vec3::ZERO;
+        for (action, axis) in [
+            (Action::MoveForward, forward),
+            (Action::MoveBack, -forward),
+            (Action::MoveRight, right),
+            (Action::MoveLeft, -right),
+            (Action::MoveUp, Vec3::Y),
+            (Action::MoveDown, -Vec3::Y),
+        ]
+        .iter()
+        {
+            if bindings.pressed(&keys, *action) {
+                dir += *axis;
+            }
+        }
+        transform.translation += dir * speed.0 * time.delta_seconds();
+        transform.rotation = Quat::from_rotation_y(fly.yaw) * Quat::from_rotation_x(fly.pitch);
+    }
+}
+
+// the color of the vertex of each cell, the lakes and the erosion map are made up front
+fn cell_colors<'a>(
+    elevation: &'a Elevation,
+    config: &'a DrawConfig,
+    palette: &'a Palette,
+    sea_level: f32,
+) -> impl Fn(usize) -> [f32; 3] + 'a {
+    let water = if config.lakes {
+        fill_depressions(elevation, sea_level)
+    } else {
+        Vec::new()
+    };
+    let heat = if config.show_erosion {
+        erosion_colors(&elevation.erosion_accum)
+    } else {
+        Vec::new()
+    };
+    move |i: usize| {
+        let h = elevation.data[i];
+        if config.show_erosion {
+            heat[i]
+        } else if config.lakes && water[i] > h && h >= sea_level {
+            palette.lake
+        } else {
+            let g = config.grad(elevation, i).length();
+            let color = config.color(palette, elevation, i, g, sea_level);
+            sediment_color(palette, color, h, elevation.sediment[i], sea_level)
+        }
+    }
+}
+
+// only rebuilds the meshes of the chunks where the terrain or the level of detail changed
+// since they were last drawn
 fn draw3d(
-    query_elevation: Query<&Elevation>,
-    query_mesh: Query<&Handle<Mesh>>,
+    query_elevation: Query<(&Elevation, &TerrainId)>,
+    changed_elevation: Query<&TerrainId, Changed<Elevation>>,
+    added_elevation: Query<&TerrainId, Added<Elevation>>,
+    mut query_chunks: Query<(&mut TerrainChunk, &TerrainId, &Handle<Mesh>)>,
     mut meshes: ResMut<Assets<Mesh>>,
+    mut mesh_stats: ResMut<MeshStats>,
+    mut drawn_scale: Local<f32>,
+    config: Res<DrawConfig>,
+    palette: Res<Palette>,
+    terrain: Res<TerrainConfig>,
+    scale: Res<HeightScale>,
 ) {
-    if let Ok(elevation) = query_elevation.single() {
-        if let Ok(mesh_handle) = query_mesh.single() {
+    // new draw options can change every color, a new height scale moves every vertex
+    let rescaled = *drawn_scale != scale.0 || config.is_changed();
+    *drawn_scale = scale.0;
+    let changed_ids: Vec<TerrainId> = changed_elevation.iter().copied().collect();
+    // a new terrain starts counting its changes from 0 again
+    let added_ids: Vec<TerrainId> = added_elevation.iter().copied().collect();
+    for (elevation, id) in query_elevation.iter() {
+        let changed = changed_ids.contains(id);
+        // lakes can fill up from a change anywhere so everything is redrawn with them,
+        // the erosion map is relative to the busiest cell
+        let everything = rescaled
+            || added_ids.contains(id)
+            || (changed && (config.lakes || config.show_erosion));
+        let dirty: Vec<(Mut<TerrainChunk>, &Handle<Mesh>)> = query_chunks
+            .iter_mut()
+            .filter(|(chunk, chunk_id, _)| {
+                *chunk_id == id && (everything || chunk.dirty(elevation))
+            })
+            .map(|(chunk, _, mesh)| (chunk, mesh))
+            .collect();
+        if dirty.is_empty() {
+            continue;
+        }
+        let color = cell_colors(elevation, &config, &palette, terrain.sea_level);
+        // deep enough to cover the height difference between two strides on steep ground
+        let skirt_depth = SKIRT_DEPTH * scale.0;
+        for (mut chunk, mesh_handle) in dirty {
             let mesh = &mut *meshes.get_mut(mesh_handle.id).unwrap();
-            let v_pos = iproduct!(0..SIZE, 0..SIZE)
-                .map(|(x, y)| {
-                    [
-                        x as f32,
-                        elevation.data[y % SIZE + x * SIZE].max(0.) * HEIGHTMULT,
-                        y as f32,
-                    ]
-                })
-                .collect::<Vec<[f32; 3]>>();
-            mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, v_pos.clone());
-            mesh.set_attribute(
-                "Vertex_Color",
-                elevation
-                    .data
-                    .iter()
-                    .copied()
-                    .enumerate()
-                    .map(|(i, h)| (h, elevation.grad(i).length()))
-                    .map(|(h, g)| {
-                        if h < f32::EPSILON {
-                            [0.01, 0.05, 0.2]
-                        } else if g > 0.01 {
-                            [h * 0.8, h * 0.6, h * 0.5]
-                        } else if h > 0.03 {
-                            [h / 4., h, h / 3.]
-                        } else {
-                            [0.8, 0.9, 0.2]
-                        }
-                    })
-                    .collect::<Vec<[f32; 3]>>(),
-            );
+            let (positions, normals) =
+                chunk
+                    .chunk
+                    .vertices(elevation, terrain.sea_level, scale.0, skirt_depth);
+            let colors: Vec<[f32; 3]> = chunk
+                .chunk
+                .vertex_cells(elevation.size())
+                .into_iter()
+                .map(|i| color(i))
+                .collect();
+            let vertices = positions.len();
+            mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+            mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+            mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0, 0.0]; vertices]);
+            mesh.set_attribute("Vertex_Color", colors);
+            mesh.set_indices(Some(Indices::U32(chunk.chunk.indices(elevation.grid))));
+            chunk.drawn_stride = chunk.chunk.stride;
+            chunk.drawn_edits = Some(elevation.edits());
+        }
+    }
+    mesh_stats.vertices = query_chunks
+        .iter_mut()
+        .map(|(chunk, _, _)| {
+            let (w, h) = chunk.chunk.dims();
+            w * h + chunk.chunk.border().len()
+        })
+        .sum();
+}
+
+fn animate_water(
+    time: Res<Time>,
+    terrain: Res<TerrainConfig>,
+    scale: Res<HeightScale>,
+    query: Query<&Handle<Mesh>, With<Water>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    let extent = terrain.grid.extent(terrain.size);
+    let t = time.seconds_since_startup() as f32;
+    for mesh_handle in query.iter() {
+        if let Some(mesh) = meshes.get_mut(mesh_handle.id) {
+            let (positions, normals) = water_vertices(extent, terrain.sea_level * scale.0, t);
+            mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+            mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
         }
     }
 }
 
-fn rotate_cam(mut query: Query<&mut Transform, With<Camera>>, time: Res<Time>) {
-    let hsize = (SIZE / 2) as f32;
-    for mut transform in query.iter_mut() {
+// the terrain and the water drawn again on the CPU the way the shaders draw them, bevy can't
+// read the window back, the meshes are gathered here and drawn on the screenshot's thread
+fn screenshot_3d(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    windows: Res<Windows>,
+    time: Res<Time>,
+    seed: Res<Seed>,
+    mut screenshots: ResMut<Screenshots>,
+    cameras: Query<(&Camera, &GlobalTransform, &ViewCamera)>,
+    elevations: Query<(&Elevation, &TerrainId)>,
+    chunks: Query<(&TerrainChunk, &TerrainId, &GlobalTransform)>,
+    water: Query<&GlobalTransform, With<Water>>,
+    config: Res<DrawConfig>,
+    palette: Res<Palette>,
+    terrain: Res<TerrainConfig>,
+    scale: Res<HeightScale>,
+    sun: Res<Sun>,
+    fog: Res<FogSettings>,
+) {
+    if !bindings.just_pressed(&keys, Action::Screenshot) {
+        return;
+    }
+    let camera = cameras
+        .iter()
+        .find(|(_, _, ViewCamera(mode))| *mode == ViewMode::ThreeD);
+    let (window, (camera, transform, _)) = match (windows.get_primary(), camera) {
+        (Some(window), Some(camera)) => (window, camera),
+        _ => return,
+    };
+    let view_proj = camera.projection_matrix * transform.compute_matrix().inverse();
+    let eye = transform.translation;
+    // the uniforms get the colors in linear space, the vertex colors are used as they are
+    let linear = |[r, g, b]: [f32; 3]| {
+        let [r, g, b, _] = Color::rgb(r, g, b).as_linear_rgba_f32();
+        Vec3::new(r, g, b)
+    };
+    let lighting = Lighting {
+        sun_direction: sun.direction(),
+        sun_color: linear(sun.color),
+        ambient: sun.ambient,
+        fog_color: linear(fog.color),
+        fog_density: fog.density,
+    };
+    let (zenith, horizon) = (linear(palette.sky_zenith), linear(palette.sky_horizon));
+    let skirt_depth = SKIRT_DEPTH * scale.0;
+    let mut meshes = Vec::new();
+    for (elevation, id) in elevations.iter() {
+        let color = cell_colors(elevation, &config, &palette, terrain.sea_level);
+        for (chunk, _, transform) in chunks.iter().filter(|(_, chunk_id, _)| *chunk_id == id) {
+            let (positions, normals) =
+                chunk
+                    .chunk
+                    .vertices(elevation, terrain.sea_level, scale.0, skirt_depth);
+            meshes.push(Shaded {
+                positions: positions,
+                normals: normals,
+                colors: chunk
+                    .chunk
+                    .vertex_cells(elevation.size())
+                    .into_iter()
+                    .map(|i| color(i))
+                    .collect(),
+                indices: chunk.chunk.indices(elevation.grid),
+                offset: transform.translation,
+                alpha: 1.,
+                posterize: config.posterize,
+            });
+        }
+    }
+    // after the terrain since it's blended over it
+    let extent = terrain.grid.extent(terrain.size);
+    let t = time.seconds_since_startup() as f32;
+    let (positions, normals) = water_vertices(extent, terrain.sea_level * scale.0, t);
+    for transform in water.iter() {
+        meshes.push(Shaded {
+            positions: positions.clone(),
+            normals: normals.clone(),
+            colors: vec![palette.shallow_water; positions.len()],
+            indices: grid_indices(WATER_RES + 1, WATER_RES + 1, 0, Grid::Square),
+            offset: transform.translation,
+            alpha: WATER_ALPHA,
+            posterize: 0,
+        });
+    }
+    let (width, height) = (window.physical_width(), window.physical_height());
+    screenshots.save(*seed, move || {
+        let (w, h) = (width as usize, height as usize);
+        let mut frame = Frame::new(w, h, view_proj, eye, zenith, horizon);
+        for mesh in &meshes {
+            frame.draw(mesh, &lighting);
+        }
+        RgbaImage::from_raw(width, height, frame.to_rgba())
+    });
+}
+
+fn rotate_cam(
+    mut query: Query<(&mut Transform, &FlyCamera)>,
+    time: Res<Time>,
+    terrain: Res<TerrainConfig>,
+    preset: Res<CameraPreset>,
+) {
+    let hsize = (terrain.size / 2) as f32;
+    for (mut transform, fly) in query.iter_mut() {
+        if fly.enabled || *preset != CameraPreset::Perspective {
+            continue;
+        }
         let alpha = (time.seconds_since_startup() as f32 / 10.).rem(2. * std::f32::consts::PI);
         *transform = Transform::from_xyz(
             hsize + alpha.cos() * hsize,
@@ -147,8 +1044,33 @@ pub struct Draw3d;
 
 impl Plugin for Draw3d {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_startup_system(setup_draw3d.system())
+        app.insert_resource(CameraPreset::Perspective)
+            .insert_resource(HeightScale::from_args())
+            .insert_resource(FogSettings::from_args())
+            .add_asset::<Fog>()
+            .add_asset::<Sky>()
+            .add_system(update_fog.system())
+            .insert_resource(Sun::from_args())
+            .init_resource::<WireframeMode>()
+            .insert_resource(Lod::from_args())
+            .init_resource::<MeshStats>()
+            .add_system(update_lod.system().before("draw3d"))
+            .add_system(toggle_wireframe.system())
+            .add_asset::<SunLight>()
+            .add_system(update_sun.system())
+            .add_system(adjust_height_scale.system())
+            .add_startup_system(setup_draw3d.system())
+            .add_system(switch_camera.system())
+            .add_system(toggle_fly_cam.system())
+            .add_system(fly_cam.system())
             .add_system(rotate_cam.system())
-            .add_system(draw3d.system());
+            .add_system_set(SystemSet::on_enter(ViewMode::ThreeD).with_system(show_3d.system()))
+            .add_system_set(SystemSet::on_exit(ViewMode::ThreeD).with_system(hide_3d.system()))
+            .add_system_set(
+                SystemSet::on_update(ViewMode::ThreeD)
+                    .with_system(draw3d.system().label("draw3d"))
+                    .with_system(animate_water.system())
+                    .with_system(screenshot_3d.system()),
+            );
     }
 }
@@ -1,24 +1,104 @@
-use crate::SIZE;
+use crate::args::{self, arg, flag};
+use bevy::ecs::world::FromWorld;
+use bevy::math::const_vec2;
 use bevy::prelude::*;
-use itertools::iproduct;
-use noise::{Fbm, NoiseFn, Seedable};
-use std::usize;
+use bevy::tasks::AsyncComputeTaskPool;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use terrain_gen::config::{Seed, TerrainConfig};
+use terrain_gen::hydrology::{fill_depressions, flow_accumulation};
+use terrain_gen::presets::Preset;
+use terrain_gen::terrain::{
+    rand_pos, step_parallel, Droplet, Elevation, ErosionParams, Grid, Kernel, Meander, DELTA_SPREAD,
+};
 
-// Hydrolic erosion constants
-const EVAPORATION: f32 = 0.05;
-const INERTIA: f32 = 0.1;
-const MINSLOPE: f32 = 0.;
-const CAPACITY: f32 = 800.0;
-const DEPOSITION: f32 = 0.1;
-const EROSION: f32 = 0.01;
+// frames between two thermal passes
+const THERMAL_PERIOD: u32 = 10;
 
-fn rand_pos() -> Vec2 {
-    Vec2::new(
-        rand::random::<f32>() * SIZE as f32,
-        rand::random::<f32>() * SIZE as f32,
-    )
+// frames between two wind passes
+const WIND_PERIOD: u32 = 10;
+// direction the wind blows towards
+const WIND_DIR: Vec2 = const_vec2!([1., 0.3]);
+// cells draining at least this many cells are rivers, too wet for the wind
+const RIVER_FLOW: u32 = 50;
+// wind passes between two updates of the dry cells, the rivers move slowly
+const DRY_PERIOD: u32 = 10;
+
+// most droplet positions kept for the trails, and ticks over which they fade out
+const TRAIL_CAPACITY: usize = 200_000;
+const TRAIL_FADE: u32 = 300;
+
+// frames between two updates of the lakes the droplets settle in
+const LAKE_PERIOD: u32 = 30;
+
+// frames between two glacier passes
+const GLACIER_PERIOD: u32 = 20;
+
+// the rng every random draw of the simulation goes through, seeded from Seed
+pub struct SimRng(pub StdRng);
+
+impl FromWorld for SimRng {
+    fn from_world(world: &mut World) -> Self {
+        let seed = world
+            .get_resource::<Seed>()
+            .expect("missing Seed resource")
+            .0;
+        SimRng(StdRng::seed_from_u64(seed as u64))
+    }
+}
+
+// freezes the simulation, the views keep drawing the frozen state
+#[derive(Default)]
+pub struct SimControl {
+    pub paused: bool,
+    // advance a single tick while paused
+    pub step: bool,
+}
+
+impl SimControl {
+    // whether the simulation advances this frame
+    fn running(&self) -> bool {
+        !self.paused || self.step
+    }
+}
+
+#[derive(Serialize)]
+pub struct Rainfall {
+    // droplets spawned per frame
+    per_frame: u32,
+    // 0 rains uniformly, higher values make it rain more on high ground
+    altitude_bias: f32,
 }
 
+impl Rainfall {
+    pub fn from_args() -> Self {
+        Rainfall {
+            per_frame: arg("--rain").unwrap_or(5),
+            altitude_bias: arg("--rain-bias").unwrap_or(0.).max(0.),
+        }
+    }
+}
+
+// tries to draw a rain position before giving up on the bias
+const RAIN_TRIES: u32 = 16;
+
+// which terrain an entity belongs to, the main one is 0 and the compared one 1
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TerrainId(pub u32);
+
+// the terrain the main one is compared against, eroded with its own parameters
+// while the main one follows the ErosionParams resource
+pub struct Compared(pub ErosionParams);
+
+// drops a source held back by the droplet cap keeps for when there's room again,
+// so it doesn't release a flood once the cap is lifted
+const MAX_STOCK: f32 = 4.;
+
 pub struct Source {
     pub pos: Vec2,
     flux: f32,
@@ -26,7 +106,7 @@ pub struct Source {
 }
 
 impl Source {
-    fn new(pos: Vec2, flux: f32) -> Self {
+    pub fn new(pos: Vec2, flux: f32) -> Self {
         Source {
             pos: pos,
             flux: flux,
@@ -34,183 +114,908 @@ impl Source {
         }
     }
 
-    fn flow(&mut self) -> u32 {
-        self.stock = self.stock + self.flux;
-        let drops = self.stock.floor();
+    // drops to spawn this frame, at most `room`
+    fn flow(&mut self, room: usize) -> u32 {
+        self.stock = (self.stock + self.flux).min(MAX_STOCK + self.flux);
+        let drops = self.stock.floor().min(room as f32);
         self.stock = self.stock - drops;
         drops as u32
     }
 }
 
-pub struct Droplet {
-    pub pos: Vec2,
-    dir: Vec2,
-    vel: f32,
-    pub water: f32,
-    sediment: f32,
+// which droplets get despawned to make room for fresh rain when too many are alive
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CullPolicy {
+    Off,
+    // the ones carrying the least water * sediment
+    LeastActive,
 }
 
-impl Droplet {
-    fn new(pos: Vec2) -> Self {
-        Droplet {
-            pos: pos,
-            dir: Vec2::default(),
-            vel: 0.,
-            water: 1.,
-            sediment: 0.,
+impl FromStr for CullPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(CullPolicy::Off),
+            "least-active" => Ok(CullPolicy::LeastActive),
+            _ => Err(format!(
+                "unknown cull policy {:?}, expected off or least-active",
+                s
+            )),
         }
     }
 }
 
-pub fn unroll(pos: Vec2, size: usize) -> usize {
-    let x = if pos.x < 0. {
-        0
-    } else if pos.x >= size as f32 {
-        size - 1
-    } else {
-        pos.x as usize
-    };
-    let y = if pos.y < 0. {
-        0
-    } else if pos.y >= size as f32 {
-        size - 1
-    } else {
-        pos.y as usize
+pub struct DropletBudget {
+    policy: CullPolicy,
+    // culling starts above this many droplets
+    soft_cap: usize,
+    // and brings the count back down to this
+    target: usize,
+    // frames between two culls
+    period: u32,
+    // no droplet is spawned while this many are alive
+    pub max_droplets: usize,
+}
+
+impl DropletBudget {
+    pub fn from_args() -> Self {
+        let soft_cap = arg("--cull-above").unwrap_or(10000);
+        DropletBudget {
+            policy: arg("--cull").unwrap_or(CullPolicy::Off),
+            soft_cap: soft_cap,
+            target: arg("--cull-to").unwrap_or(soft_cap * 3 / 4),
+            period: arg("--cull-period").unwrap_or(30).max(1),
+            max_droplets: arg("--max-droplets").unwrap_or(100_000),
+        }
+    }
+}
+
+// caps the droplet steps simulated per frame so large droplet counts don't stall the render,
+// the droplets left out are stepped on the next frames
+pub struct ErosionBudget {
+    pub max_steps_per_frame: u32,
+}
+
+impl ErosionBudget {
+    pub fn from_args() -> Self {
+        ErosionBudget {
+            max_steps_per_frame: arg("--max-steps").unwrap_or(u32::MAX).max(1),
+        }
+    }
+}
+
+// the wind moving the loose sediment of the dry regions into dunes
+#[derive(Serialize)]
+pub struct Wind {
+    enabled: bool,
+    // cells above this height are dry enough, if no river runs through them
+    dry_height: f32,
+}
+
+impl Wind {
+    pub fn from_args() -> Self {
+        Wind {
+            enabled: flag("--wind"),
+            dry_height: arg("--dry-height").unwrap_or(0.4),
+        }
+    }
+}
+
+// ice flowing down from the high ground, carving U-shaped valleys
+#[derive(Serialize)]
+pub struct Glacier {
+    enabled: bool,
+    // height above which the ground is covered in ice
+    altitude: f32,
+    // height carved per unit of slope and pass
+    rate: f32,
+}
+
+impl Glacier {
+    pub fn from_args() -> Self {
+        Glacier {
+            enabled: flag("--glacier"),
+            altitude: arg("--glacier-height").unwrap_or(0.6),
+            rate: arg("--glacier-rate").unwrap_or(0.5),
+        }
+    }
+}
+
+// positions tried when no number of sources is asked for, a source on each high enough
+const SOURCE_TRIES: usize = 400;
+// tries to place each source asked for before giving up on the threshold
+const TRIES_PER_SOURCE: usize = 10;
+
+// the sources placed on the generated terrain
+pub struct SourceConfig {
+    // number of sources wanted, fewer are placed if the threshold is too high to find them
+    pub count: Option<usize>,
+    // height above which sources are placed
+    pub threshold: f32,
+    // droplets per frame of each source
+    pub flux: f32,
+}
+
+impl SourceConfig {
+    pub fn from_args() -> Self {
+        SourceConfig {
+            count: arg("--sources"),
+            threshold: arg("--source-threshold").unwrap_or(0.3),
+            flux: arg("--source-flux").unwrap_or(0.01),
+        }
+    }
+}
+
+// cells the droplets of the main terrain went through lately, drawn as fading trails in 2D
+#[derive(Default)]
+pub struct Trails {
+    pub enabled: bool,
+    // every cell visited with the tick it was on, oldest first
+    pub visits: VecDeque<(usize, u32)>,
+    tick: u32,
+}
+
+impl Trails {
+    fn record(&mut self, cells: impl Iterator<Item = usize>) {
+        self.tick += 1;
+        let tick = self.tick;
+        self.visits.extend(cells.map(|cell| (cell, tick)));
+        while let Some(&(_, visited)) = self.visits.front() {
+            if self.visits.len() <= TRAIL_CAPACITY && tick - visited < TRAIL_FADE {
+                break;
+            }
+            self.visits.pop_front();
+        }
+    }
+
+    // 1 for a visit on the last tick down to 0 once it faded out
+    pub fn recency(&self, tick: u32) -> f32 {
+        1. - (self.tick - tick) as f32 / TRAIL_FADE as f32
+    }
+}
+
+// droplets alive at the start of the frame and spawned since, to stop spawning at the cap
+#[derive(Default)]
+pub struct Population(pub usize);
+
+// number of droplets spawned so far, gives each one its order
+#[derive(Default)]
+pub struct DropletCount(u64);
+
+impl DropletCount {
+    fn next(&mut self) -> u64 {
+        self.0 += 1;
+        self.0 - 1
+    }
+}
+
+// running totals of the simulation of one terrain
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SedimentStats {
+    // sum of the eroded and deposited heights
+    pub sediment_moved: f64,
+    // sediment the droplets still carried when they were despawned, with it and the sediment
+    // they carry the total height of the terrain doesn't change
+    pub sediment_lost: f64,
+}
+
+// the running totals of each terrain, so the compared one doesn't add up with the main one
+#[derive(Default)]
+pub struct ErosionStats {
+    terrains: Vec<SedimentStats>,
+}
+
+impl ErosionStats {
+    pub fn terrain(&self, id: TerrainId) -> SedimentStats {
+        self.terrains
+            .get(id.0 as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn terrain_mut(&mut self, id: TerrainId) -> &mut SedimentStats {
+        let i = id.0 as usize;
+        if self.terrains.len() <= i {
+            self.terrains.resize(i + 1, SedimentStats::default());
+        }
+        &mut self.terrains[i]
+    }
+}
+
+// the terrain being generated on another thread so the window stays responsive,
+// it's spawned with its sources once it's done
+struct Generation(Mutex<Receiver<Elevation>>);
+
+fn setup_elevation(
+    mut commands: Commands,
+    pool: Res<AsyncComputeTaskPool>,
+    config: Res<TerrainConfig>,
+    seed: Res<Seed>,
+) {
+    start_generation(&mut commands, &pool, &config, *seed);
+}
+
+// generates the terrain of seed in the background, at startup and when the simulation is reset,
+// a generation still running is abandoned
+pub fn start_generation(
+    commands: &mut Commands,
+    pool: &AsyncComputeTaskPool,
+    config: &TerrainConfig,
+    seed: Seed,
+) {
+    let (sender, receiver) = mpsc::channel();
+    let config = config.clone();
+    pool.spawn(async move {
+        // the receiver is gone if the generation was abandoned
+        sender.send(generate(&config, seed)).ok();
+    })
+    .detach();
+    commands.insert_resource(Generation(Mutex::new(receiver)));
+}
+
+fn spawn_generated(
+    mut commands: Commands,
+    generation: Option<Res<Generation>>,
+    config: Res<TerrainConfig>,
+    params: Res<ErosionParams>,
+    sources: Res<SourceConfig>,
+    mut rng: ResMut<SimRng>,
+) {
+    let elevation = match generation.map(|g| g.0.lock().unwrap().try_recv()) {
+        Some(Ok(elevation)) => elevation,
+        _ => return,
     };
-    x % size + y * size
-}
-pub struct Elevation {
-    pub data: Vec<f32>,
-    size: usize,
-}
-
-impl Elevation {
-    fn new(size: usize, noise: Fbm) -> Self {
-        let sizef = size as f32;
-        Elevation {
-            data: iproduct!(0..size, 0..size)
-                .map(|(x, y)| (2. * (x as f32) / sizef - 1., 2. * (y as f32) / sizef - 1.))
-                .map(|(x, y)| {
-                    noise.get([x as f64, y as f64]) as f32 - ((x * x + y * y) as f32).sqrt() + 0.5
-                })
-                //.map(|(x, y)| (x as f32 / sizef) * (y as f32 / sizef).max(0.5))
-                .collect(),
-            size: size,
-        }
-    }
-
-    pub fn grad(&self, mut i: usize) -> Vec2 {
-        if i % self.size == self.size - 1 {
-            i -= 1;
-        }
-        if i + self.size >= self.data.len() {
-            i -= self.size;
-        }
-        Vec2::new(
-            (self.data[i + 1] - self.data[i]) * 0.5
-                + (self.data[i + 1 + self.size] - self.data[i + self.size]) * 0.5,
-            (self.data[i + self.size] - self.data[i]) * 0.5
-                + (self.data[i + 1 + self.size] - self.data[i + 1]) * 0.5,
-        )
-    }
-
-    fn add(&mut self, pos: Vec2, v: f32) {
-        for dx in -1i32..=1 {
-            for dy in -1i32..=1 {
-                let delta = Vec2::new(dx as f32, dy as f32);
-                let dist = dx.abs() + dy.abs();
-                self.data[unroll(pos + delta, self.size)] += v * if dist == 2 {
-                    0.05
-                } else if dist == 1 {
-                    0.1
-                } else {
-                    0.4
-                };
+    commands.remove_resource::<Generation>();
+    spawn_terrain(
+        &mut commands,
+        &config,
+        elevation,
+        &params,
+        &sources,
+        &mut rng.0,
+    );
+}
+
+fn generate(config: &TerrainConfig, seed: Seed) -> Elevation {
+    let loaded = config.load.as_ref().and_then(|path| {
+        Elevation::load(path, config.size)
+            .map_err(|e| println!("couldn't load {}: {}", path.display(), e))
+            .ok()
+    });
+    let mut elevation = match (loaded, &config.heightmap) {
+        (Some(elevation), _) => elevation.with_layout(config.grid, config.topology),
+        (None, Some(path)) => {
+            let elevation = Elevation::from_image(path, config.size)
+                .unwrap_or_else(|e| panic!("couldn't load heightmap {}: {}", path.display(), e));
+            // both grids store size x size cells, hex just offsets every other row
+            let mut elevation = elevation.with_layout(config.grid, config.topology);
+            if config.heightmap_falloff {
+                elevation.apply_falloff(&config.island, seed.0.wrapping_add(4));
             }
+            elevation
+        }
+        (None, None) => {
+            let noise = config.noise.build(seed.0, &config.fractal);
+            Elevation::new(config, noise.as_ref(), seed.0.wrapping_add(4))
         }
+    };
+    if let Some(width) = config.beaches {
+        elevation.generate_beaches(width, config.sea_level);
+    }
+    if config.hardness > 0. {
+        elevation.set_hardness(seed.0.wrapping_add(2), config.hardness);
+    }
+    if config.precipitation > 0. {
+        elevation.set_precipitation(seed.0.wrapping_add(3), config.precipitation);
     }
+    if let Some(azimuth) = config.rain_shadow {
+        elevation.set_rain_shadow(azimuth);
+    }
+    elevation
 }
 
-fn setup_elevation(mut commands: Commands) {
-    let elevation = Elevation::new(SIZE, Fbm::new().set_seed(rand::random::<u32>()));
-    // initialize the sources
+// spawns the terrains and their sources
+fn spawn_terrain(
+    commands: &mut Commands,
+    config: &TerrainConfig,
+    elevation: Elevation,
+    params: &ErosionParams,
+    sources: &SourceConfig,
+    rng: &mut StdRng,
+) {
+    let terrains = if config.compare { 2 } else { 1 };
+    // initialize the sources, at the same places on both terrains
     let mut count = 0;
-    for _ in 0..400 {
-        let pos = rand_pos();
-        if elevation.data[unroll(pos, SIZE)] > 0.3 {
+    let tries = sources
+        .count
+        .map_or(SOURCE_TRIES, |wanted| wanted * TRIES_PER_SOURCE);
+    for _ in 0..tries {
+        if sources.count.map_or(false, |wanted| count >= wanted) {
+            break;
+        }
+        let pos = rand_pos(config, rng);
+        if elevation.data[elevation.index(pos)] > sources.threshold {
             count += 1;
-            commands.spawn().insert(Source::new(pos, 0.01));
+            for id in 0..terrains {
+                commands
+                    .spawn()
+                    .insert(Source::new(pos, sources.flux))
+                    .insert(TerrainId(id));
+            }
         }
     }
     println!("{} rivers", count);
     // initialize the texture
-    commands.spawn().insert(elevation);
+    if config.compare {
+        commands
+            .spawn()
+            .insert(elevation.clone())
+            .insert(TerrainId(1))
+            .insert(Compared(args::erosion_params(params, "--compare-")));
+    }
+    commands.spawn().insert(elevation).insert(TerrainId(0));
 }
 
-fn rain(mut commands: Commands) {
-    for _ in 0..5 {
-        commands.spawn().insert(Droplet::new(rand_pos()));
+// a position in a cell drawn with probability proportional to its precipitation
+fn wet_pos(elevation: &Elevation, cells: &WeightedIndex<f32>, rng: &mut impl Rng) -> Vec2 {
+    let center = elevation.center(cells.sample(rng));
+    match elevation.grid {
+        // square cells span a unit square from their corner
+        Grid::Square => center + Vec2::new(rng.gen(), rng.gen()),
+        Grid::Hex => center,
     }
 }
 
-fn flows(mut commands: Commands, mut query: Query<&mut Source>) {
-    for mut source in query.iter_mut() {
-        let drops = source.flow();
+fn rain(
+    mut commands: Commands,
+    control: Res<SimControl>,
+    config: Res<TerrainConfig>,
+    rainfall: Res<Rainfall>,
+    mut rng: ResMut<SimRng>,
+    mut count: ResMut<DropletCount>,
+    budget: Res<DropletBudget>,
+    mut population: ResMut<Population>,
+    mut cells: Local<Option<WeightedIndex<f32>>>,
+    query_elevation: Query<&Elevation, Without<Compared>>,
+    added_elevation: Query<Entity, (Added<Elevation>, Without<Compared>)>,
+    query_droplets: Query<&Droplet>,
+) {
+    // the precipitation doesn't change so the distribution is only built once per terrain
+    if added_elevation.iter().next().is_some() {
+        *cells = None;
+    }
+    if !control.running() {
+        return;
+    }
+    population.0 = query_droplets.iter().count();
+    // no rain until the terrain is generated
+    let elevation = match query_elevation.single() {
+        Ok(elevation) => Some(elevation),
+        Err(_) => return,
+    };
+    let terrains = if config.compare { 2 } else { 1 };
+    if let (true, Some(elevation)) = (cells.is_none() && config.varied_rain(), elevation) {
+        *cells = WeightedIndex::new(&elevation.precipitation).ok();
+    }
+    let draw = |rng: &mut StdRng| match (&*cells, elevation) {
+        (Some(cells), Some(elevation)) => wet_pos(elevation, cells, rng),
+        _ => rand_pos(&config, rng),
+    };
+    for _ in 0..rainfall.per_frame {
+        if population.0 + terrains as usize > budget.max_droplets {
+            break;
+        }
+        let mut pos = draw(&mut rng.0);
+        // rejection sampling, a position is kept with probability height^bias
+        if let (Some(elevation), true) = (elevation, rainfall.altitude_bias > 0.) {
+            for _ in 1..RAIN_TRIES {
+                let h = elevation.data[elevation.index(pos)].clamp(0., 1.);
+                if rng.0.gen::<f32>() < h.powf(rainfall.altitude_bias) {
+                    break;
+                }
+                pos = draw(&mut rng.0);
+            }
+        }
+        // the compared terrain gets the same rain, drawn on the main one
+        for id in 0..terrains {
+            commands
+                .spawn()
+                .insert(Droplet::new(pos, count.next()))
+                .insert(TerrainId(id));
+        }
+        population.0 += terrains as usize;
+    }
+}
+
+fn flows(
+    mut commands: Commands,
+    control: Res<SimControl>,
+    mut count: ResMut<DropletCount>,
+    budget: Res<DropletBudget>,
+    mut population: ResMut<Population>,
+    mut query: Query<(&mut Source, &TerrainId)>,
+) {
+    if !control.running() {
+        return;
+    }
+    for (mut source, id) in query.iter_mut() {
+        let drops = source.flow(budget.max_droplets.saturating_sub(population.0));
+        population.0 += drops as usize;
         for _ in 0..drops {
-            commands.spawn().insert(Droplet::new(source.pos));
+            commands
+                .spawn()
+                .insert(Droplet::new(source.pos, count.next()))
+                .insert(*id);
         }
     }
 }
 
-fn evaporation(mut commands: Commands, query: Query<(Entity, &Droplet)>) {
-    for (entity, droplet) in query.iter() {
-        if droplet.water < f32::EPSILON {
+fn evaporation(
+    mut commands: Commands,
+    control: Res<SimControl>,
+    config: Res<TerrainConfig>,
+    mut stats: ResMut<ErosionStats>,
+    query: Query<(Entity, &Droplet, &TerrainId)>,
+) {
+    if !control.running() {
+        return;
+    }
+    let extent = config.grid.extent(config.size);
+    for (entity, droplet, id) in query.iter() {
+        if droplet.finished(extent) {
+            stats.terrain_mut(*id).sediment_lost += droplet.sediment as f64;
             commands.entity(entity).despawn();
         }
     }
 }
 
-fn hydrolic_erosion(
+fn cull_droplets(
+    mut commands: Commands,
+    control: Res<SimControl>,
+    budget: Res<DropletBudget>,
+    mut frame: Local<u32>,
+    mut stats: ResMut<ErosionStats>,
+    query: Query<(Entity, &Droplet, &TerrainId)>,
+) {
+    if !control.running() || query.iter().next().is_none() {
+        return;
+    }
+    *frame += 1;
+    if budget.policy == CullPolicy::Off || *frame % budget.period != 0 {
+        return;
+    }
+    let count = query.iter().count();
+    if count <= budget.soft_cap {
+        return;
+    }
+    let mut droplets: Vec<(Entity, f32, u64)> = query
+        .iter()
+        .map(|(entity, droplet, _)| (entity, droplet.water * droplet.sediment, droplet.order))
+        .collect();
+    // ties are broken by age so the same droplets are culled on every run
+    droplets.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.2.cmp(&b.2)));
+    for (entity, _, _) in droplets.iter().take(count - budget.target.min(count)) {
+        if let Ok((_, droplet, id)) = query.get(*entity) {
+            stats.terrain_mut(*id).sediment_lost += droplet.sediment as f64;
+        }
+        commands.entity(*entity).despawn();
+    }
+}
+
+fn thermal_erosion(
+    control: Res<SimControl>,
+    mut frame: Local<u32>,
+    mut query_elevation: Query<&mut Elevation>,
+) {
+    // the periods start with the terrain, not with how long it took to generate
+    if !control.running() || query_elevation.iter().next().is_none() {
+        return;
+    }
+    *frame += 1;
+    if *frame % THERMAL_PERIOD != 0 {
+        return;
+    }
+    for mut elevation in query_elevation.iter_mut() {
+        elevation.thermal_step();
+    }
+}
+
+fn wind_erosion(
+    control: Res<SimControl>,
+    wind: Res<Wind>,
+    mut frame: Local<u32>,
+    mut dry: Local<Vec<Vec<bool>>>,
+    mut query_elevation: Query<(&mut Elevation, &TerrainId)>,
+) {
+    // like the lakes, the dry cells of a replaced terrain are forgotten right away
+    for (elevation, id) in query_elevation.iter_mut() {
+        if elevation.is_added() {
+            if let Some(dry) = dry.get_mut(id.0 as usize) {
+                dry.clear();
+            }
+        }
+    }
+    if !control.running() || query_elevation.iter().next().is_none() {
+        return;
+    }
+    *frame += 1;
+    if !wind.enabled || *frame % WIND_PERIOD != 0 {
+        return;
+    }
+    let refresh = *frame % (WIND_PERIOD * DRY_PERIOD) == 0;
+    for (mut elevation, id) in query_elevation.iter_mut() {
+        let terrain = id.0 as usize;
+        if dry.len() <= terrain {
+            dry.resize(terrain + 1, Vec::new());
+        }
+        if refresh || dry[terrain].len() != elevation.data.len() {
+            dry[terrain] = flow_accumulation(&elevation)
+                .into_iter()
+                .zip(elevation.data.iter())
+                .map(|(flow, h)| flow < RIVER_FLOW && *h > wind.dry_height)
+                .collect();
+        }
+        elevation.wind_step(WIND_DIR, &dry[terrain]);
+    }
+}
+
+fn glacial_erosion(
+    control: Res<SimControl>,
+    glacier: Res<Glacier>,
+    mut frame: Local<u32>,
     mut query_elevation: Query<&mut Elevation>,
-    mut query_droplet: Query<&mut Droplet>,
 ) {
-    if let Ok(mut elevation) = query_elevation.single_mut() {
-        for mut droplet in query_droplet.iter_mut() {
-            let g = elevation.grad(unroll(droplet.pos, SIZE));
-            droplet.dir = (droplet.dir * INERTIA * droplet.vel - g * (1. - INERTIA * droplet.vel))
-                .normalize();
-            let old_pos: Vec2 = droplet.pos;
-            droplet.pos = droplet.pos + droplet.dir;
-            let h = elevation.data[unroll(droplet.pos, SIZE)];
-            // if we're below water level we immediatly deposit all sediment and water
-            let hdif = elevation.data[unroll(old_pos, SIZE)] - h;
-            let cdif = f32::max(hdif, MINSLOPE) * droplet.vel * droplet.water * CAPACITY
-                - droplet.sediment;
-            if cdif < 0. {
-                // we deposit sediment
-                let deposit = -cdif * DEPOSITION;
-                droplet.sediment = droplet.sediment - deposit;
-                elevation.add(old_pos, deposit);
-            } else if h >= 0. {
-                // we draw sediment if we're above water
-                let erosion = f32::min(cdif * EROSION, hdif);
-                droplet.sediment = droplet.sediment + erosion;
-                elevation.add(old_pos, -erosion);
+    if !control.running() || query_elevation.iter().next().is_none() {
+        return;
+    }
+    *frame += 1;
+    if !glacier.enabled || *frame % GLACIER_PERIOD != 0 {
+        return;
+    }
+    for mut elevation in query_elevation.iter_mut() {
+        elevation.glacier_step(glacier.altitude, glacier.rate);
+    }
+}
+
+// each terrain is eroded by its own droplets only
+fn hydrolic_erosion(
+    control: Res<SimControl>,
+    mut query_elevation: Query<(&mut Elevation, &TerrainId, Option<&Compared>)>,
+    mut query_droplet: Query<(&mut Droplet, &TerrainId)>,
+    params: Res<ErosionParams>,
+    meander: Res<Meander>,
+    config: Res<TerrainConfig>,
+    budget: Res<ErosionBudget>,
+    mut stats: ResMut<ErosionStats>,
+    mut trails: ResMut<Trails>,
+    mut next: Local<Vec<usize>>,
+    mut kernel: Local<Kernel>,
+    mut delta_kernel: Local<Kernel>,
+    mut frame: Local<u32>,
+    mut lakes: Local<Vec<Vec<f32>>>,
+) {
+    // a new terrain after a reset or a load has none of the lakes of the old one,
+    // checked even when paused so the next tick doesn't settle droplets in them
+    for (elevation, id, _) in query_elevation.iter_mut() {
+        if elevation.is_added() {
+            if let Some(lakes) = lakes.get_mut(id.0 as usize) {
+                lakes.clear();
             }
-            droplet.vel = (droplet.vel.powi(2) + hdif).max(0.).sqrt();
-            droplet.water = droplet.water * (1. - EVAPORATION * (1. - droplet.vel));
         }
     }
+    if !control.running() || query_elevation.iter().next().is_none() {
+        return;
+    }
+    *frame += 1;
+    for (mut elevation, id, compared) in query_elevation.iter_mut() {
+        let params = compared.map_or(&*params, |compared| &compared.0);
+        if kernel.radius() != params.radius {
+            *kernel = Kernel::new(params.radius);
+        }
+        if delta_kernel.radius() != params.radius * DELTA_SPREAD {
+            *delta_kernel = Kernel::new(params.radius * DELTA_SPREAD);
+        }
+        // the query order depends on the ECS internals, the spawn order doesn't
+        let mut droplets: Vec<Mut<Droplet>> = query_droplet
+            .iter_mut()
+            .filter(|(_, droplet_id)| *droplet_id == id)
+            .map(|(droplet, _)| droplet)
+            .collect();
+        droplets.sort_by_key(|droplet| droplet.order);
+        // over budget, the droplets take turns starting where the last frame stopped
+        let max_steps = budget.max_steps_per_frame as usize;
+        let terrain = id.0 as usize;
+        if next.len() <= terrain {
+            next.resize(terrain + 1, 0);
+        }
+        if droplets.len() > max_steps {
+            let start = next[terrain] % droplets.len();
+            droplets.rotate_left(start);
+            droplets.truncate(max_steps);
+            next[terrain] = start + max_steps;
+        }
+        // every droplet steps in parallel on the terrain as it was at the start of the frame,
+        // then their changes are applied in order so the result doesn't depend on threads
+        let deltas = step_parallel(
+            &mut droplets,
+            &elevation,
+            params,
+            &meander,
+            config.sea_level,
+        );
+        for (delta, droplet) in deltas.into_iter().zip(droplets.iter()) {
+            if let Some((pos, v)) = delta {
+                stats.terrain_mut(*id).sediment_moved += v.abs() as f64;
+                elevation.record_transport(pos, v, droplet.transport(pos));
+                if v > 0. && elevation.in_delta(pos, config.sea_level) {
+                    elevation.deposit(pos, v, &delta_kernel);
+                } else {
+                    elevation.deposit(pos, v, &kernel);
+                }
+            }
+        }
+        // filling the basins is too slow for every frame, the lakes lag a bit behind the terrain
+        if lakes.len() <= terrain {
+            lakes.resize(terrain + 1, Vec::new());
+        }
+        if lakes[terrain].len() != elevation.data.len() || *frame % LAKE_PERIOD == 0 {
+            lakes[terrain] = fill_depressions(&elevation, config.sea_level);
+        }
+        // droplets reaching a lake drop their sediment on its floor and stop there
+        for droplet in droplets.iter_mut() {
+            let i = elevation.index(droplet.pos);
+            let h = elevation.data[i];
+            if lakes[terrain][i] > h && h >= config.sea_level {
+                let pos = droplet.pos;
+                let sediment = droplet.settle();
+                if sediment > 0. {
+                    stats.terrain_mut(*id).sediment_moved += sediment as f64;
+                    elevation.record_transport(pos, sediment, droplet.transport(pos));
+                    elevation.deposit(pos, sediment, &kernel);
+                }
+            }
+        }
+        if trails.enabled && compared.is_none() {
+            trails.record(droplets.iter().map(|droplet| elevation.index(droplet.pos)));
+        }
+    }
+}
+
+// the requested step has been simulated by every system
+fn end_step(mut control: ResMut<SimControl>) {
+    control.step = false;
 }
 
 pub struct Erosion;
 
 impl Plugin for Erosion {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_startup_system(setup_elevation.system())
-            .add_system(rain.system())
-            .add_system(flows.system())
-            .add_system(evaporation.system())
-            .add_system(hydrolic_erosion.system());
+        let seed = app
+            .world()
+            .get_resource::<Seed>()
+            .expect("missing Seed resource")
+            .0;
+        let params = arg::<Preset>("--preset").map_or_else(ErosionParams::default, |p| p.params());
+        app.insert_resource(params)
+            .init_resource::<SimRng>()
+            .insert_resource(args::meander(seed))
+            .insert_resource(DropletBudget::from_args())
+            .insert_resource(ErosionBudget::from_args())
+            .insert_resource(Rainfall::from_args())
+            .insert_resource(Wind::from_args())
+            .insert_resource(Glacier::from_args())
+            .insert_resource(SourceConfig::from_args())
+            .init_resource::<ErosionStats>()
+            .init_resource::<DropletCount>()
+            .init_resource::<Population>()
+            .init_resource::<SimControl>()
+            .init_resource::<Trails>()
+            .add_startup_system(setup_elevation.system())
+            .add_system(spawn_generated.system().before("rain"))
+            // the systems drawing droplets or changing the terrain run in a fixed order
+            // so a seed always gives the same terrain
+            .add_system(rain.system().label("rain"))
+            .add_system(flows.system().label("flows").after("rain"))
+            // the droplets are despawned once they've all been spawned and taken their step
+            .add_system(
+                evaporation
+                    .system()
+                    .label("evaporation")
+                    .after("rain")
+                    .after("flows")
+                    .after("hydrolic"),
+            )
+            .add_system(cull_droplets.system().after("evaporation"))
+            .add_system(hydrolic_erosion.system().label("hydrolic").after("flows"))
+            .add_system(thermal_erosion.system().label("thermal").after("hydrolic"))
+            .add_system(wind_erosion.system().label("wind").after("thermal"))
+            .add_system(glacial_erosion.system().after("wind"))
+            .add_system_to_stage(CoreStage::PostUpdate, end_step.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn heights(world: &mut World) -> Option<Vec<f32>> {
+        world
+            .query::<&Elevation>()
+            .iter(world)
+            .next()
+            .map(|elevation| elevation.data.clone())
+    }
+
+    // the world after running the whole simulation of a small terrain for some ticks
+    fn simulate(seed: u32, ticks: u32) -> World {
+        simulate_with(seed, ticks, |_| {})
+    }
+
+    // simulate with the resources of the plugin changed by setup
+    fn simulate_with(seed: u32, ticks: u32, setup: impl FnOnce(&mut AppBuilder)) -> World {
+        let mut app = App::build();
+        app.insert_resource(TerrainConfig::new(32))
+            .insert_resource(Seed(seed))
+            .add_plugins(MinimalPlugins)
+            .add_plugin(Erosion);
+        setup(&mut app);
+        // the terrain is generated in the background
+        for _ in 0..10_000 {
+            if heights(&mut app.app.world).is_some() {
+                break;
+            }
+            app.app.update();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(
+            heights(&mut app.app.world).is_some(),
+            "the terrain was never generated"
+        );
+        for _ in 0..ticks {
+            app.app.update();
+        }
+        std::mem::take(&mut app.app.world)
+    }
+
+    #[test]
+    fn same_seed_gives_same_terrain() {
+        let terrain = |seed: u32| heights(&mut simulate(seed, 200)).unwrap();
+        let first = terrain(3);
+        assert_eq!(first, terrain(3));
+        assert_ne!(first, terrain(4));
+    }
+
+    #[test]
+    fn same_seed_gives_same_rain() {
+        // the droplets rained on the first tick, after their first step
+        let rain = |seed: u32| {
+            let mut world = simulate(seed, 1);
+            let mut droplets: Vec<(u64, Vec2)> = world
+                .query::<&Droplet>()
+                .iter(&world)
+                .map(|droplet| (droplet.order, droplet.pos))
+                .collect();
+            droplets.sort_by_key(|(order, _)| *order);
+            droplets
+        };
+        let first = rain(3);
+        assert!(!first.is_empty());
+        assert_eq!(first, rain(3));
+        assert_ne!(first, rain(4));
+    }
+
+    #[test]
+    fn rain_falls_in_proportion_to_the_precipitation() {
+        let mut elevation = Elevation::from_data(vec![0.; 16], 4);
+        elevation.precipitation = vec![0.; 16];
+        elevation.precipitation[0] = 3.;
+        elevation.precipitation[5] = 1.;
+        let cells = WeightedIndex::new(&elevation.precipitation).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut hits = [0; 16];
+        for _ in 0..20_000 {
+            hits[elevation.index(wet_pos(&elevation, &cells, &mut rng))] += 1;
+        }
+        assert_eq!(hits[0] + hits[5], 20_000);
+        assert!((hits[0] as f32 / 20_000. - 0.75).abs() < 0.02);
+    }
+
+    #[test]
+    fn no_droplet_is_spawned_past_the_cap() {
+        let mut world = simulate_with(3, 50, |app| {
+            app.insert_resource(Rainfall {
+                per_frame: 50,
+                altitude_bias: 0.,
+            })
+            .insert_resource(DropletBudget {
+                max_droplets: 20,
+                ..DropletBudget::from_args()
+            });
+        });
+        let alive = world.query::<&Droplet>().iter(&world).count();
+        assert!(alive > 0 && alive <= 20, "{} droplets", alive);
+    }
+
+    #[test]
+    fn a_starved_source_holds_back_a_few_drops_only() {
+        let mut source = Source::new(Vec2::ZERO, 0.5);
+        for _ in 0..100 {
+            assert_eq!(source.flow(0), 0);
+            assert!(source.stock <= MAX_STOCK + source.flux);
+        }
+        // once there's room it lets out what it held back, then goes back to its flux
+        assert_eq!(
+            source.flow(usize::MAX),
+            (MAX_STOCK + source.flux).floor() as u32
+        );
+        let drops: u32 = (0..10).map(|_| source.flow(usize::MAX)).sum();
+        assert_eq!(drops, 5);
+    }
+
+    #[test]
+    fn an_unreachable_threshold_places_no_source() {
+        let count_sources = |count: Option<usize>, threshold: f32| {
+            let mut world = simulate_with(3, 0, |app| {
+                app.insert_resource(SourceConfig {
+                    count: count,
+                    threshold: threshold,
+                    flux: 0.01,
+                });
+            });
+            world.query::<&Source>().iter(&world).count()
+        };
+        // above the highest point, the tries run out instead of looking forever
+        assert_eq!(count_sources(Some(50), 10.), 0);
+        assert_eq!(count_sources(Some(50), -10.), 50);
+        // without a number, every position tried gets one
+        assert_eq!(count_sources(None, -10.), 400);
+    }
+
+    #[test]
+    fn a_droplet_reaching_a_lake_drops_its_sediment_and_goes() {
+        // a basin in the middle of a plateau
+        let mut data = vec![1.; 81];
+        for i in 0..81 {
+            let (x, y) = (i % 9, i / 9);
+            if (3..6).contains(&x) && (3..6).contains(&y) {
+                data[i] = 0.5;
+            }
+        }
+        let mut droplet = Droplet::new(Vec2::new(4.5, 4.5), 0);
+        droplet.sediment = 0.2;
+        let mut app = App::build();
+        app.insert_resource(TerrainConfig::new(9))
+            .insert_resource(ErosionParams::default())
+            .insert_resource(Meander::default())
+            .insert_resource(ErosionBudget {
+                max_steps_per_frame: u32::MAX,
+            })
+            .init_resource::<SimControl>()
+            .init_resource::<ErosionStats>()
+            .init_resource::<Trails>()
+            .add_system(hydrolic_erosion.system().label("hydrolic"))
+            .add_system(evaporation.system().after("hydrolic"));
+        let world = app.world_mut();
+        world
+            .spawn()
+            .insert(Elevation::from_data(data, 9))
+            .insert(TerrainId(0));
+        world.spawn().insert(droplet).insert(TerrainId(0));
+        app.app.update();
+        let world = &mut app.app.world;
+        assert_eq!(world.query::<&Droplet>().iter(world).count(), 0);
+        let elevation = world.query::<&Elevation>().iter(world).next().unwrap();
+        let raised: f32 = elevation
+            .data
+            .iter()
+            .map(|h| h - 0.5)
+            .filter(|d| *d < 0.4)
+            .sum();
+        assert!((raised - 0.2).abs() < 1e-5);
+        let stats = world.get_resource::<ErosionStats>().unwrap();
+        assert!((stats.terrain(TerrainId(0)).sediment_moved - 0.2).abs() < 1e-5);
     }
 }
@@ -0,0 +1,145 @@
+use crate::args::arg;
+use crate::draw2d::Terrain2d;
+use crate::draw3d::HeightScale;
+use crate::erosion::Compared;
+use crate::input::{Action, KeyBindings};
+use crate::view::ViewMode;
+use bevy::prelude::*;
+use image::RgbaImage;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use terrain_gen::config::{Seed, TerrainConfig};
+use terrain_gen::files::{export_flow, export_gltf, export_obj, export_stl};
+use terrain_gen::palette::Palette;
+use terrain_gen::terrain::Elevation;
+
+pub struct ExportConfig {
+    pub flow: PathBuf,
+    pub state: PathBuf,
+    // thickness of the solid under the lowest point of the printed terrain, in world units
+    pub stl_base: f32,
+}
+
+impl ExportConfig {
+    pub fn from_args() -> Self {
+        ExportConfig {
+            flow: arg("--export-flow").unwrap_or_else(|| PathBuf::from("flow.bin")),
+            state: arg("--save").unwrap_or_else(|| PathBuf::from("terrain.bin")),
+            stl_base: arg("--stl-base").unwrap_or(5.),
+        }
+    }
+}
+
+fn export(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    config: Res<ExportConfig>,
+    terrain: Res<TerrainConfig>,
+    scale: Res<HeightScale>,
+    palette: Res<Palette>,
+    seed: Res<Seed>,
+    query: Query<&Elevation, Without<Compared>>,
+) {
+    if let Ok(elevation) = query.single() {
+        if bindings.just_pressed(&keys, Action::ExportHeightmap) {
+            let path = Path::new("heightmap.png");
+            match elevation.export_png_16(path) {
+                Ok(()) => println!("heightmap saved to {}", path.display()),
+                Err(e) => println!("couldn't save heightmap: {}", e),
+            }
+        }
+        if bindings.just_pressed(&keys, Action::ExportMesh) {
+            let path = Path::new("terrain.obj");
+            match export_obj(elevation, terrain.sea_level, scale.0, path) {
+                Ok(()) => println!("mesh saved to {}", path.display()),
+                Err(e) => println!("couldn't save mesh: {}", e),
+            }
+        }
+        if bindings.just_pressed(&keys, Action::ExportGltf) {
+            let path = Path::new("terrain.glb");
+            match export_gltf(elevation, terrain.sea_level, scale.0, &palette, path) {
+                Ok(()) => println!("colored mesh saved to {}", path.display()),
+                Err(e) => println!("couldn't save colored mesh: {}", e),
+            }
+        }
+        if bindings.just_pressed(&keys, Action::ExportStl) {
+            let path = Path::new("terrain.stl");
+            match export_stl(elevation, scale.0, config.stl_base, path) {
+                Ok(()) => println!("solid saved to {}", path.display()),
+                Err(e) => println!("couldn't save solid: {}", e),
+            }
+        }
+        if bindings.just_pressed(&keys, Action::SaveState) {
+            match elevation.save(*seed, &config.state) {
+                Ok(()) => println!("terrain saved to {}", config.state.display()),
+                Err(e) => println!("couldn't save terrain: {}", e),
+            }
+        }
+        if bindings.just_pressed(&keys, Action::ExportFlow) {
+            match export_flow(elevation, &config.flow) {
+                Ok(()) => println!("flow accumulation saved to {}", config.flow.display()),
+                Err(e) => println!("couldn't save flow accumulation: {}", e),
+            }
+        }
+    }
+}
+
+// screenshots taken so far, to tell apart the ones taken in the same millisecond
+#[derive(Default)]
+pub struct Screenshots(u32);
+
+impl Screenshots {
+    // makes the image and saves it on another thread, drawing the 3D view again or encoding
+    // the png takes longer than a frame, the name has the seed and the time so no two collide
+    pub fn save(&mut self, seed: Seed, image: impl FnOnce() -> Option<RgbaImage> + Send + 'static) {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_millis());
+        let path = PathBuf::from(format!("screenshot_{}_{}_{}.png", seed.0, millis, self.0));
+        self.0 += 1;
+        thread::spawn(move || match image().map(|image| image.save(&path)) {
+            Some(Ok(())) => println!("screenshot saved to {}", path.display()),
+            Some(Err(e)) => println!("couldn't save screenshot: {}", e),
+            None => println!("couldn't save screenshot: the image doesn't match its size"),
+        });
+    }
+}
+
+// saves the 2D view as it's drawn, the 3D view is drawn again by draw3d
+fn screenshot_2d(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    view: Res<State<ViewMode>>,
+    seed: Res<Seed>,
+    query_mat: Query<&Handle<ColorMaterial>, With<Terrain2d>>,
+    materials: Res<Assets<ColorMaterial>>,
+    textures: Res<Assets<Texture>>,
+    mut screenshots: ResMut<Screenshots>,
+) {
+    if !bindings.just_pressed(&keys, Action::Screenshot) || *view.current() != ViewMode::TwoD {
+        return;
+    }
+    let texture = query_mat
+        .single()
+        .ok()
+        .and_then(|handle| materials.get(handle.id))
+        .and_then(|material| material.texture.as_ref())
+        .and_then(|texture| textures.get(texture.id));
+    if let Some(texture) = texture {
+        let (width, height) = (texture.size.width, texture.size.height);
+        let data = texture.data.clone();
+        screenshots.save(*seed, move || RgbaImage::from_raw(width, height, data));
+    }
+}
+
+pub struct Export;
+
+impl Plugin for Export {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ExportConfig::from_args())
+            .add_system(export.system())
+            .init_resource::<Screenshots>()
+            .add_system(screenshot_2d.system());
+    }
+}
@@ -0,0 +1,382 @@
+use crate::config::Seed;
+use crate::hydrology::flow_accumulation;
+use crate::mesh::{compute_normals, mesh_indices, mesh_positions};
+use crate::palette::{sediment_color, terrain_color, Palette};
+use crate::terrain::Elevation;
+use glam::Vec3;
+use image::{ImageBuffer, Luma};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// Writes the flow accumulation grid as raw little-endian data:
+// - bytes 0..4: width as u32
+// - bytes 4..8: height as u32
+// - then width * height f32 (4 bytes each) in row-major order, cell (x, y) at x + y * width
+// with numpy: np.fromfile(path, dtype="<f4", offset=8).reshape(height, width)
+pub fn export_flow(elevation: &Elevation, path: &Path) -> io::Result<()> {
+    let size = elevation.size() as u32;
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&size.to_le_bytes())?;
+    file.write_all(&size.to_le_bytes())?;
+    for flow in flow_accumulation(elevation) {
+        file.write_all(&(flow as f32).to_le_bytes())?;
+    }
+    file.flush()
+}
+
+fn read_u32(file: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    file.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+impl Elevation {
+    // Saves the terrain to resume from it later, little-endian:
+    // - bytes 0..4: size as u32
+    // - bytes 4..8: seed of the run as u32
+    // - then size * size f32 heights in row-major order
+    pub fn save(&self, seed: Seed, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&(self.size() as u32).to_le_bytes())?;
+        file.write_all(&seed.0.to_le_bytes())?;
+        for h in self.data.iter() {
+            file.write_all(&h.to_le_bytes())?;
+        }
+        file.flush()
+    }
+
+    // terrain saved by save, which must be size cells wide
+    pub fn load(path: &Path, size: usize) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let saved_size = read_u32(&mut file)? as usize;
+        if saved_size != size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the terrain is {} cells wide but --size is {}",
+                    saved_size, size
+                ),
+            ));
+        }
+        let seed = read_u32(&mut file)?;
+        let mut data = Vec::with_capacity(size * size);
+        for _ in 0..size * size {
+            data.push(f32::from_bits(read_u32(&mut file)?));
+        }
+        println!("loaded {} (seed {})", path.display(), seed);
+        Ok(Elevation::from_data(data, size))
+    }
+
+    // 16 bit grayscale heightmap normalized between the lowest land and the highest point,
+    // everything underwater is black
+    pub fn export_png_16(&self, path: &Path) -> io::Result<()> {
+        let size = self.size() as u32;
+        let stats = self.stats(0.);
+        let (min, max) = (stats.min.max(0.), stats.max);
+        let range = if max > min { max - min } else { 1. };
+        let pixels = self
+            .data
+            .iter()
+            .map(|h| (((h - min) / range).clamp(0., 1.) * u16::MAX as f32) as u16)
+            .collect();
+        ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(size, size, pixels)
+            .unwrap()
+            .save(path)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+// Wavefront OBJ of the terrain mesh as it's drawn in 3D
+pub fn export_obj(
+    elevation: &Elevation,
+    sea_level: f32,
+    height_mult: f32,
+    path: &Path,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for [x, y, z] in mesh_positions(elevation, sea_level, height_mult) {
+        writeln!(file, "v {} {} {}", x, y, z)?;
+    }
+    // obj indices start at 1
+    for face in mesh_indices(elevation.size(), elevation.grid).chunks(3) {
+        writeln!(file, "f {} {} {}", face[0] + 1, face[1] + 1, face[2] + 1)?;
+    }
+    file.flush()
+}
+
+// Binary glTF (.glb) of the terrain mesh as it's drawn in 3D, with its normals and colors:
+// - a 12 bytes header, then a JSON chunk describing the mesh and a binary chunk holding
+//   the positions, normals and colors as f32 VEC3 followed by the u32 triangle indices
+pub fn export_gltf(
+    elevation: &Elevation,
+    sea_level: f32,
+    height_mult: f32,
+    palette: &Palette,
+    path: &Path,
+) -> io::Result<()> {
+    let positions = mesh_positions(elevation, sea_level, height_mult);
+    let normals = compute_normals(elevation, sea_level, height_mult);
+    let colors: Vec<[f32; 3]> = (0..elevation.data.len())
+        .map(|i| {
+            let (h, g) = (elevation.data[i], elevation.grad(i).length());
+            let color = terrain_color(palette, h, g, sea_level);
+            sediment_color(palette, color, h, elevation.sediment[i], sea_level)
+        })
+        .collect();
+    let indices = mesh_indices(elevation.size(), elevation.grid);
+    let (mut min, mut max) = ([f32::INFINITY; 3], [f32::NEG_INFINITY; 3]);
+    for p in positions.iter() {
+        for c in 0..3 {
+            min[c] = min[c].min(p[c]);
+            max[c] = max[c].max(p[c]);
+        }
+    }
+    let mut bin: Vec<u8> = Vec::new();
+    for attribute in [&positions, &normals, &colors].iter() {
+        for v in attribute.iter() {
+            for x in v.iter() {
+                bin.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+    }
+    for i in indices.iter() {
+        bin.extend_from_slice(&i.to_le_bytes());
+    }
+    let (vertices, attribute_len) = (positions.len(), positions.len() * 12);
+    let json = format!(
+        concat!(
+            "{{\"asset\":{{\"version\":\"2.0\",\"generator\":\"TerrainGen\"}},",
+            "\"scene\":0,\"scenes\":[{{\"nodes\":[0]}}],\"nodes\":[{{\"mesh\":0}}],",
+            "\"meshes\":[{{\"primitives\":[{{",
+            "\"attributes\":{{\"POSITION\":0,\"NORMAL\":1,\"COLOR_0\":2}},\"indices\":3}}]}}],",
+            "\"buffers\":[{{\"byteLength\":{bin}}}],",
+            "\"bufferViews\":[",
+            "{{\"buffer\":0,\"byteOffset\":0,\"byteLength\":{attr},\"target\":34962}},",
+            "{{\"buffer\":0,\"byteOffset\":{attr},\"byteLength\":{attr},\"target\":34962}},",
+            "{{\"buffer\":0,\"byteOffset\":{attr2},\"byteLength\":{attr},\"target\":34962}},",
+            "{{\"buffer\":0,\"byteOffset\":{attr3},\"byteLength\":{idx},\"target\":34963}}],",
+            "\"accessors\":[",
+            "{{\"bufferView\":0,\"componentType\":5126,\"count\":{n},\"type\":\"VEC3\",",
+            "\"min\":[{},{},{}],\"max\":[{},{},{}]}},",
+            "{{\"bufferView\":1,\"componentType\":5126,\"count\":{n},\"type\":\"VEC3\"}},",
+            "{{\"bufferView\":2,\"componentType\":5126,\"count\":{n},\"type\":\"VEC3\"}},",
+            "{{\"bufferView\":3,\"componentType\":5125,\"count\":{m},\"type\":\"SCALAR\"}}]}}"
+        ),
+        min[0],
+        min[1],
+        min[2],
+        max[0],
+        max[1],
+        max[2],
+        bin = bin.len(),
+        attr = attribute_len,
+        attr2 = 2 * attribute_len,
+        attr3 = 3 * attribute_len,
+        idx = indices.len() * 4,
+        n = vertices,
+        m = indices.len(),
+    );
+    // chunks are padded to 4 bytes, with spaces for the JSON
+    let mut json = json.into_bytes();
+    json.resize((json.len() + 3) / 4 * 4, b' ');
+    bin.resize((bin.len() + 3) / 4 * 4, 0);
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(b"glTF")?;
+    file.write_all(&2u32.to_le_bytes())?;
+    file.write_all(&(12 + 8 + json.len() as u32 + 8 + bin.len() as u32).to_le_bytes())?;
+    file.write_all(&(json.len() as u32).to_le_bytes())?;
+    file.write_all(b"JSON")?;
+    file.write_all(&json)?;
+    file.write_all(&(bin.len() as u32).to_le_bytes())?;
+    file.write_all(b"BIN\0")?;
+    file.write_all(&bin)?;
+    file.flush()
+}
+
+// Binary STL of the terrain as a closed solid to 3D print: the surface as it's meshed,
+// walls around its border and a flat bottom base_thickness under its lowest point
+pub fn export_stl(
+    elevation: &Elevation,
+    height_mult: f32,
+    base_thickness: f32,
+    path: &Path,
+) -> io::Result<()> {
+    let size = elevation.size();
+    // the real heights, underwater cells included
+    let top: Vec<Vec3> = mesh_positions(elevation, f32::MIN, height_mult)
+        .into_iter()
+        .map(Vec3::from)
+        .collect();
+    let lowest = top.iter().map(|p| p.y).fold(f32::INFINITY, f32::min);
+    let bottom: Vec<Vec3> = top
+        .iter()
+        .map(|p| Vec3::new(p.x, lowest - base_thickness.max(0.), p.z))
+        .collect();
+    let indices = mesh_indices(size, elevation.grid);
+    let mut triangles: Vec<[Vec3; 3]> = Vec::new();
+    for face in indices.chunks(3) {
+        let (a, b, c) = (face[0] as usize, face[1] as usize, face[2] as usize);
+        triangles.push([top[a], top[b], top[c]]);
+        // the same triangulation faces down under the solid
+        triangles.push([bottom[a], bottom[c], bottom[b]]);
+    }
+    // the border cells clockwise seen from above, so the walls face outwards
+    let last = size - 1;
+    let border: Vec<usize> = (0..last)
+        .chain((0..last).map(|y| last + y * size))
+        .chain((0..last).map(|k| (last - k) + last * size))
+        .chain((0..last).map(|k| (last - k) * size))
+        .collect();
+    for (k, &a) in border.iter().enumerate() {
+        let b = border[(k + 1) % border.len()];
+        triangles.push([top[a], top[b], bottom[b]]);
+        triangles.push([top[a], bottom[b], bottom[a]]);
+    }
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&[0; 80])?;
+    file.write_all(&(triangles.len() as u32).to_le_bytes())?;
+    for [a, b, c] in triangles {
+        let normal = (b - a).cross(c - a);
+        let normal = if normal.length() > 0. {
+            normal.normalize()
+        } else {
+            normal
+        };
+        for v in [normal, a, b, c].iter() {
+            for x in [v.x, v.y, v.z].iter() {
+                file.write_all(&x.to_le_bytes())?;
+            }
+        }
+        // attribute byte count, unused
+        file.write_all(&[0; 2])?;
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gltf::Semantic;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    // a file of the temporary directory only this test of this run writes to
+    fn temp_path(test: &str, extension: &str) -> PathBuf {
+        let name = format!("terrain_gen_{}_{}.{}", test, process::id(), extension);
+        env::temp_dir().join(name)
+    }
+
+    // a bumpy square terrain of size x size cells
+    fn bumps(size: usize) -> Elevation {
+        let data = (0..size * size)
+            .map(|i| ((i % size) as f32 * 0.7).sin() * ((i / size) as f32 * 0.4).cos())
+            .collect();
+        Elevation::from_data(data, size)
+    }
+
+    #[test]
+    fn png_16_black_under_water_and_normalized_above() {
+        // from -0.5 to 1 along the rows
+        let data = (0..16).map(|i| -0.5 + 0.1 * i as f32).collect();
+        let elevation = Elevation::from_data(data, 4);
+        let path = temp_path("png_16", "png");
+        elevation.export_png_16(&path).unwrap();
+        let png = image::open(&path).unwrap().into_luma16();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(png.dimensions(), (4, 4));
+        let pixels: Vec<u16> = png.pixels().map(|p| p.0[0]).collect();
+        // the range starts at 0 rather than at the lowest height, which is under water
+        let max = elevation.data.iter().copied().fold(f32::MIN, f32::max);
+        for (h, pixel) in elevation.data.iter().zip(pixels.iter()) {
+            let expected = (h.max(0.) / max * u16::MAX as f32) as i32;
+            assert!((*pixel as i32 - expected).abs() <= 1, "{} for {}", pixel, h);
+        }
+        assert_eq!(pixels[..5], [0; 5]);
+        assert_eq!(pixels[15], u16::MAX);
+        assert!(pixels.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn obj_has_a_vertex_per_cell_and_2_faces_per_quad() {
+        let n = 6;
+        let path = temp_path("obj", "obj");
+        export_obj(&bumps(n), 0., 10., &path).unwrap();
+        let obj = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines = |prefix: &str| obj.lines().filter(|l| l.starts_with(prefix)).count();
+        assert_eq!(lines("v "), n * n);
+        assert_eq!(lines("f "), 2 * (n - 1) * (n - 1));
+        let indices: Vec<usize> = obj
+            .lines()
+            .filter(|l| l.starts_with("f "))
+            .flat_map(|l| l.split_whitespace().skip(1))
+            .map(|i| i.parse().unwrap())
+            .collect();
+        assert!(indices.iter().all(|i| *i >= 1 && *i <= n * n));
+    }
+
+    #[test]
+    fn a_saved_terrain_loads_back_the_same() {
+        let elevation = bumps(9);
+        let path = temp_path("save_load", "terrain");
+        elevation.save(Seed(12), &path).unwrap();
+        let loaded = Elevation::load(&path, 9);
+        // a terrain of another size is refused
+        let other = Elevation::load(&path, 10);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap().data, elevation.data);
+        assert_eq!(
+            other.err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[test]
+    fn stl_is_a_closed_solid_of_finite_triangles() {
+        let n = 6;
+        let path = temp_path("stl", "stl");
+        export_stl(&bumps(n), 10., 2., &path).unwrap();
+        let stl = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        // the top and bottom, and 2 triangles per border cell for the walls
+        let triangles = 4 * (n - 1) * (n - 1) + 8 * (n - 1);
+        let mut count = [0; 4];
+        count.copy_from_slice(&stl[80..84]);
+        assert_eq!(u32::from_le_bytes(count) as usize, triangles);
+        assert_eq!(stl.len(), 84 + 50 * triangles);
+        for triangle in stl[84..].chunks(50) {
+            for x in triangle[..48].chunks(4) {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(x);
+                assert!(f32::from_le_bytes(bytes).is_finite());
+            }
+        }
+    }
+
+    #[test]
+    fn gltf_holds_the_whole_mesh() {
+        let n = 5;
+        let path = temp_path("gltf", "glb");
+        export_gltf(&bumps(n), 0., 10., &Palette::default(), &path).unwrap();
+        let glb = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let gltf = gltf::Gltf::from_slice(&glb).unwrap();
+        assert_eq!(gltf.meshes().count(), 1);
+        assert_eq!(gltf.accessors().count(), 4);
+        assert_eq!(gltf.buffers().count(), 1);
+        let primitive = gltf.meshes().next().unwrap().primitives().next().unwrap();
+        let semantics = [Semantic::Positions, Semantic::Normals, Semantic::Colors(0)];
+        for semantic in semantics.iter() {
+            assert_eq!(primitive.get(semantic).unwrap().count(), n * n);
+        }
+        let indices = 6 * (n - 1) * (n - 1);
+        assert_eq!(primitive.indices().unwrap().count(), indices);
+        // the binary chunk holds the 3 attributes and the indices
+        let bin_len = 3 * 12 * n * n + 4 * indices;
+        assert_eq!(gltf.buffers().next().unwrap().length(), bin_len);
+        assert_eq!(gltf.blob.as_ref().map(Vec::len), Some(bin_len));
+    }
+}
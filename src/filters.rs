@@ -0,0 +1,57 @@
+use crate::args::arg;
+use crate::erosion::Compared;
+use crate::input::{Action, KeyBindings};
+use bevy::prelude::*;
+use terrain_gen::config::TerrainConfig;
+use terrain_gen::terrain::Elevation;
+
+// post-processes applied to the whole terrain on a key press
+pub struct FilterConfig {
+    // standard deviation of the blur in cells
+    pub smooth_sigma: f32,
+    pub smooth_iterations: u32,
+    // number of steps the land is cut into and how flat they are, from 0 to 1
+    pub terrace_levels: u32,
+    pub terrace_strength: f32,
+}
+
+impl FilterConfig {
+    pub fn from_args() -> Self {
+        FilterConfig {
+            smooth_sigma: arg("--smooth-sigma").unwrap_or(1.),
+            smooth_iterations: arg("--smooth-iterations").unwrap_or(1),
+            terrace_levels: arg("--terrace-levels").unwrap_or(8),
+            terrace_strength: arg("--terrace-strength").unwrap_or(1.),
+        }
+    }
+}
+
+fn filters(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    config: Res<FilterConfig>,
+    terrain: Res<TerrainConfig>,
+    mut query_elevation: Query<&mut Elevation, Without<Compared>>,
+) {
+    if let Ok(mut elevation) = query_elevation.single_mut() {
+        if bindings.just_pressed(&keys, Action::Smooth) {
+            elevation.smooth(config.smooth_sigma, config.smooth_iterations);
+        }
+        if bindings.just_pressed(&keys, Action::Terrace) {
+            elevation.terrace(
+                config.terrace_levels,
+                config.terrace_strength,
+                terrain.sea_level,
+            );
+        }
+    }
+}
+
+pub struct Filters;
+
+impl Plugin for Filters {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(FilterConfig::from_args())
+            .add_system(filters.system());
+    }
+}
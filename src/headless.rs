@@ -0,0 +1,108 @@
+use crate::args::arg;
+use crate::erosion::{Compared, ErosionStats, Glacier, Rainfall, TerrainId, Wind};
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Instant;
+use terrain_gen::config::{Seed, TerrainConfig};
+use terrain_gen::terrain::{Elevation, ErosionParams, TerrainStats};
+
+// number of simulation ticks to run without a window before exiting
+pub struct HeadlessRun {
+    iterations: u32,
+}
+
+impl HeadlessRun {
+    pub fn from_args() -> Self {
+        HeadlessRun {
+            iterations: arg("--iterations").unwrap_or(1000),
+        }
+    }
+}
+
+// directory where each run of a batch writes its heightmap
+pub struct BatchOutput(pub PathBuf);
+
+// everything a batch run was made from, and what came out of it
+#[derive(Serialize)]
+struct BatchRecord<'a> {
+    seed: Seed,
+    iterations: u32,
+    terrain: &'a TerrainConfig,
+    erosion: &'a ErosionParams,
+    rain: &'a Rainfall,
+    wind: &'a Wind,
+    glacier: &'a Glacier,
+    stats: TerrainStats,
+}
+
+// <seed>.png and a <seed>.json recording how it was made
+fn write_batch(out: &BatchOutput, elevation: &Elevation, record: &BatchRecord) -> io::Result<()> {
+    let png = out.0.join(format!("{}.png", record.seed.0));
+    elevation.export_png_16(&png)?;
+    let json = serde_json::to_string_pretty(record)?;
+    fs::write(out.0.join(format!("{}.json", record.seed.0)), json + "\n")?;
+    println!("wrote {}", png.display());
+    Ok(())
+}
+
+// the clock starts on the first tick so the terrain generation isn't counted
+fn count_ticks(
+    run: Res<HeadlessRun>,
+    stats: Res<ErosionStats>,
+    batch: Option<Res<BatchOutput>>,
+    seed: Res<Seed>,
+    config: Res<TerrainConfig>,
+    params: Res<ErosionParams>,
+    rain: Res<Rainfall>,
+    wind: Res<Wind>,
+    glacier: Res<Glacier>,
+    query_elevation: Query<&Elevation, Without<Compared>>,
+    mut ticks: Local<u32>,
+    mut start: Local<Option<Instant>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    // the terrain is still being generated
+    if query_elevation.single().is_err() {
+        return;
+    }
+    let start = *start.get_or_insert_with(Instant::now);
+    *ticks += 1;
+    if *ticks >= run.iterations {
+        let elapsed = start.elapsed();
+        println!(
+            "{} iterations in {:.2?}, {:.3} sediment moved",
+            *ticks,
+            elapsed,
+            stats.terrain(TerrainId(0)).sediment_moved
+        );
+        if let (Some(out), Ok(elevation)) = (batch, query_elevation.single()) {
+            let record = BatchRecord {
+                seed: *seed,
+                iterations: *ticks,
+                terrain: &config,
+                erosion: &params,
+                rain: &rain,
+                wind: &wind,
+                glacier: &glacier,
+                stats: elevation.stats(config.sea_level),
+            };
+            if let Err(e) = write_batch(&out, elevation, &record) {
+                println!("couldn't write seed {}: {}", seed.0, e);
+            }
+        }
+        exit.send(AppExit);
+    }
+}
+
+pub struct Headless;
+
+impl Plugin for Headless {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(HeadlessRun::from_args())
+            .add_system(count_ticks.system());
+    }
+}
@@ -0,0 +1,252 @@
+use crate::terrain::{coords, Elevation, Topology};
+use glam::Vec2;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+// D8 routing: each cell drains into its steepest downhill neighbor,
+// ties go to the first neighbor found and sinks drain nowhere
+fn receivers(elevation: &Elevation) -> Vec<Option<usize>> {
+    (0..elevation.data.len())
+        .map(|i| {
+            let mut receiver = None;
+            let mut steepest = 0.;
+            for (n, dist) in elevation.neighbors(i) {
+                let slope = (elevation.data[i] - elevation.data[n]) / dist;
+                if slope > steepest {
+                    steepest = slope;
+                    receiver = Some(n);
+                }
+            }
+            receiver
+        })
+        .collect()
+}
+
+// number of cells draining through each cell, itself included
+pub fn flow_accumulation(elevation: &Elevation) -> Vec<u32> {
+    let receivers = receivers(elevation);
+    // a cell only drains into strictly lower cells so visiting them from top to bottom
+    // guarantees every contribution is in before it's passed on
+    let mut order: Vec<usize> = (0..elevation.data.len()).collect();
+    order.sort_by(|&a, &b| {
+        elevation.data[b]
+            .total_cmp(&elevation.data[a])
+            .then(a.cmp(&b))
+    });
+    let mut flow = vec![1; elevation.data.len()];
+    for i in order {
+        if let Some(r) = receivers[i] {
+            flow[r] += flow[i];
+        }
+    }
+    flow
+}
+
+// share of the moisture carried over a cell that rains out even on flat ground
+const BASE_RAINOUT: f32 = 0.002;
+// share of the moisture that rains out per unit of height the air is forced up
+const OROGRAPHIC_RAINOUT: f32 = 30.;
+
+// precipitation of every cell from the moisture a wind blowing along wind brings from
+// outside the upwind edges, a bit rains out everywhere and a lot where the air is forced up
+// the slopes, which leaves the leeward side of the ridges dry, the edges don't wrap on a torus
+pub fn rain_shadow(elevation: &Elevation, wind: Vec2, initial_moisture: f32) -> Vec<f32> {
+    let size = elevation.size();
+    let wind = if wind.length() > 0. {
+        wind.normalize()
+    } else {
+        Vec2::new(1., 0.)
+    };
+    let (sx, sy) = (wind.x.signum() as i32, wind.y.signum() as i32);
+    let (wx, wy) = (wind.x.abs(), wind.y.abs());
+    // the upwind neighbors of a cell are further upwind, so they're visited first
+    let upwindness = |i: usize| {
+        let (x, y) = coords(i, size);
+        x as f32 * wind.x + y as f32 * wind.y
+    };
+    let mut order: Vec<usize> = (0..elevation.data.len()).collect();
+    order.sort_by(|&a, &b| upwindness(a).total_cmp(&upwindness(b)));
+    let mut moisture = vec![0.; elevation.data.len()];
+    let mut rain = vec![0.; elevation.data.len()];
+    for i in order {
+        let (x, y) = coords(i, size);
+        let (x, y) = (x as i32, y as i32);
+        // moisture and height of the air coming along an axis, fresh air from outside the grid
+        let upwind = |dx: i32, dy: i32| {
+            let (ux, uy) = (x - dx, y - dy);
+            if ux < 0 || uy < 0 || ux >= size as i32 || uy >= size as i32 {
+                (initial_moisture, elevation.data[i])
+            } else {
+                let u = ux as usize + uy as usize * size;
+                (moisture[u], elevation.data[u])
+            }
+        };
+        let ((mx, hx), (my, hy)) = (upwind(sx, 0), upwind(0, sy));
+        let carried = (mx * wx + my * wy) / (wx + wy);
+        let rise = (elevation.data[i] - (hx * wx + hy * wy) / (wx + wy)).max(0.);
+        rain[i] = carried * (BASE_RAINOUT + OROGRAPHIC_RAINOUT * rise).min(1.);
+        moisture[i] = carried - rain[i];
+    }
+    rain
+}
+
+// a cell in the flood queue, ordered so the lowest water level is popped first
+struct Flooded(f32, usize);
+
+impl PartialEq for Flooded {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Flooded {}
+
+impl PartialOrd for Flooded {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Flooded {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.total_cmp(&self.0).then(other.1.cmp(&self.1))
+    }
+}
+
+// priority-flood: water surface level of every cell, which is above the terrain in closed basins
+// and equal to it everywhere else, the border and the sea are the outlets
+pub fn fill_depressions(elevation: &Elevation, sea_level: f32) -> Vec<f32> {
+    let size = elevation.size();
+    let mut level = elevation.data.clone();
+    let mut visited = vec![false; level.len()];
+    let mut queue = BinaryHeap::new();
+    for i in 0..level.len() {
+        let (x, y) = coords(i, size);
+        // a torus has no border to drain through
+        let border = elevation.topology == Topology::Clamped
+            && (x == 0 || y == 0 || x == size - 1 || y == size - 1);
+        if border || level[i] < sea_level {
+            visited[i] = true;
+            queue.push(Flooded(level[i], i));
+        }
+    }
+    // cells are popped from the lowest, so the first time a cell is reached
+    // it's through the lowest spill point around it
+    while let Some(Flooded(h, i)) = queue.pop() {
+        for (n, _) in elevation.neighbors(i) {
+            if !visited[n] {
+                visited[n] = true;
+                level[n] = level[n].max(h);
+                queue.push(Flooded(level[n], n));
+            }
+        }
+    }
+    level
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::iproduct;
+
+    // a ridge running north to south along x = 16
+    fn ridge() -> Elevation {
+        let data = (0..32 * 32)
+            .map(|i| 0.5 - 0.03 * (coords(i, 32).0 as f32 - 16.).abs())
+            .collect();
+        Elevation::from_data(data, 32)
+    }
+
+    #[test]
+    fn the_windward_side_gets_more_rain() {
+        let rain = rain_shadow(&ridge(), Vec2::new(1., 0.), 1.);
+        let side = |xs: std::ops::Range<usize>| -> f32 {
+            (0..rain.len())
+                .filter(|i| xs.contains(&coords(*i, 32).0))
+                .map(|i| rain[i])
+                .sum()
+        };
+        assert!(side(8..16) > side(17..25));
+    }
+
+    #[test]
+    fn flow_gathers_down_a_plane_and_a_valley() {
+        // going down towards the east, every cell drains into its right neighbor
+        let plane = (0..64).map(|i| -(coords(i, 8).0 as f32)).collect();
+        let flow = flow_accumulation(&Elevation::from_data(plane, 8));
+        for i in 0..64 {
+            assert_eq!(flow[i], coords(i, 8).0 as u32 + 1);
+        }
+        // the flanks drain into the floor of the valley, which drains down to its last row
+        let valley = (0..81)
+            .map(|i| coords(i, 9))
+            .map(|(x, y)| (x as f32 - 4.).abs() + 0.1 * (8 - y) as f32)
+            .collect();
+        let flow = flow_accumulation(&Elevation::from_data(valley, 9));
+        for y in 0..9 {
+            assert_eq!(flow[4 + y * 9], 9 * (y as u32 + 1));
+        }
+    }
+
+    #[test]
+    fn a_pit_fills_up_to_its_spill_point() {
+        // a basin in the middle of a plateau, with a notch in its rim leading to the border
+        let mut data = vec![1.; 49];
+        for (x, y) in iproduct!(2..5, 2..5) {
+            data[x + y * 7] = 0.3;
+        }
+        data[3 + 3 * 7] = 0.1;
+        data[3 + 7] = 0.7;
+        data[3] = 0.;
+        let elevation = Elevation::from_data(data.clone(), 7);
+        let level = fill_depressions(&elevation, -1.);
+        for i in 0..49 {
+            let (x, y) = coords(i, 7);
+            let basin = (2..5).contains(&x) && (2..5).contains(&y);
+            let expected = if basin { 0.7 } else { data[i] };
+            assert!((level[i] - expected).abs() < 1e-6, "cell {:?}", (x, y));
+            assert!(level[i] >= data[i]);
+        }
+    }
+
+    #[test]
+    fn a_nan_height_doesnt_stop_the_flow() {
+        let mut data: Vec<f32> = (0..64).map(|i| -((i % 8) as f32)).collect();
+        data[27] = f32::NAN;
+        let flow = flow_accumulation(&Elevation::from_data(data, 8));
+        // the rows still gather their cells in the last column
+        assert_eq!(flow[7], 8);
+    }
+
+    #[test]
+    fn a_nan_height_doesnt_stop_the_flood() {
+        let mut data = vec![0.5; 64];
+        data[27] = f32::NAN;
+        let level = fill_depressions(&Elevation::from_data(data, 8), 0.);
+        assert_eq!(level.len(), 64);
+        assert!((0..64).filter(|i| *i != 27).all(|i| level[i] == 0.5));
+    }
+
+    #[test]
+    fn flow_grows_downhill_on_a_tilted_plane() {
+        let plane = (0..256)
+            .map(|i| coords(i, 16))
+            .map(|(x, y)| -(x as f32 + 0.5 * y as f32))
+            .collect();
+        let elevation = Elevation::from_data(plane, 16);
+        let flow = flow_accumulation(&elevation);
+        for (i, receiver) in receivers(&elevation).into_iter().enumerate() {
+            if let Some(r) = receiver {
+                assert!(flow[r] > flow[i]);
+            }
+            // both directions of the plane go down
+            let (x, y) = coords(i, 16);
+            if x < 15 {
+                assert!(flow[i + 1] >= flow[i]);
+            }
+            if y < 15 {
+                assert!(flow[i + 16] >= flow[i]);
+            }
+        }
+    }
+}
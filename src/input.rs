@@ -0,0 +1,338 @@
+use crate::args::args;
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Action {
+    Quit,
+    ExportFlow,
+    ExportHeightmap,
+    ExportMesh,
+    SaveState,
+    MoveForward,
+    MoveBack,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    ToggleFlyCamera,
+    ToggleView,
+    Sculpt,
+    ToggleStats,
+    CameraTopDown,
+    CameraIsometric,
+    CameraPerspective,
+    HeightScaleUp,
+    HeightScaleDown,
+    ToggleHillshade,
+    ExportStl,
+    Smooth,
+    Terrace,
+    Screenshot,
+    PaintSources,
+    Pause,
+    Step,
+    Reset,
+    ExportGltf,
+    ToggleTrails,
+    ToggleWireframe,
+    Measure,
+    ToggleGrid,
+    ToggleMinimap,
+    ToggleErosionMap,
+    ResetErosionMap,
+    Undo,
+    Redo,
+}
+
+impl Action {
+    const ALL: [Action; 39] = [
+        Action::Quit,
+        Action::ExportFlow,
+        Action::ExportHeightmap,
+        Action::ExportMesh,
+        Action::SaveState,
+        Action::MoveForward,
+        Action::MoveBack,
+        Action::MoveLeft,
+        Action::MoveRight,
+        Action::MoveUp,
+        Action::MoveDown,
+        Action::ToggleFlyCamera,
+        Action::ToggleView,
+        Action::Sculpt,
+        Action::ToggleStats,
+        Action::CameraTopDown,
+        Action::CameraIsometric,
+        Action::CameraPerspective,
+        Action::HeightScaleUp,
+        Action::HeightScaleDown,
+        Action::ToggleHillshade,
+        Action::ExportStl,
+        Action::Smooth,
+        Action::Terrace,
+        Action::Screenshot,
+        Action::PaintSources,
+        Action::Pause,
+        Action::Step,
+        Action::Reset,
+        Action::ExportGltf,
+        Action::ToggleTrails,
+        Action::ToggleWireframe,
+        Action::Measure,
+        Action::ToggleGrid,
+        Action::ToggleMinimap,
+        Action::ToggleErosionMap,
+        Action::ResetErosionMap,
+        Action::Undo,
+        Action::Redo,
+    ];
+
+    // only triggered while the sculpt key is held, so it can share its key with another action
+    fn with_sculpt(&self) -> bool {
+        matches!(self, Action::Undo | Action::Redo)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Quit => "quit",
+            Action::ExportFlow => "export_flow",
+            Action::ExportHeightmap => "export_heightmap",
+            Action::ExportMesh => "export_mesh",
+            Action::SaveState => "save_state",
+            Action::MoveForward => "move_forward",
+            Action::MoveBack => "move_back",
+            Action::MoveLeft => "move_left",
+            Action::MoveRight => "move_right",
+            Action::MoveUp => "move_up",
+            Action::MoveDown => "move_down",
+            Action::ToggleFlyCamera => "toggle_fly_camera",
+            Action::ToggleView => "toggle_view",
+            Action::Sculpt => "sculpt",
+            Action::ToggleStats => "toggle_stats",
+            Action::CameraTopDown => "camera_top_down",
+            Action::CameraIsometric => "camera_isometric",
+            Action::CameraPerspective => "camera_perspective",
+            Action::HeightScaleUp => "height_scale_up",
+            Action::HeightScaleDown => "height_scale_down",
+            Action::ToggleHillshade => "toggle_hillshade",
+            Action::ExportStl => "export_stl",
+            Action::Smooth => "smooth",
+            Action::Terrace => "terrace",
+            Action::Screenshot => "screenshot",
+            Action::PaintSources => "paint_sources",
+            Action::Pause => "pause",
+            Action::Step => "step",
+            Action::Reset => "reset",
+            Action::ExportGltf => "export_gltf",
+            Action::ToggleTrails => "toggle_trails",
+            Action::ToggleWireframe => "toggle_wireframe",
+            Action::Measure => "measure",
+            Action::ToggleGrid => "toggle_grid",
+            Action::ToggleMinimap => "toggle_minimap",
+            Action::ToggleErosionMap => "toggle_erosion_map",
+            Action::ResetErosionMap => "reset_erosion_map",
+            Action::Undo => "undo",
+            Action::Redo => "redo",
+        }
+    }
+
+    fn default_key(&self) -> KeyCode {
+        match self {
+            Action::Quit => KeyCode::Escape,
+            Action::ExportFlow => KeyCode::F7,
+            Action::ExportHeightmap => KeyCode::F5,
+            Action::ExportMesh => KeyCode::F6,
+            Action::SaveState => KeyCode::F9,
+            Action::MoveForward => KeyCode::W,
+            Action::MoveBack => KeyCode::S,
+            Action::MoveLeft => KeyCode::A,
+            Action::MoveRight => KeyCode::D,
+            Action::MoveUp => KeyCode::Space,
+            Action::MoveDown => KeyCode::LShift,
+            Action::ToggleFlyCamera => KeyCode::Tab,
+            Action::ToggleView => KeyCode::V,
+            Action::Sculpt => KeyCode::LControl,
+            Action::ToggleStats => KeyCode::F3,
+            Action::CameraTopDown => KeyCode::Key1,
+            Action::CameraIsometric => KeyCode::Key2,
+            Action::CameraPerspective => KeyCode::Key3,
+            Action::HeightScaleUp => KeyCode::Equals,
+            Action::HeightScaleDown => KeyCode::Minus,
+            Action::ToggleHillshade => KeyCode::H,
+            Action::ExportStl => KeyCode::F8,
+            Action::Smooth => KeyCode::B,
+            Action::Terrace => KeyCode::T,
+            Action::Screenshot => KeyCode::F12,
+            // shift click removes the sources instead of adding one, either shift key works
+            Action::PaintSources => KeyCode::LAlt,
+            Action::Pause => KeyCode::P,
+            Action::Step => KeyCode::Period,
+            Action::Reset => KeyCode::R,
+            Action::ExportGltf => KeyCode::F10,
+            Action::ToggleTrails => KeyCode::Y,
+            Action::ToggleWireframe => KeyCode::F4,
+            Action::Measure => KeyCode::X,
+            Action::ToggleGrid => KeyCode::G,
+            Action::ToggleMinimap => KeyCode::M,
+            Action::ToggleErosionMap => KeyCode::C,
+            Action::ResetErosionMap => KeyCode::Back,
+            Action::Undo => KeyCode::Z,
+            Action::Redo => KeyCode::Y,
+        }
+    }
+}
+
+fn parse_key(name: &str) -> Option<KeyCode> {
+    let key = match name.to_lowercase().as_str() {
+        "a" => KeyCode::A,
+        "b" => KeyCode::B,
+        "c" => KeyCode::C,
+        "d" => KeyCode::D,
+        "e" => KeyCode::E,
+        "f" => KeyCode::F,
+        "g" => KeyCode::G,
+        "h" => KeyCode::H,
+        "i" => KeyCode::I,
+        "j" => KeyCode::J,
+        "k" => KeyCode::K,
+        "l" => KeyCode::L,
+        "m" => KeyCode::M,
+        "n" => KeyCode::N,
+        "o" => KeyCode::O,
+        "p" => KeyCode::P,
+        "q" => KeyCode::Q,
+        "r" => KeyCode::R,
+        "s" => KeyCode::S,
+        "t" => KeyCode::T,
+        "u" => KeyCode::U,
+        "v" => KeyCode::V,
+        "w" => KeyCode::W,
+        "x" => KeyCode::X,
+        "y" => KeyCode::Y,
+        "z" => KeyCode::Z,
+        "0" => KeyCode::Key0,
+        "1" => KeyCode::Key1,
+        "2" => KeyCode::Key2,
+        "3" => KeyCode::Key3,
+        "4" => KeyCode::Key4,
+        "5" => KeyCode::Key5,
+        "6" => KeyCode::Key6,
+        "7" => KeyCode::Key7,
+        "8" => KeyCode::Key8,
+        "9" => KeyCode::Key9,
+        "f1" => KeyCode::F1,
+        "f2" => KeyCode::F2,
+        "f3" => KeyCode::F3,
+        "f4" => KeyCode::F4,
+        "f5" => KeyCode::F5,
+        "f6" => KeyCode::F6,
+        "f7" => KeyCode::F7,
+        "f8" => KeyCode::F8,
+        "f9" => KeyCode::F9,
+        "f10" => KeyCode::F10,
+        "f11" => KeyCode::F11,
+        "f12" => KeyCode::F12,
+        "escape" => KeyCode::Escape,
+        "space" => KeyCode::Space,
+        "tab" => KeyCode::Tab,
+        "return" => KeyCode::Return,
+        "back" => KeyCode::Back,
+        "delete" => KeyCode::Delete,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "lshift" => KeyCode::LShift,
+        "rshift" => KeyCode::RShift,
+        "lcontrol" => KeyCode::LControl,
+        "rcontrol" => KeyCode::RControl,
+        "lalt" => KeyCode::LAlt,
+        "ralt" => KeyCode::RAlt,
+        "period" => KeyCode::Period,
+        "comma" => KeyCode::Comma,
+        "minus" => KeyCode::Minus,
+        "equals" => KeyCode::Equals,
+        _ => return None,
+    };
+    Some(key)
+}
+
+// maps every action to the key that triggers it
+pub struct KeyBindings(HashMap<Action, KeyCode>);
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings(
+            Action::ALL
+                .iter()
+                .map(|action| (*action, action.default_key()))
+                .collect(),
+        )
+    }
+}
+
+impl KeyBindings {
+    // defaults overridden by any number of `--bind action=key`
+    pub fn from_args() -> Self {
+        let mut bindings = KeyBindings::default();
+        for bind in args::<String>("--bind") {
+            let (name, key) = bind
+                .split_once('=')
+                .unwrap_or_else(|| panic!("invalid binding {:?}, expected action=key", bind));
+            let action = Action::ALL
+                .iter()
+                .find(|action| action.name() == name)
+                .unwrap_or_else(|| panic!("unknown action {:?}", name));
+            let key = parse_key(key).unwrap_or_else(|| panic!("unknown key {:?}", key));
+            bindings.0.insert(*action, key);
+        }
+        for (a, b) in pairs(&Action::ALL) {
+            if bindings.key(a) == bindings.key(b) && a.with_sculpt() == b.with_sculpt() {
+                println!(
+                    "warning: {} and {} are both bound to {:?}",
+                    a.name(),
+                    b.name(),
+                    bindings.key(a)
+                );
+            }
+        }
+        bindings
+    }
+
+    pub fn key(&self, action: Action) -> KeyCode {
+        self.0[&action]
+    }
+
+    pub fn just_pressed(&self, keys: &Input<KeyCode>, action: Action) -> bool {
+        keys.just_pressed(self.key(action))
+    }
+
+    pub fn pressed(&self, keys: &Input<KeyCode>, action: Action) -> bool {
+        keys.pressed(self.key(action))
+    }
+}
+
+// every unordered pair of distinct actions
+fn pairs(actions: &[Action]) -> impl Iterator<Item = (Action, Action)> + '_ {
+    actions
+        .iter()
+        .enumerate()
+        .flat_map(move |(i, a)| actions[i + 1..].iter().map(move |b| (*a, *b)))
+}
+
+fn quit(keys: Res<Input<KeyCode>>, bindings: Res<KeyBindings>, mut exit: EventWriter<AppExit>) {
+    if bindings.just_pressed(&keys, Action::Quit) {
+        exit.send(AppExit);
+    }
+}
+
+pub struct Inputs;
+
+impl Plugin for Inputs {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(KeyBindings::from_args())
+            .add_system(quit.system());
+    }
+}
@@ -0,0 +1,10 @@
+// terrain generation and erosion without any rendering, the bevy app in main.rs is built on top
+pub mod biome;
+pub mod config;
+pub mod files;
+pub mod hydrology;
+pub mod mesh;
+pub mod palette;
+pub mod presets;
+pub mod raster;
+pub mod terrain;
@@ -1,17 +1,91 @@
+mod args;
+mod brush;
+mod control;
 mod draw2d;
 mod draw3d;
 mod erosion;
+mod export;
+mod filters;
+mod headless;
+mod input;
+mod measure;
+mod minimap;
+mod pick;
+mod ui;
+mod view;
+use args::{arg, flag, FromArgs};
 use bevy::prelude::*;
-// use draw2d::Draw2d;
+use bevy::wgpu::{WgpuFeature, WgpuFeatures, WgpuOptions};
+use brush::Sculpt;
+use control::Control;
+use draw2d::Draw2d;
 use draw3d::Draw3d;
 use erosion::Erosion;
-use std::usize;
-pub const SIZE: usize = 512;
+use export::Export;
+use filters::Filters;
+use headless::{BatchOutput, Headless};
+use input::Inputs;
+use measure::MeasureTool;
+use minimap::MinimapView;
+use pick::Pick;
+use std::fs;
+use std::path::PathBuf;
+use terrain_gen::config::{DrawConfig, Seed, TerrainConfig};
+use ui::Ui;
+use view::View;
 
-fn main() {
-    App::build()
-        .add_plugins(DefaultPlugins)
-        .add_plugin(Draw3d)
+// only the simulation, with the exact same systems as with a window
+fn headless(app: &mut AppBuilder, seed: Seed) {
+    app.insert_resource(TerrainConfig::from_args())
+        .insert_resource(seed)
+        .add_plugins(MinimalPlugins)
         .add_plugin(Erosion)
-        .run();
+        .add_plugin(Headless);
+}
+
+fn main() {
+    if let Some(count) = arg::<u32>("--batch") {
+        let out: PathBuf = arg("--out").unwrap_or_else(|| PathBuf::from("batch"));
+        fs::create_dir_all(&out)
+            .unwrap_or_else(|e| panic!("couldn't create {}: {}", out.display(), e));
+        for seed in 0..count {
+            let mut app = App::build();
+            app.insert_resource(BatchOutput(out.clone()));
+            headless(&mut app, Seed(seed));
+            app.run();
+        }
+        return;
+    }
+    let mut app = App::build();
+    if flag("--headless") {
+        headless(&mut app, Seed::from_args());
+    } else {
+        let config = TerrainConfig::from_args();
+        app.insert_resource(args::palette(config.sea_level))
+            .insert_resource(config)
+            .insert_resource(Seed::from_args())
+            .insert_resource(DrawConfig::from_args())
+            // the wireframe view draws polygons as lines
+            .insert_resource(WgpuOptions {
+                features: WgpuFeatures {
+                    features: vec![WgpuFeature::NonFillPolygonMode],
+                },
+                ..Default::default()
+            })
+            .add_plugins(DefaultPlugins)
+            .add_plugin(Inputs)
+            .add_plugin(View)
+            .add_plugin(Draw2d)
+            .add_plugin(Draw3d)
+            .add_plugin(Erosion)
+            .add_plugin(Control)
+            .add_plugin(Export)
+            .add_plugin(Filters)
+            .add_plugin(Pick)
+            .add_plugin(MeasureTool)
+            .add_plugin(MinimapView)
+            .add_plugin(Sculpt)
+            .add_plugin(Ui);
+    }
+    app.run();
 }
@@ -0,0 +1,104 @@
+use crate::erosion::Compared;
+use crate::input::{Action, KeyBindings};
+use crate::pick::Cursor;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use terrain_gen::terrain::Elevation;
+
+// cells between two samples of the profile
+const PROFILE_SPACING: f32 = 1.;
+// size in points of the profile graph
+const GRAPH_SIZE: (f32, f32) = (300., 80.);
+
+// the segment being measured, the first press of the key sets its start
+// and the second its end
+#[derive(Default)]
+pub struct Measure {
+    start: Option<Vec2>,
+    // length of the last segment and the heights along it
+    profile: Option<(f32, Vec<f32>)>,
+}
+
+fn measure(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    cursor: Res<Cursor>,
+    mut measure: ResMut<Measure>,
+    query_elevation: Query<&Elevation, Without<Compared>>,
+) {
+    if !bindings.just_pressed(&keys, Action::Measure) {
+        return;
+    }
+    let (pos, elevation) = match (cursor.0, query_elevation.single()) {
+        (Some(pos), Ok(elevation)) => (pos, elevation),
+        _ => return,
+    };
+    match measure.start.take() {
+        None => {
+            measure.start = Some(pos);
+            measure.profile = None;
+        }
+        Some(start) => {
+            let distance = (pos - start).length();
+            let profile = elevation.profile(start, pos, PROFILE_SPACING);
+            let (low, high) = span(&profile);
+            println!(
+                "({:.1}, {:.1}) to ({:.1}, {:.1}): {:.1} cells, heights {:.4} to {:.4}",
+                start.x, start.y, pos.x, pos.y, distance, low, high
+            );
+            measure.profile = Some((distance, profile));
+        }
+    }
+}
+
+fn span(profile: &[f32]) -> (f32, f32) {
+    profile
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(low, high), h| {
+            (low.min(*h), high.max(*h))
+        })
+}
+
+// the heights along the last segment as a line graph
+fn profile_panel(egui_context: Res<EguiContext>, measure: Res<Measure>) {
+    let (distance, profile) = match &measure.profile {
+        Some(profile) => profile,
+        None => return,
+    };
+    egui::Window::new("Profile").show(egui_context.ctx(), |ui| {
+        let (low, high) = span(profile);
+        ui.label(format!(
+            "{:.1} cells, heights {:.4} to {:.4}",
+            distance, low, high
+        ));
+        let (response, painter) =
+            ui.allocate_painter(egui::vec2(GRAPH_SIZE.0, GRAPH_SIZE.1), egui::Sense::hover());
+        let rect = response.rect;
+        let range = if high > low { high - low } else { 1. };
+        let last = (profile.len() - 1).max(1) as f32;
+        let points: Vec<egui::Pos2> = profile
+            .iter()
+            .enumerate()
+            .map(|(k, h)| {
+                egui::pos2(
+                    rect.left() + rect.width() * k as f32 / last,
+                    rect.bottom() - rect.height() * (h - low) / range,
+                )
+            })
+            .collect();
+        painter.add(egui::Shape::line(
+            points,
+            egui::Stroke::new(1.5, egui::Color32::LIGHT_BLUE),
+        ));
+    });
+}
+
+pub struct MeasureTool;
+
+impl Plugin for MeasureTool {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<Measure>()
+            .add_system(measure.system().after("pick"))
+            .add_system(profile_panel.system());
+    }
+}
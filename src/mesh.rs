@@ -0,0 +1,364 @@
+use crate::terrain::{coords, Elevation, Grid};
+use glam::{Vec2, Vec3};
+use itertools::iproduct;
+
+// triangle list of the terrain mesh, 2 triangles per quad of cells
+pub fn mesh_indices(size: usize, grid: Grid) -> Vec<u32> {
+    grid_indices(size, size, 0, grid)
+}
+
+// triangle list of a width x height block of vertices starting on row y0 of the grid,
+// one quad per cell that has a right and a lower neighbor so no triangle wraps around a row
+pub fn grid_indices(width: usize, height: usize, y0: usize, grid: Grid) -> Vec<u32> {
+    let (width, height, y0) = (width as u32, height as u32, y0 as u32);
+    iproduct!(0..height.saturating_sub(1), 0..width.saturating_sub(1))
+        .flat_map(|(y, x)| {
+            let (tl, tr) = (x + y * width, x + 1 + y * width);
+            let (bl, br) = (tl + width, tr + width);
+            // odd hex rows are shifted right so their quads are split along the other diagonal
+            IntoIterator::into_iter(match (grid, (y0 + y) % 2) {
+                (Grid::Square, _) | (Grid::Hex, 0) => [tl, bl, tr, tr, bl, br],
+                (Grid::Hex, _) => [tl, br, tr, tl, bl, br],
+            })
+        })
+        .collect()
+}
+
+// vertex positions of the terrain mesh, underwater cells are flattened to sea level
+pub fn mesh_positions(elevation: &Elevation, sea_level: f32, height_mult: f32) -> Vec<[f32; 3]> {
+    (0..elevation.data.len())
+        .map(|i| vertex_position(elevation, i, sea_level, height_mult))
+        .collect()
+}
+
+// vertex normals of the terrain mesh
+pub fn compute_normals(elevation: &Elevation, sea_level: f32, height_mult: f32) -> Vec<[f32; 3]> {
+    (0..elevation.data.len())
+        .map(|i| vertex_normal(elevation, i, sea_level, height_mult))
+        .collect()
+}
+
+// position of the vertex of cell i
+pub fn vertex_position(
+    elevation: &Elevation,
+    i: usize,
+    sea_level: f32,
+    height_mult: f32,
+) -> [f32; 3] {
+    let h = elevation.data[i].max(sea_level) * height_mult;
+    match elevation.grid {
+        Grid::Square => {
+            let (x, y) = coords(i, elevation.size());
+            [x as f32, h, y as f32]
+        }
+        Grid::Hex => {
+            let p = elevation.center(i);
+            [p.x, h, p.y]
+        }
+    }
+}
+
+// normal of the vertex of cell i from central differences of the drawn heights,
+// one-sided differences at the borders
+pub fn vertex_normal(
+    elevation: &Elevation,
+    i: usize,
+    sea_level: f32,
+    height_mult: f32,
+) -> [f32; 3] {
+    let size = elevation.size();
+    let h = |i: usize| elevation.data[i].max(sea_level) * height_mult;
+    // derivative along the axis of coordinate `coord`, neighbors on that axis are `stride` apart
+    let diff = |coord: usize, stride: usize| {
+        let lo = if coord > 0 { i - stride } else { i };
+        let hi = if coord < size - 1 { i + stride } else { i };
+        (h(hi) - h(lo)) / ((hi - lo) / stride) as f32
+    };
+    let n = match elevation.grid {
+        // x goes along a row and z across rows
+        Grid::Square => {
+            let (x, y) = coords(i, size);
+            Vec3::new(-diff(x, 1), 1., -diff(y, size))
+        }
+        Grid::Hex if elevation.data[i] < sea_level => Vec3::Y,
+        Grid::Hex => {
+            let g = elevation.grad(i) * height_mult;
+            Vec3::new(-g.x, 1., -g.y)
+        }
+    };
+    n.normalize().into()
+}
+
+// every stride-th coordinate of a span, and always its last one so chunks keep sharing edges
+fn decimate(start: usize, len: usize, stride: usize) -> Vec<usize> {
+    let mut coords: Vec<usize> = (start..start + len).step_by(stride).collect();
+    if coords.last() != Some(&(start + len - 1)) {
+        coords.push(start + len - 1);
+    }
+    coords
+}
+
+// a block of the terrain drawn by its own mesh, neighboring chunks share their edge cells
+#[derive(Clone, PartialEq, Debug)]
+pub struct Chunk {
+    pub x0: usize,
+    pub y0: usize,
+    pub width: usize,
+    pub height: usize,
+    // only every stride-th cell is drawn
+    pub stride: usize,
+}
+
+impl Chunk {
+    // the chunks covering a size x size grid
+    pub fn split(size: usize, chunk_size: usize) -> Vec<Chunk> {
+        let starts = |size: usize| (0..size - 1).step_by(chunk_size);
+        iproduct!(starts(size), starts(size))
+            .map(|(y0, x0)| Chunk {
+                x0,
+                y0,
+                width: (chunk_size + 1).min(size - x0),
+                height: (chunk_size + 1).min(size - y0),
+                stride: 1,
+            })
+            .collect()
+    }
+
+    // global index of every vertex of the chunk in row-major order
+    pub fn cells(&self, size: usize) -> Vec<usize> {
+        let xs = decimate(self.x0, self.width, self.stride);
+        iproduct!(decimate(self.y0, self.height, self.stride), xs)
+            .map(|(y, x)| x + y * size)
+            .collect()
+    }
+
+    // vertices per row and column at the current stride
+    pub fn dims(&self) -> (usize, usize) {
+        (
+            decimate(self.x0, self.width, self.stride).len(),
+            decimate(self.y0, self.height, self.stride).len(),
+        )
+    }
+
+    // index among the chunk's vertices of the ones on its border, going around it
+    pub fn border(&self) -> Vec<usize> {
+        let (w, h) = self.dims();
+        (0..w - 1)
+            .chain((0..h - 1).map(|y| w - 1 + y * w))
+            .chain((0..w - 1).map(|k| (w - 1 - k) + (h - 1) * w))
+            .chain((0..h - 1).map(|k| (h - 1 - k) * w))
+            .collect()
+    }
+
+    // the cell of every vertex of the mesh, the ones of the surface followed by the border
+    // ones again for the skirt
+    pub fn vertex_cells(&self, size: usize) -> Vec<usize> {
+        let cells = self.cells(size);
+        let skirt: Vec<usize> = self.border().into_iter().map(|k| cells[k]).collect();
+        cells.into_iter().chain(skirt).collect()
+    }
+
+    // positions and normals of the vertices of the mesh, the skirt hangs skirt_depth
+    // under the border
+    pub fn vertices(
+        &self,
+        elevation: &Elevation,
+        sea_level: f32,
+        height_mult: f32,
+        skirt_depth: f32,
+    ) -> (Vec<[f32; 3]>, Vec<[f32; 3]>) {
+        let surface = {
+            let (w, h) = self.dims();
+            w * h
+        };
+        let cells = self.vertex_cells(elevation.size());
+        let mut positions: Vec<[f32; 3]> = cells
+            .iter()
+            .map(|i| vertex_position(elevation, *i, sea_level, height_mult))
+            .collect();
+        for p in positions[surface..].iter_mut() {
+            p[1] -= skirt_depth;
+        }
+        let normals = cells
+            .iter()
+            .map(|i| vertex_normal(elevation, *i, sea_level, height_mult))
+            .collect();
+        (positions, normals)
+    }
+
+    // the triangles of the surface followed by the skirt hanging from the border down to
+    // the skirt vertices added after the cells, which hides the cracks between chunks
+    // drawn at different strides
+    pub fn indices(&self, grid: Grid) -> Vec<u32> {
+        let (w, h) = self.dims();
+        // decimated hex rows don't alternate anymore
+        let grid = if self.stride > 1 { Grid::Square } else { grid };
+        let mut indices = grid_indices(w, h, self.y0, grid);
+        let border = self.border();
+        let base = (w * h) as u32;
+        for k in 0..border.len() {
+            let (a, b) = (border[k] as u32, border[(k + 1) % border.len()] as u32);
+            let (sa, sb) = (base + k as u32, base + ((k + 1) % border.len()) as u32);
+            // both sides so the skirt is seen from inside and outside
+            indices.extend_from_slice(&[a, b, sb, a, sb, sa, a, sb, b, a, sa, sb]);
+        }
+        indices
+    }
+
+    // whether any cell of the chunk or around it changed after the given number of changes,
+    // since the normals and colors of a vertex depend on its neighbors
+    pub fn touched_since(&self, elevation: &Elevation, edits: u64) -> bool {
+        let size = elevation.size();
+        let (x0, y0) = (self.x0.saturating_sub(1), self.y0.saturating_sub(1));
+        let x1 = (self.x0 + self.width + 1).min(size);
+        let y1 = (self.y0 + self.height + 1).min(size);
+        elevation.edited_since(x0, y0, x1, y1, edits)
+    }
+}
+
+// distance between two samples of a picking ray, in cells
+const RAY_STEP: f32 = 0.5;
+// halvings of the last step to find where the ray crosses the surface
+const RAY_REFINE: u32 = 8;
+
+// first point where a ray goes under the terrain surface as it's drawn, with the cell under it,
+// None if the ray misses the terrain
+pub fn pick_terrain(
+    ray_origin: Vec3,
+    ray_dir: Vec3,
+    elevation: &Elevation,
+    sea_level: f32,
+    height_mult: f32,
+) -> Option<(usize, Vec3)> {
+    let dir = ray_dir.normalize();
+    let extent = elevation.grid.extent(elevation.size());
+    // underwater cells are drawn at sea level
+    let high = elevation.data.iter().copied().fold(sea_level, f32::max);
+    // part of the ray inside the box around the terrain
+    let lo = Vec3::new(0., sea_level * height_mult, 0.);
+    let hi = Vec3::new(extent.x, high * height_mult, extent.y);
+    let (mut enter, mut exit) = (0f32, f32::INFINITY);
+    for &(o, d, lo, hi) in [
+        (ray_origin.x, dir.x, lo.x, hi.x),
+        (ray_origin.y, dir.y, lo.y, hi.y),
+        (ray_origin.z, dir.z, lo.z, hi.z),
+    ]
+    .iter()
+    {
+        if d.abs() < f32::EPSILON {
+            if o < lo || o > hi {
+                return None;
+            }
+            continue;
+        }
+        let (t0, t1) = ((lo - o) / d, (hi - o) / d);
+        enter = enter.max(t0.min(t1));
+        exit = exit.min(t0.max(t1));
+    }
+    if enter > exit {
+        return None;
+    }
+    let point = |t: f32| ray_origin + dir * t;
+    let under = |t: f32| {
+        let p = point(t);
+        let pos = Vec2::new(p.x, p.z);
+        let inside = pos.x >= 0. && pos.y >= 0. && pos.x < extent.x && pos.y < extent.y;
+        inside && p.y <= elevation.height_bilinear(pos).max(sea_level) * height_mult
+    };
+    let steps = ((exit - enter) / RAY_STEP).ceil() as usize + 1;
+    let hit = (0..steps)
+        .map(|step| (enter + step as f32 * RAY_STEP).min(exit))
+        .find(|t| under(*t))?;
+    // the surface is somewhere in the last step
+    let (mut above, mut below) = ((hit - RAY_STEP).max(enter), hit);
+    for _ in 0..RAY_REFINE {
+        let mid = (above + below) / 2.;
+        if under(mid) {
+            below = mid;
+        } else {
+            above = mid;
+        }
+    }
+    let p = point(below);
+    Some((elevation.index(Vec2::new(p.x, p.z)), p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_match_the_whole_mesh() {
+        let size = 37;
+        let data = (0..size * size)
+            .map(|i| ((i % size) as f32 * 0.3).sin() * ((i / size) as f32 * 0.2).cos())
+            .collect();
+        let elevation = Elevation::from_data(data, size);
+        let positions = mesh_positions(&elevation, -0.5, 40.);
+        let normals = compute_normals(&elevation, -0.5, 40.);
+        let mut covered = vec![false; size * size];
+        for chunk in Chunk::split(size, 8) {
+            let (chunk_positions, chunk_normals) = chunk.vertices(&elevation, -0.5, 40., 0.);
+            for (k, i) in chunk.vertex_cells(size).into_iter().enumerate() {
+                assert_eq!(chunk_positions[k], positions[i]);
+                assert_eq!(chunk_normals[k], normals[i]);
+                covered[i] = true;
+            }
+        }
+        assert!(covered.iter().all(|c| *c));
+    }
+
+    // a chunk drawn at a coarser stride than its neighbor keeps the shared edge's end points
+    // and hangs a skirt under its whole border, so no crack shows between them
+    #[test]
+    fn lod_chunks_share_their_edges() {
+        let size = 33;
+        let mut chunks = Chunk::split(size, 16);
+        chunks[0].stride = 8;
+        let (coarse, fine) = (&chunks[0], &chunks[1]);
+        assert_eq!(coarse.x0 + coarse.width - 1, fine.x0);
+        let edge = |chunk: &Chunk| -> Vec<usize> {
+            chunk
+                .cells(size)
+                .into_iter()
+                .filter(|i| i % size == fine.x0)
+                .collect()
+        };
+        let fine_edge = edge(fine);
+        assert!(edge(coarse).iter().all(|i| fine_edge.contains(i)));
+        let (w, h) = coarse.dims();
+        let border = coarse.border();
+        assert_eq!(border.len(), 2 * (w - 1) + 2 * (h - 1));
+        assert_eq!(coarse.vertex_cells(size).len(), w * h + border.len());
+        let vertices = (w * h + border.len()) as u32;
+        assert!(coarse.indices(Grid::Square).iter().all(|i| *i < vertices));
+    }
+
+    #[test]
+    fn no_triangle_spans_the_seam_between_rows() {
+        let (width, height) = (7, 5);
+        for (y0, grid) in iproduct!(0..2, [Grid::Square, Grid::Hex].iter()) {
+            let indices = grid_indices(width, height, y0, *grid);
+            assert_eq!(indices.len(), 6 * (width - 1) * (height - 1));
+            for triangle in indices.chunks(3) {
+                assert!(triangle.iter().all(|i| (*i as usize) < width * height));
+                let columns: Vec<usize> = triangle.iter().map(|i| *i as usize % width).collect();
+                let rows: Vec<usize> = triangle.iter().map(|i| *i as usize / width).collect();
+                let span = |c: &[usize]| c.iter().max().unwrap() - c.iter().min().unwrap();
+                assert!(span(&columns) == 1 && span(&rows) == 1, "{:?}", triangle);
+            }
+        }
+    }
+
+    #[test]
+    fn a_ray_straight_down_picks_the_cell_under_it() {
+        let elevation = Elevation::from_data(vec![0.5; 256], 16);
+        let down = Vec3::new(0., -1., 0.);
+        let (i, p) = pick_terrain(Vec3::new(5.3, 100., 7.6), down, &elevation, 0., 10.).unwrap();
+        assert_eq!(i, 5 + 7 * 16);
+        assert!((p - Vec3::new(5.3, 5., 7.6)).length() < 0.01);
+        // looking up or beside the terrain misses it
+        assert!(pick_terrain(Vec3::new(5.3, 100., 7.6), -down, &elevation, 0., 10.).is_none());
+        assert!(pick_terrain(Vec3::new(-1., 100., 7.6), down, &elevation, 0., 10.).is_none());
+    }
+}
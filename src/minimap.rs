@@ -0,0 +1,173 @@
+use crate::draw3d::FlyCamera;
+use crate::erosion::Compared;
+use crate::input::{Action, KeyBindings};
+use crate::view::ViewMode;
+use bevy::ecs::world::FromWorld;
+use bevy::prelude::*;
+use bevy::render::texture::{Extent3d, TextureDimension, TextureFormat};
+use bevy_egui::{egui, EguiContext};
+use terrain_gen::config::{DrawConfig, TerrainConfig};
+use terrain_gen::palette::{sediment_color, Palette};
+use terrain_gen::terrain::Elevation;
+
+// pixels per side of the minimap
+const MINIMAP_SIZE: usize = 160;
+// id of the minimap texture among the ones egui draws
+const MINIMAP_TEXTURE: u64 = 1;
+// frames between two recolorings of the minimap, the terrain changes slowly
+const MINIMAP_PERIOD: u32 = 30;
+// pixels between the camera marker and the tip of its facing line
+const MARKER_LENGTH: f32 = 8.;
+
+// a small top-down map in a corner of the 3D view with the camera on it
+pub struct Minimap {
+    pub shown: bool,
+    texture: Handle<Texture>,
+}
+
+impl FromWorld for Minimap {
+    fn from_world(world: &mut World) -> Self {
+        let texture = Texture::new(
+            Extent3d::new(MINIMAP_SIZE as u32, MINIMAP_SIZE as u32, 1),
+            TextureDimension::D2,
+            vec![0; MINIMAP_SIZE * MINIMAP_SIZE * 4],
+            TextureFormat::Rgba8Unorm,
+        );
+        let texture = world
+            .get_resource_mut::<Assets<Texture>>()
+            .unwrap()
+            .add(texture);
+        Minimap {
+            shown: true,
+            texture: texture,
+        }
+    }
+}
+
+fn setup_minimap(mut egui_context: ResMut<EguiContext>, minimap: Res<Minimap>) {
+    egui_context.set_egui_texture(MINIMAP_TEXTURE, minimap.texture.clone());
+}
+
+fn toggle_minimap(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    mut minimap: ResMut<Minimap>,
+) {
+    if bindings.just_pressed(&keys, Action::ToggleMinimap) {
+        minimap.shown = !minimap.shown;
+    }
+}
+
+// the terrain downscaled with the 2D coloring
+fn colors(
+    elevation: &Elevation,
+    config: &DrawConfig,
+    palette: &Palette,
+    sea_level: f32,
+) -> Vec<u8> {
+    let size = elevation.size();
+    let mut data = vec![255; MINIMAP_SIZE * MINIMAP_SIZE * 4];
+    for p in 0..MINIMAP_SIZE * MINIMAP_SIZE {
+        let (x, y) = (p % MINIMAP_SIZE, p / MINIMAP_SIZE);
+        let i = x * size / MINIMAP_SIZE + y * size / MINIMAP_SIZE * size;
+        let h = elevation.data[i];
+        let slope = config.grad(elevation, i).length();
+        let color = config.color(palette, elevation, i, slope, sea_level);
+        let color = sediment_color(palette, color, h, elevation.sediment[i], sea_level);
+        for c in 0..3 {
+            data[p * 4 + c] = (color[c] * 255.) as u8;
+        }
+    }
+    data
+}
+
+// the terrain is only recolored every MINIMAP_PERIOD frames it changed, the camera moving
+// around (the orbit included) only moves the marker over the colors kept from then
+fn draw_minimap(
+    minimap: Res<Minimap>,
+    mut textures: ResMut<Assets<Texture>>,
+    query_elevation: Query<&Elevation, Without<Compared>>,
+    changed_elevation: Query<Entity, (Changed<Elevation>, Without<Compared>)>,
+    cameras: Query<&GlobalTransform, With<FlyCamera>>,
+    config: Res<DrawConfig>,
+    palette: Res<Palette>,
+    terrain: Res<TerrainConfig>,
+    mut base: Local<Vec<u8>>,
+    mut frame: Local<u32>,
+    mut stale: Local<bool>,
+) {
+    *frame += 1;
+    *stale |= changed_elevation.iter().next().is_some() || config.is_changed();
+    if !minimap.shown {
+        return;
+    }
+    let elevation = match query_elevation.single() {
+        Ok(elevation) => elevation,
+        Err(_) => return,
+    };
+    if base.is_empty() || (*stale && *frame % MINIMAP_PERIOD == 0) {
+        *base = colors(elevation, &config, &palette, terrain.sea_level);
+        *stale = false;
+    }
+    let texture = match textures.get_mut(minimap.texture.id) {
+        Some(texture) => texture,
+        None => return,
+    };
+    texture.data.copy_from_slice(&base);
+    let transform = match cameras.single() {
+        Ok(transform) => transform,
+        Err(_) => return,
+    };
+    // the terrain spans x and z from 0 to its size in cells
+    let scale = MINIMAP_SIZE as f32 / elevation.size() as f32;
+    let pos = Vec2::new(transform.translation.x, transform.translation.z) * scale;
+    let forward = transform.rotation * -Vec3::Z;
+    let facing = Vec2::new(forward.x, forward.z);
+    let facing = if facing.length() > 0. {
+        facing.normalize()
+    } else {
+        Vec2::ZERO
+    };
+    let mut plot = |p: Vec2| {
+        let (x, y) = (p.x.floor() as i32, p.y.floor() as i32);
+        if x >= 0 && y >= 0 && (x as usize) < MINIMAP_SIZE && (y as usize) < MINIMAP_SIZE {
+            let p = (x as usize + y as usize * MINIMAP_SIZE) * 4;
+            texture.data[p..p + 3].copy_from_slice(&[255, 0, 0]);
+        }
+    };
+    for k in 0..=MARKER_LENGTH as usize {
+        plot(pos + facing * k as f32);
+    }
+    for (dx, dy) in [(-1., 0.), (1., 0.), (0., -1.), (0., 1.)].iter() {
+        plot(pos + Vec2::new(*dx, *dy));
+    }
+}
+
+fn minimap_panel(egui_context: Res<EguiContext>, minimap: Res<Minimap>) {
+    if !minimap.shown {
+        return;
+    }
+    egui::Window::new("Minimap")
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.image(
+                egui::TextureId::User(MINIMAP_TEXTURE),
+                [MINIMAP_SIZE as f32, MINIMAP_SIZE as f32],
+            );
+        });
+}
+
+pub struct MinimapView;
+
+impl Plugin for MinimapView {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<Minimap>()
+            .add_startup_system(setup_minimap.system())
+            .add_system(toggle_minimap.system())
+            .add_system_set(
+                SystemSet::on_update(ViewMode::ThreeD)
+                    .with_system(draw_minimap.system())
+                    .with_system(minimap_panel.system()),
+            );
+    }
+}
@@ -0,0 +1,329 @@
+use glam::{Vec2, Vec3};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// heights span [-1, 1] over hundreds of cells, the relief is shaded as if it was this much steeper
+const HILLSHADE_RELIEF: f32 = 60.;
+
+// colors of the terrain and the heights and slope at which they change
+pub struct Palette {
+    pub deep_water: [f32; 3],
+    pub shallow_water: [f32; 3],
+    pub lake: [f32; 3],
+    pub beach: [f32; 3],
+    // grass and rock get darker towards the sea
+    pub grass: [f32; 3],
+    pub rock: [f32; 3],
+    pub snow: [f32; 3],
+    // deposited sediment covering the ground
+    pub sediment: [f32; 3],
+    // the sky behind the 3D view, from straight up to the horizon and below
+    pub sky_zenith: [f32; 3],
+    pub sky_horizon: [f32; 3],
+    // depth under the sea level below which the water is deep
+    pub shallow_depth: f32,
+    // height above the sea level under which the shore is sand
+    pub beach_height: f32,
+    // slope above which the ground is bare rock
+    pub rock_slope: f32,
+    // height above which the ground is snow
+    pub snow_height: f32,
+    // sediment depth above which the ground is fully covered
+    pub sediment_depth: f32,
+    // direction of the light shading the relief in 2D, in degrees clockwise from north
+    // and above the horizon
+    pub light_azimuth: f32,
+    pub light_altitude: f32,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            deep_water: [0.01, 0.05, 0.2],
+            shallow_water: [0.05, 0.15, 0.4],
+            lake: [0.08, 0.25, 0.6],
+            beach: [0.8, 0.9, 0.2],
+            grass: [0.25, 1., 0.33],
+            rock: [0.8, 0.6, 0.5],
+            snow: [0.95, 0.95, 1.],
+            sediment: [0.85, 0.75, 0.5],
+            sky_zenith: [0.3, 0.5, 0.85],
+            sky_horizon: [0.7, 0.8, 0.9],
+            shallow_depth: 0.05,
+            beach_height: 0.03,
+            rock_slope: 0.01,
+            snow_height: 0.8,
+            sediment_depth: 0.02,
+            light_azimuth: 315.,
+            light_altitude: 45.,
+        }
+    }
+}
+
+impl Palette {
+    // Reads a palette from TOML lines of `name = value`, named after the fields of Palette,
+    // with the colors written [r, g, b] in [0, 1] and everything after a # ignored:
+    //   grass = [0.3, 0.9, 0.3]
+    //   snow_height = 0.7
+    // the fields left out keep their default
+    pub fn load(path: &Path, sea_level: f32) -> io::Result<Self> {
+        Palette::parse(&fs::read_to_string(path)?, sea_level)
+    }
+
+    // a palette from the text of a palette file
+    pub fn parse(text: &str, sea_level: f32) -> io::Result<Self> {
+        let mut palette = Palette::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |msg: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", n + 1, msg),
+                )
+            };
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected name = value, got {:?}", line)))?;
+            let (name, value) = (name.trim(), value.trim());
+            let color = || {
+                parse_color(value)
+                    .ok_or_else(|| invalid(format!("invalid color {:?} for {}", value, name)))
+            };
+            let number = || {
+                value
+                    .parse::<f32>()
+                    .map_err(|_| invalid(format!("invalid number {:?} for {}", value, name)))
+            };
+            match name {
+                "deep_water" => palette.deep_water = color()?,
+                "shallow_water" => palette.shallow_water = color()?,
+                "lake" => palette.lake = color()?,
+                "beach" => palette.beach = color()?,
+                "grass" => palette.grass = color()?,
+                "rock" => palette.rock = color()?,
+                "snow" => palette.snow = color()?,
+                "sediment" => palette.sediment = color()?,
+                "sky_zenith" => palette.sky_zenith = color()?,
+                "sky_horizon" => palette.sky_horizon = color()?,
+                "shallow_depth" => palette.shallow_depth = number()?,
+                "beach_height" => palette.beach_height = number()?,
+                "rock_slope" => palette.rock_slope = number()?,
+                "snow_height" => palette.snow_height = number()?,
+                "sediment_depth" => palette.sediment_depth = number()?,
+                "light_azimuth" => palette.light_azimuth = number()?,
+                "light_altitude" => palette.light_altitude = number()?,
+                _ => return Err(invalid(format!("unknown field {:?}", name))),
+            }
+        }
+        palette
+            .check(sea_level)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(palette)
+    }
+
+    // the bands must go up from the deep water to the snow
+    fn check(&self, sea_level: f32) -> Result<(), String> {
+        if self.shallow_depth < 0. {
+            return Err(format!(
+                "shallow_depth must not be negative, got {}",
+                self.shallow_depth
+            ));
+        }
+        if self.beach_height < 0. {
+            return Err(format!(
+                "beach_height must not be negative, got {}",
+                self.beach_height
+            ));
+        }
+        if self.snow_height < sea_level + self.beach_height {
+            return Err(format!(
+                "snow_height {} is below the top of the beach at {}",
+                self.snow_height,
+                sea_level + self.beach_height
+            ));
+        }
+        if self.rock_slope < 0. {
+            return Err(format!(
+                "rock_slope must not be negative, got {}",
+                self.rock_slope
+            ));
+        }
+        if self.sediment_depth <= 0. {
+            return Err(format!(
+                "sediment_depth must be positive, got {}",
+                self.sediment_depth
+            ));
+        }
+        Ok(())
+    }
+}
+
+// `[r, g, b]`
+fn parse_color(s: &str) -> Option<[f32; 3]> {
+    let channels: Vec<f32> = s
+        .strip_prefix('[')?
+        .strip_suffix(']')?
+        .split(',')
+        .map(|c| c.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    match channels[..] {
+        [r, g, b] => Some([r, g, b]),
+        _ => None,
+    }
+}
+
+fn scale(color: [f32; 3], k: f32) -> [f32; 3] {
+    [color[0] * k, color[1] * k, color[2] * k]
+}
+
+// color of a cell of the given height and slope, shared by the 2D and 3D views
+pub fn terrain_color(palette: &Palette, height: f32, slope: f32, sea_level: f32) -> [f32; 3] {
+    if height < sea_level - palette.shallow_depth {
+        palette.deep_water
+    } else if height < sea_level {
+        palette.shallow_water
+    } else if height > palette.snow_height {
+        palette.snow
+    } else if slope > palette.rock_slope {
+        scale(palette.rock, height)
+    } else if height < sea_level + palette.beach_height {
+        palette.beach
+    } else {
+        scale(palette.grass, height)
+    }
+}
+
+// blends the color of a land cell towards sand as sediment piles up on it
+pub fn sediment_color(
+    palette: &Palette,
+    color: [f32; 3],
+    height: f32,
+    sediment: f32,
+    sea_level: f32,
+) -> [f32; 3] {
+    if height < sea_level || sediment <= 0. {
+        return color;
+    }
+    let k = (sediment / palette.sediment_depth).min(1.);
+    let mut blend = color;
+    for c in 0..3 {
+        blend[c] = color[c] * (1. - k) + palette.sediment[c] * k;
+    }
+    blend
+}
+
+// from blue for 0 to red for 1 through cyan, green and yellow
+pub fn heat_color(t: f32) -> [f32; 3] {
+    let t = t.clamp(0., 1.);
+    [
+        (2. * t - 0.5).clamp(0., 1.),
+        (1.5 - (4. * t - 2.).abs()).clamp(0., 1.),
+        (1.5 - 4. * t).clamp(0., 1.),
+    ]
+}
+
+// fully saturated color of hue t, from red at 0 through green and blue back to red at 1
+pub fn hue(t: f32) -> [f32; 3] {
+    let t = t.rem_euclid(1.) * 6.;
+    let channel = |offset: f32| (((t - offset).rem_euclid(6.) - 3.).abs() - 1.).clamp(0., 1.);
+    [channel(0.), channel(2.), channel(4.)]
+}
+
+// heat colors of the erosion accumulated on each cell relative to the busiest cell, on a square
+// root scale so the quieter channels still show next to the main rivers
+pub fn erosion_colors(accum: &[f32]) -> Vec<[f32; 3]> {
+    let busiest = accum.iter().copied().fold(0., f32::max);
+    accum
+        .iter()
+        .map(|a| {
+            heat_color(if busiest > 0. {
+                (a / busiest).sqrt()
+            } else {
+                0.
+            })
+        })
+        .collect()
+}
+
+// brightness in [0, 1] of a cell of the given gradient lit by the palette's light,
+// north is towards the first row
+pub fn hillshade(palette: &Palette, grad: Vec2) -> f32 {
+    let (azimuth, altitude) = (
+        palette.light_azimuth.to_radians(),
+        palette.light_altitude.to_radians(),
+    );
+    let light = Vec3::new(
+        altitude.cos() * azimuth.sin(),
+        -altitude.cos() * azimuth.cos(),
+        altitude.sin(),
+    );
+    let normal = Vec3::new(-grad.x * HILLSHADE_RELIEF, -grad.y * HILLSHADE_RELIEF, 1.).normalize();
+    normal.dot(light).max(0.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_color_bands_change_at_their_heights() {
+        let p = Palette::default();
+        let color = |height: f32, slope: f32| terrain_color(&p, height, slope, 0.);
+        assert_eq!(color(-0.06, 0.), p.deep_water);
+        assert_eq!(color(-0.05, 0.), p.shallow_water);
+        assert_eq!(color(-0.001, 0.), p.shallow_water);
+        assert_eq!(color(0., 0.), p.beach);
+        assert_eq!(color(0.03, 0.), scale(p.grass, 0.03));
+        assert_eq!(color(0.8, 0.), scale(p.grass, 0.8));
+        assert_eq!(color(0.81, 0.), p.snow);
+        // steep ground is bare rock, the beach included but not the snow
+        assert_eq!(color(0.01, 0.02), scale(p.rock, 0.01));
+        assert_eq!(color(0.5, 0.02), scale(p.rock, 0.5));
+        assert_eq!(color(0.9, 0.02), p.snow);
+    }
+
+    #[test]
+    fn slopes_facing_the_light_are_brighter() {
+        let p = Palette::default();
+        let flat = hillshade(&p, Vec2::ZERO);
+        assert!((flat - p.light_altitude.to_radians().sin()).abs() < 1e-6);
+        // the light comes from the north west, towards the first row and column
+        let facing = hillshade(&p, Vec2::new(0.005, 0.005));
+        let away = hillshade(&p, Vec2::new(-0.005, -0.005));
+        assert!(facing > flat && flat > away);
+        // tilting across the light dims less than tilting away from it
+        let across = hillshade(&p, Vec2::new(0.005, -0.005));
+        assert!(across < flat && across > away);
+    }
+
+    #[test]
+    fn palettes_parse_and_report_the_bad_lines() {
+        let p = Palette::parse(
+            "# a drier palette\ngrass = [0.5, 0.6, 0.2]\n\nsnow_height = 0.7 # lower snow\n",
+            0.,
+        )
+        .unwrap();
+        assert_eq!(p.grass, [0.5, 0.6, 0.2]);
+        assert_eq!(p.snow_height, 0.7);
+        // the fields left out keep their default
+        assert_eq!(p.rock, Palette::default().rock);
+        for (text, line) in [
+            ("grass = [0.5, 0.6]", "line 1"),
+            ("\nsnow_height = high", "line 2"),
+            ("lava = [1, 0, 0]", "line 1"),
+            ("grass [0.5, 0.6, 0.2]", "line 1"),
+        ]
+        .iter()
+        {
+            let e = Palette::parse(text, 0.).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(e.to_string().starts_with(line), "{}", e);
+        }
+        // the bands out of order
+        assert!(Palette::parse("snow_height = 0.01", 0.).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use crate::draw3d::{FlyCamera, HeightScale};
+use crate::erosion::Compared;
+use crate::input::{Action, KeyBindings};
+use crate::view::ViewMode;
+use bevy::prelude::*;
+use terrain_gen::config::TerrainConfig;
+use terrain_gen::mesh::pick_terrain;
+use terrain_gen::terrain::Elevation;
+
+// position on the terrain grid under the mouse cursor, None when it's off the terrain
+#[derive(Default)]
+pub struct Cursor(pub Option<Vec2>);
+
+// the terrain sprite is centered on the 2D camera with one pixel per cell, row 0 at the top
+fn pick_2d(elevation: &Elevation, camera: &GlobalTransform, offset: Vec2) -> Option<Vec2> {
+    let size = elevation.size() as f32;
+    let world = camera.translation.truncate() + offset;
+    let (col, row) = (world.x + size / 2., size / 2. - world.y);
+    if col < 0. || row < 0. || col >= size || row >= size {
+        return None;
+    }
+    Some(elevation.center(col as usize + row as usize * elevation.size()))
+}
+
+// the terrain under the ray through the cursor
+fn pick_3d(
+    elevation: &Elevation,
+    sea_level: f32,
+    height_scale: f32,
+    camera: &Camera,
+    transform: &GlobalTransform,
+    ndc: Vec2,
+) -> Option<Vec2> {
+    let ndc_to_world = transform.compute_matrix() * camera.projection_matrix.inverse();
+    // from the near plane rather than the camera so orthographic rays start in the right place
+    let origin = ndc_to_world.project_point3(ndc.extend(0.));
+    let far = ndc_to_world.project_point3(ndc.extend(1.));
+    pick_terrain(origin, far - origin, elevation, sea_level, height_scale)
+        .map(|(_, hit)| Vec2::new(hit.x, hit.z))
+}
+
+fn pick(
+    windows: Res<Windows>,
+    view: Res<State<ViewMode>>,
+    terrain: Res<TerrainConfig>,
+    scale: Res<HeightScale>,
+    query_elevation: Query<&Elevation, Without<Compared>>,
+    cameras_2d: Query<&GlobalTransform, (With<Camera>, Without<FlyCamera>)>,
+    cameras_3d: Query<(&Camera, &GlobalTransform), With<FlyCamera>>,
+    mut cursor: ResMut<Cursor>,
+) {
+    cursor.0 = None;
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let (screen, elevation) = match (window.cursor_position(), query_elevation.single()) {
+        (Some(screen), Ok(elevation)) => (screen, elevation),
+        _ => return,
+    };
+    let window_size = Vec2::new(window.width(), window.height());
+    cursor.0 = match view.current() {
+        ViewMode::TwoD => cameras_2d
+            .single()
+            .ok()
+            .and_then(|camera| pick_2d(elevation, camera, screen - window_size / 2.)),
+        ViewMode::ThreeD => cameras_3d.single().ok().and_then(|(camera, transform)| {
+            let ndc = screen / window_size * 2. - Vec2::ONE;
+            pick_3d(
+                elevation,
+                terrain.sea_level,
+                scale.0,
+                camera,
+                transform,
+                ndc,
+            )
+        }),
+    };
+}
+
+// the terrain at the last cell clicked, shown in the Cell window
+#[derive(Default)]
+pub struct QueriedCell(pub Option<CellInfo>);
+
+pub struct CellInfo {
+    pub index: usize,
+    pub pos: Vec2,
+    pub height: f32,
+    pub grad: Vec2,
+}
+
+// queries the terrain under the cursor on left click, the clicks of the brushes are left to them
+fn query_cell(
+    keys: Res<Input<KeyCode>>,
+    buttons: Res<Input<MouseButton>>,
+    bindings: Res<KeyBindings>,
+    cursor: Res<Cursor>,
+    query_elevation: Query<&Elevation, Without<Compared>>,
+    mut queried: ResMut<QueriedCell>,
+) {
+    if !buttons.just_pressed(MouseButton::Left)
+        || bindings.pressed(&keys, Action::Sculpt)
+        || bindings.pressed(&keys, Action::PaintSources)
+    {
+        return;
+    }
+    if let (Some(pos), Ok(elevation)) = (cursor.0, query_elevation.single()) {
+        let i = elevation.index(pos);
+        queried.0 = Some(CellInfo {
+            index: i,
+            pos: pos,
+            height: elevation.data[i],
+            grad: elevation.grad(i),
+        });
+    }
+}
+
+pub struct Pick;
+
+impl Plugin for Pick {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<Cursor>()
+            .init_resource::<QueriedCell>()
+            .add_system(pick.system().label("pick"))
+            .add_system(query_cell.system().after("pick"));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::config::TerrainConfig;
+use crate::terrain::{ErosionParams, FractalParams, IslandShape, NoiseKind};
+use serde::Serialize;
+use std::str::FromStr;
+
+// parameters known to go well together, for a starting point
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    // sharp ridges cut deep by fast eroding rivers that carry their sediment far
+    Canyon,
+    // smooth low hills with wide valleys filled with sediment
+    RollingHills,
+    // many small islands in a high sea
+    Archipelago,
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Preset::ALL
+            .iter()
+            .find(|preset| preset.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "unknown preset {:?}, expected canyon, rolling-hills or archipelago",
+                    s
+                )
+            })
+    }
+}
+
+impl Preset {
+    pub const ALL: [Preset; 3] = [Preset::Canyon, Preset::RollingHills, Preset::Archipelago];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::Canyon => "canyon",
+            Preset::RollingHills => "rolling-hills",
+            Preset::Archipelago => "archipelago",
+        }
+    }
+
+    pub fn params(&self) -> ErosionParams {
+        match self {
+            Preset::Canyon => ErosionParams {
+                evaporation: 0.02,
+                inertia: 0.3,
+                capacity: 1600.,
+                deposition: 0.02,
+                erosion: 0.03,
+                radius: 1,
+                ..ErosionParams::default()
+            },
+            Preset::RollingHills => ErosionParams {
+                evaporation: 0.05,
+                inertia: 0.05,
+                capacity: 400.,
+                deposition: 0.3,
+                erosion: 0.005,
+                radius: 3,
+                ..ErosionParams::default()
+            },
+            Preset::Archipelago => ErosionParams {
+                evaporation: 0.08,
+                capacity: 800.,
+                deposition: 0.1,
+                erosion: 0.01,
+                radius: 2,
+                ..ErosionParams::default()
+            },
+        }
+    }
+
+    // sets the noise, island and sea level the terrain is generated with
+    pub fn shape(&self, config: &mut TerrainConfig) {
+        let (noise, fractal, island, sea_level) = match self {
+            Preset::Canyon => (
+                NoiseKind::RidgedMulti,
+                FractalParams {
+                    octaves: Some(6),
+                    lacunarity: Some(2.2),
+                    ..FractalParams::default()
+                },
+                IslandShape {
+                    strength: 0.6,
+                    radius: 0.8,
+                    ..IslandShape::default()
+                },
+                -0.3,
+            ),
+            Preset::RollingHills => (
+                NoiseKind::Fbm,
+                FractalParams {
+                    octaves: Some(4),
+                    frequency: Some(0.8),
+                    persistence: Some(0.35),
+                    ..FractalParams::default()
+                },
+                IslandShape {
+                    strength: 0.5,
+                    radius: 0.9,
+                    ..IslandShape::default()
+                },
+                -0.4,
+            ),
+            Preset::Archipelago => (
+                NoiseKind::Fbm,
+                FractalParams {
+                    octaves: Some(6),
+                    frequency: Some(2.5),
+                    persistence: Some(0.5),
+                    ..FractalParams::default()
+                },
+                IslandShape {
+                    strength: 0.8,
+                    radius: 0.4,
+                    ..IslandShape::default()
+                },
+                0.15,
+            ),
+        };
+        config.noise = noise;
+        config.fractal = fractal;
+        config.island = island;
+        config.sea_level = sea_level;
+        config.preset = Some(*self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_stay_in_range() {
+        for preset in Preset::ALL.iter() {
+            let params = preset.params();
+            assert!(
+                params.evaporation > 0. && params.evaporation < 1.,
+                "{:?}",
+                preset
+            );
+            assert!((0. ..=1.).contains(&params.inertia), "{:?}", preset);
+            assert!(params.min_slope >= 0., "{:?}", preset);
+            assert!(params.capacity > 0., "{:?}", preset);
+            assert!(
+                params.deposition > 0. && params.deposition <= 1.,
+                "{:?}",
+                preset
+            );
+            assert!(params.erosion > 0. && params.erosion <= 1., "{:?}", preset);
+            assert!(params.radius >= 1, "{:?}", preset);
+            assert!(params.max_velocity > 0., "{:?}", preset);
+            assert!(
+                params.max_water > 0. && params.max_water <= 1.,
+                "{:?}",
+                preset
+            );
+            let mut config = TerrainConfig::default();
+            preset.shape(&mut config);
+            assert_eq!(config.preset, Some(*preset));
+            assert!((-1. ..=1.).contains(&config.sea_level), "{:?}", preset);
+            assert!((0. ..=1.).contains(&config.island.strength), "{:?}", preset);
+            assert!(config.island.radius > 0., "{:?}", preset);
+            assert!(
+                config.fractal.octaves.map_or(true, |o| o >= 1),
+                "{:?}",
+                preset
+            );
+            assert_eq!(preset.name().parse::<Preset>(), Ok(*preset));
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use crate::config::posterize;
+use glam::{Mat4, Vec3, Vec4};
+
+// the light the 3D shaders shade the meshes with, every color is linear
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Lighting {
+    // towards the sun
+    pub sun_direction: Vec3,
+    pub sun_color: Vec3,
+    // part of the light reaching the faces turned away from the sun
+    pub ambient: f32,
+    pub fog_color: Vec3,
+    // 0 is no fog at all
+    pub fog_density: f32,
+}
+
+// a triangle list as it's handed to the GPU, the colors are linear
+#[derive(Clone, PartialEq, Debug)]
+pub struct Shaded {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub colors: Vec<[f32; 3]>,
+    pub indices: Vec<u32>,
+    // translation of the mesh in the world
+    pub offset: Vec3,
+    // 1 is opaque, the meshes that aren't are blended over what was drawn before them
+    pub alpha: f32,
+    // color levels per channel, under 2 keeps the colors as they are
+    pub posterize: u32,
+}
+
+// an image of the 3D view drawn on the CPU with the same shading as the GPU,
+// bevy can't read the window back
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    view_proj: Mat4,
+    eye: Vec3,
+    colors: Vec<Vec3>,
+    depth: Vec<f32>,
+}
+
+// twice the signed area of the triangle abp on the screen
+fn edge(a: Vec3, b: Vec3, p: Vec3) -> f32 {
+    (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+}
+
+// a linear channel encoded like the srgb swap chain does
+fn srgb(c: f32) -> u8 {
+    let c = c.clamp(0., 1.);
+    let s = if c <= 0.003_130_8 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    };
+    (s * 255.).round() as u8
+}
+
+impl Frame {
+    // the sky from the horizon to the zenith everywhere, at the depth of the far plane
+    pub fn new(
+        width: usize,
+        height: usize,
+        view_proj: Mat4,
+        eye: Vec3,
+        zenith: Vec3,
+        horizon: Vec3,
+    ) -> Self {
+        let unproject = view_proj.inverse();
+        let colors = (0..width * height)
+            .map(|i| {
+                let x = ((i % width) as f32 + 0.5) / width as f32 * 2. - 1.;
+                let y = 1. - ((i / width) as f32 + 0.5) / height as f32 * 2.;
+                let far = unproject * Vec4::new(x, y, 1., 1.);
+                let dir = far.truncate() / far.w - eye;
+                // the horizon color goes on below the horizon
+                let up = dir.normalize().y.max(0.);
+                horizon + (zenith - horizon) * up.sqrt()
+            })
+            .collect();
+        Frame {
+            width: width,
+            height: height,
+            view_proj: view_proj,
+            eye: eye,
+            colors: colors,
+            depth: vec![1.; width * height],
+        }
+    }
+
+    // lambert from the sun, posterized then fogged with the distance to the eye
+    fn shade(&self, color: Vec3, normal: Vec3, pos: Vec3, levels: u32, light: &Lighting) -> Vec3 {
+        let lambert = normal
+            .normalize()
+            .dot(light.sun_direction.normalize())
+            .max(0.);
+        let lit = color * (light.ambient + (1. - light.ambient) * lambert * light.sun_color);
+        let lit = Vec3::new(
+            posterize(lit.x, levels),
+            posterize(lit.y, levels),
+            posterize(lit.z, levels),
+        );
+        let fog = 1. - (-(light.fog_density * (pos - self.eye).length()).powi(2)).exp();
+        lit + (light.fog_color - lit) * fog
+    }
+
+    // draws the triangles in front of what's already there, the ones crossing the near plane
+    // are left out rather than clipped
+    pub fn draw(&mut self, mesh: &Shaded, light: &Lighting) {
+        let (width, height) = (self.width as f32, self.height as f32);
+        let world: Vec<Vec3> = mesh
+            .positions
+            .iter()
+            .map(|p| Vec3::from(*p) + mesh.offset)
+            .collect();
+        let clip: Vec<Vec4> = world
+            .iter()
+            .map(|p| self.view_proj * p.extend(1.))
+            .collect();
+        for triangle in mesh.indices.chunks_exact(3) {
+            let v = [
+                triangle[0] as usize,
+                triangle[1] as usize,
+                triangle[2] as usize,
+            ];
+            if v.iter().any(|&v| clip[v].w <= 0. || clip[v].z < 0.) {
+                continue;
+            }
+            // pixel coordinates with the rows going down, and the depth
+            let to_screen = |v: usize| {
+                let ndc = clip[v].truncate() / clip[v].w;
+                Vec3::new((ndc.x + 1.) / 2. * width, (1. - ndc.y) / 2. * height, ndc.z)
+            };
+            let screen = [to_screen(v[0]), to_screen(v[1]), to_screen(v[2])];
+            let area = edge(screen[0], screen[1], screen[2]);
+            if area.abs() < f32::EPSILON {
+                continue;
+            }
+            let lo = screen[0].min(screen[1]).min(screen[2]);
+            let hi = screen[0].max(screen[1]).max(screen[2]);
+            let (x0, y0) = (lo.x.max(0.) as usize, lo.y.max(0.) as usize);
+            let (x1, y1) = (
+                hi.x.ceil().min(width) as usize,
+                hi.y.ceil().min(height) as usize,
+            );
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let p = Vec3::new(x as f32 + 0.5, y as f32 + 0.5, 0.);
+                    let b = [
+                        edge(screen[1], screen[2], p) / area,
+                        edge(screen[2], screen[0], p) / area,
+                        edge(screen[0], screen[1], p) / area,
+                    ];
+                    if b.iter().any(|b| *b < 0.) {
+                        continue;
+                    }
+                    let i = x + y * self.width;
+                    let z = b[0] * screen[0].z + b[1] * screen[1].z + b[2] * screen[2].z;
+                    if z > 1. || z >= self.depth[i] {
+                        continue;
+                    }
+                    // the attributes are interpolated in the world, not on the screen
+                    let w = [
+                        b[0] / clip[v[0]].w,
+                        b[1] / clip[v[1]].w,
+                        b[2] / clip[v[2]].w,
+                    ];
+                    let sum = w[0] + w[1] + w[2];
+                    let lerp = |attr: &dyn Fn(usize) -> Vec3| {
+                        (attr(v[0]) * w[0] + attr(v[1]) * w[1] + attr(v[2]) * w[2]) / sum
+                    };
+                    let color = self.shade(
+                        lerp(&|v| Vec3::from(mesh.colors[v])),
+                        lerp(&|v| Vec3::from(mesh.normals[v])),
+                        lerp(&|v| world[v]),
+                        mesh.posterize,
+                        light,
+                    );
+                    self.colors[i] = self.colors[i] + (color - self.colors[i]) * mesh.alpha;
+                    self.depth[i] = z;
+                }
+            }
+        }
+    }
+
+    // 8 bits per channel rows from the top down, as the window shows them
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.colors
+            .iter()
+            .flat_map(|c| vec![srgb(c.x), srgb(c.y), srgb(c.z), 255])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sky() -> Vec3 {
+        Vec3::new(0.2, 0.4, 0.8)
+    }
+
+    // from straight above, the square [-1, 1] on the ground fills the whole frame
+    fn top_down(size: usize) -> Frame {
+        let view = Mat4::look_at_rh(Vec3::new(0., 10., 0.), Vec3::ZERO, -Vec3::Z);
+        let proj = Mat4::orthographic_rh(-1., 1., -1., 1., 1., 20.);
+        Frame::new(
+            size,
+            size,
+            proj * view,
+            Vec3::new(0., 10., 0.),
+            sky(),
+            sky(),
+        )
+    }
+
+    // a flat quad at height y over x and z in [lo, hi]
+    fn quad(lo: f32, hi: f32, y: f32, color: [f32; 3], alpha: f32) -> Shaded {
+        Shaded {
+            positions: vec![[lo, y, lo], [hi, y, lo], [lo, y, hi], [hi, y, hi]],
+            normals: vec![[0., 1., 0.]; 4],
+            colors: vec![color; 4],
+            indices: vec![0, 2, 1, 1, 2, 3],
+            offset: Vec3::ZERO,
+            alpha: alpha,
+            posterize: 0,
+        }
+    }
+
+    // sun straight above so flat ground is fully lit, and no fog
+    fn noon() -> Lighting {
+        Lighting {
+            sun_direction: Vec3::Y,
+            sun_color: Vec3::ONE,
+            ambient: 0.3,
+            fog_color: Vec3::ZERO,
+            fog_density: 0.,
+        }
+    }
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> [u8; 3] {
+        let rgba = frame.to_rgba();
+        let i = (x + y * frame.width) * 4;
+        [rgba[i], rgba[i + 1], rgba[i + 2]]
+    }
+
+    #[test]
+    fn the_nearest_triangles_hide_the_others_and_the_sky() {
+        let mut frame = top_down(8);
+        frame.draw(&quad(-0.5, 0.5, 1., [1., 0., 0.], 1.), &noon());
+        // drawn after the red one but lower, so only seen around it
+        frame.draw(&quad(-1., 1., 0., [0., 1., 0.], 1.), &noon());
+        assert_eq!(pixel(&frame, 4, 4), [255, 0, 0]);
+        assert_eq!(pixel(&frame, 0, 0), [0, 255, 0]);
+        let mut empty = top_down(8);
+        empty.draw(&quad(-0.5, 0.5, 0., [1., 0., 0.], 1.), &noon());
+        assert_eq!(pixel(&empty, 0, 0), [srgb(0.2), srgb(0.4), srgb(0.8)]);
+    }
+
+    #[test]
+    fn transparent_meshes_blend_over_and_fog_fades_with_distance() {
+        let mut frame = top_down(4);
+        frame.draw(&quad(-1., 1., 0., [1., 0., 0.], 1.), &noon());
+        frame.draw(&quad(-1., 1., 1., [0., 0., 1.], 0.5), &noon());
+        assert_eq!(pixel(&frame, 2, 2), [srgb(0.5), 0, srgb(0.5)]);
+        let fog = Lighting {
+            fog_color: Vec3::ONE,
+            fog_density: 0.1,
+            ..noon()
+        };
+        let (mut near, mut far) = (top_down(4), top_down(4));
+        near.draw(&quad(-1., 1., 5., [0., 0., 0.], 1.), &fog);
+        far.draw(&quad(-1., 1., -5., [0., 0., 0.], 1.), &fog);
+        assert!(pixel(&near, 2, 2)[0] < pixel(&far, 2, 2)[0]);
+    }
+
+    #[test]
+    fn faces_turned_away_from_the_sun_only_get_the_ambient_light() {
+        let mut frame = top_down(4);
+        let light = Lighting {
+            sun_direction: -Vec3::Y,
+            ..noon()
+        };
+        frame.draw(&quad(-1., 1., 0., [1., 1., 1.], 1.), &light);
+        assert_eq!(pixel(&frame, 1, 1), [srgb(0.3); 3]);
+    }
+}
@@ -0,0 +1,2666 @@
+use crate::config::TerrainConfig;
+use crate::hydrology::rain_shadow;
+use glam::Vec2;
+use image::imageops::{self, FilterType};
+use itertools::iproduct;
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin, RidgedMulti, Seedable, Worley};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::ops::DerefMut;
+use std::path::Path;
+use std::str::FromStr;
+
+// Hydrolic erosion parameters
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct ErosionParams {
+    pub evaporation: f32,
+    pub inertia: f32,
+    pub min_slope: f32,
+    pub capacity: f32,
+    pub deposition: f32,
+    pub erosion: f32,
+    // cells around a droplet that its erosion and deposition spread over
+    pub radius: usize,
+    // droplets diving down cliffs are slowed down to this so they don't carve spikes
+    pub max_velocity: f32,
+    // water a droplet can carry, evaporation can't push it above
+    pub max_water: f32,
+}
+
+impl Default for ErosionParams {
+    fn default() -> Self {
+        ErosionParams {
+            evaporation: 0.05,
+            inertia: 0.1,
+            min_slope: 0.,
+            capacity: 800.0,
+            deposition: 0.1,
+            erosion: 0.01,
+            radius: 1,
+            max_velocity: 2.,
+            max_water: 1.,
+        }
+    }
+}
+// weights of the cells within radius of a deposit, falling off with the distance and summing to 1
+pub struct Kernel {
+    radius: usize,
+    weights: Vec<(Vec2, f32)>,
+}
+
+impl Kernel {
+    pub fn new(radius: usize) -> Self {
+        let r = radius as i32;
+        // a quarter of the center weight at distance radius
+        let spread = (radius as f32).powi(2);
+        let mut weights: Vec<(Vec2, f32)> = iproduct!(-r..=r, -r..=r)
+            .map(|(dx, dy)| Vec2::new(dx as f32, dy as f32))
+            .map(|d| {
+                let w = if radius <= 1 {
+                    // the original 3x3 weights
+                    match d.x.abs() + d.y.abs() {
+                        l if l < 1. => 0.4,
+                        l if l < 2. => 0.1,
+                        _ => 0.05,
+                    }
+                } else {
+                    0.25f32.powf(d.length_squared() / spread)
+                };
+                (d, w)
+            })
+            .collect();
+        let total: f32 = weights.iter().map(|(_, w)| w).sum();
+        for (_, w) in weights.iter_mut() {
+            *w /= total;
+        }
+        Kernel { radius, weights }
+    }
+
+    pub fn radius(&self) -> usize {
+        self.radius
+    }
+}
+
+impl Default for Kernel {
+    fn default() -> Self {
+        Kernel::new(ErosionParams::default().radius)
+    }
+}
+
+// steps after which a droplet is despawned even if it still has water
+const MAX_LIFETIME: u32 = 64;
+
+// Thermal erosion constants
+// steepest stable slope, in height per cell
+const TALUS_ANGLE: f32 = 0.012;
+// fraction of the excess height moved downhill per pass
+const THERMAL_RATE: f32 = 0.5;
+// Wind erosion constants
+// height the wind lifts per pass from a dry cell on flat ground, sediment first and then
+// the weathered rock under it
+const WIND_RATE: f32 = 1e-4;
+// how much more it lifts per unit of height the cell rises above its upwind neighbor
+const WINDWARD_BOOST: f32 = 100.;
+// cells the lifted sediment travels before settling
+const WIND_HOP: f32 = 2.;
+// share of the lifted sediment landing on each side of the cell it's blown to,
+// which lines the ripples of neighboring rows up across the wind
+const WIND_SPREAD: f32 = 0.25;
+// the ice carves much wider than the droplets, which gives its valleys their U shape
+const GLACIER_RADIUS: usize = 6;
+// sideways push given to droplets so channels meander instead of running straight,
+// driven by a slowly varying noise so neighboring droplets bend the same way
+pub struct Meander {
+    amplitude: f32,
+    frequency: f32,
+    noise: Perlin,
+}
+
+impl Default for Meander {
+    fn default() -> Self {
+        Meander {
+            amplitude: 0.,
+            frequency: 0.05,
+            noise: Perlin::new(),
+        }
+    }
+}
+
+impl Meander {
+    // droplets pushed up to amplitude times their speed sideways, by a noise of the given
+    // frequency drawn from seed
+    pub fn new(amplitude: f32, frequency: f32, seed: u32) -> Self {
+        Meander {
+            amplitude: amplitude,
+            frequency: frequency,
+            noise: Perlin::new().set_seed(seed),
+        }
+    }
+
+    // the same push from a noise drawn from another seed
+    pub fn reseed(&mut self, seed: u32) {
+        self.noise = Perlin::new().set_seed(seed);
+    }
+
+    // lateral offset for a droplet at pos going in dir with speed vel
+    fn offset(&self, pos: Vec2, dir: Vec2, vel: f32) -> Vec2 {
+        let p = pos * self.frequency;
+        let wave = self.noise.get([p.x as f64, p.y as f64]) as f32;
+        Vec2::new(-dir.y, dir.x) * wave * self.amplitude * vel
+    }
+}
+
+// noise the terrain is generated from
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoiseKind {
+    Perlin,
+    Fbm,
+    // sharp mountain ridges
+    #[serde(rename = "ridged")]
+    RidgedMulti,
+    // cell like plateaus
+    Worley,
+}
+
+impl FromStr for NoiseKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "perlin" => Ok(NoiseKind::Perlin),
+            "fbm" => Ok(NoiseKind::Fbm),
+            "ridged" => Ok(NoiseKind::RidgedMulti),
+            "worley" => Ok(NoiseKind::Worley),
+            _ => Err(format!(
+                "unknown noise {:?}, expected perlin, fbm, ridged or worley",
+                s
+            )),
+        }
+    }
+}
+
+// overrides of the fractal noises parameters, None keeps the noise's default
+#[derive(Clone, Default, PartialEq, Debug, Serialize)]
+pub struct FractalParams {
+    pub octaves: Option<usize>,
+    pub frequency: Option<f64>,
+    pub lacunarity: Option<f64>,
+    pub persistence: Option<f64>,
+}
+
+impl FractalParams {
+    fn apply<T: MultiFractal>(&self, mut noise: T) -> T {
+        if let Some(octaves) = self.octaves {
+            noise = noise.set_octaves(octaves);
+        }
+        if let Some(frequency) = self.frequency {
+            noise = noise.set_frequency(frequency);
+        }
+        if let Some(lacunarity) = self.lacunarity {
+            noise = noise.set_lacunarity(lacunarity);
+        }
+        if let Some(persistence) = self.persistence {
+            noise = noise.set_persistence(persistence);
+        }
+        noise
+    }
+}
+
+// noises sampled in 2D, or in 4D to tile on a torus
+pub trait TerrainNoise: NoiseFn<[f64; 2]> + NoiseFn<[f64; 4]> {}
+
+impl<T: NoiseFn<[f64; 2]> + NoiseFn<[f64; 4]>> TerrainNoise for T {}
+
+impl NoiseKind {
+    pub fn build(&self, seed: u32, fractal: &FractalParams) -> Box<dyn TerrainNoise> {
+        match self {
+            NoiseKind::Perlin => Box::new(Perlin::new().set_seed(seed)),
+            NoiseKind::Fbm => Box::new(fractal.apply(Fbm::new().set_seed(seed))),
+            NoiseKind::RidgedMulti => Box::new(fractal.apply(RidgedMulti::new().set_seed(seed))),
+            NoiseKind::Worley => Box::new(Worley::new().set_seed(seed)),
+        }
+    }
+}
+
+// distance between two hex rows
+const SQRT3_2: f32 = 0.866_025_4;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Grid {
+    Square,
+    // pointy top hexagons, odd rows are shifted right by half a cell
+    Hex,
+}
+
+impl FromStr for Grid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(Grid::Square),
+            "hex" => Ok(Grid::Hex),
+            _ => Err(format!("unknown grid {:?}, expected square or hex", s)),
+        }
+    }
+}
+
+impl Grid {
+    // world size of a grid with `size` cells per side
+    pub fn extent(&self, size: usize) -> Vec2 {
+        match self {
+            Grid::Square => Vec2::splat(size as f32),
+            Grid::Hex => Vec2::new(size as f32, size as f32 * SQRT3_2),
+        }
+    }
+
+    // world position of the center of cell i
+    fn center(&self, i: usize, size: usize) -> Vec2 {
+        let (col, row) = coords(i, size);
+        match self {
+            Grid::Square => Vec2::new(col as f32, row as f32),
+            Grid::Hex => hex_center(col as i32, row as i32),
+        }
+    }
+}
+
+fn hex_center(col: i32, row: i32) -> Vec2 {
+    Vec2::new(col as f32 + 0.5 * (row & 1) as f32, row as f32 * SQRT3_2)
+}
+
+// what happens past the edges of the grid
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Topology {
+    // positions are clamped to the border cells
+    Clamped,
+    // the left edge connects to the right one and the top edge to the bottom one
+    Toroidal,
+}
+
+impl FromStr for Topology {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamped" => Ok(Topology::Clamped),
+            "toroidal" => Ok(Topology::Toroidal),
+            _ => Err(format!(
+                "unknown topology {:?}, expected clamped or toroidal",
+                s
+            )),
+        }
+    }
+}
+
+pub fn rand_pos(config: &TerrainConfig, rng: &mut impl Rng) -> Vec2 {
+    let extent = config.grid.extent(config.size);
+    Vec2::new(rng.gen::<f32>() * extent.x, rng.gen::<f32>() * extent.y)
+}
+
+pub struct Droplet {
+    pub pos: Vec2,
+    dir: Vec2,
+    vel: f32,
+    pub water: f32,
+    pub sediment: f32,
+    steps: u32,
+    // where the droplet picked up the most sediment at once, and how much
+    origin: Vec2,
+    pickup: f32,
+    // droplets are stepped in the order they were spawned so runs are reproducible
+    pub order: u64,
+}
+
+impl Droplet {
+    pub fn new(pos: Vec2, order: u64) -> Self {
+        Droplet {
+            pos: pos,
+            dir: Vec2::default(),
+            vel: 0.,
+            water: 1.,
+            sediment: 0.,
+            steps: 0,
+            origin: pos,
+            pickup: 0.,
+            order: order,
+        }
+    }
+
+    // how far the sediment deposited at pos traveled
+    pub fn transport(&self, pos: Vec2) -> f32 {
+        (pos - self.origin).length()
+    }
+
+    // the droplet stops in still water, returns the sediment it drops there
+    pub fn settle(&mut self) -> f32 {
+        let sediment = self.sediment;
+        self.sediment = 0.;
+        self.water = 0.;
+        sediment
+    }
+
+    // out of water, too old, or off a grid of the given extent
+    pub fn finished(&self, extent: Vec2) -> bool {
+        let outside =
+            self.pos.x < 0. || self.pos.y < 0. || self.pos.x >= extent.x || self.pos.y >= extent.y;
+        self.water < f32::EPSILON || self.steps > MAX_LIFETIME || outside
+    }
+}
+
+pub fn unroll(pos: Vec2, size: usize) -> usize {
+    let x = if pos.x < 0. {
+        0
+    } else if pos.x >= size as f32 {
+        size - 1
+    } else {
+        pos.x as usize
+    };
+    let y = if pos.y < 0. {
+        0
+    } else if pos.y >= size as f32 {
+        size - 1
+    } else {
+        pos.y as usize
+    };
+    x % size + y * size
+}
+
+// the offset rows of the hex grid don't line up across the edges of a torus
+fn check_layout(grid: Grid, topology: Topology) {
+    if grid == Grid::Hex && topology == Topology::Toroidal {
+        panic!("the toroidal topology only works on the square grid");
+    }
+}
+
+// column and row of cell i, the inverse of unroll
+pub fn coords(i: usize, size: usize) -> (usize, usize) {
+    (i % size, i / size)
+}
+// radial term added to the noise to shape the terrain into an island
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct IslandShape {
+    pub enabled: bool,
+    // height lost per unit of distance to the center
+    pub strength: f32,
+    // distance to the center of the shore, the center is [-1, 1]
+    pub radius: f32,
+    // carve a central crater instead of raising a central island
+    pub invert: bool,
+    // number of islands scattered at random, 1 is a single central one
+    pub islands: usize,
+}
+
+// one of the islands of an archipelago, in the [-1, 1] coordinates of the falloff
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct IslandCenter {
+    pub pos: Vec2,
+    pub radius: f32,
+    pub strength: f32,
+}
+
+// depth of the shallow water along the coast where the rivers spread their sediment into deltas
+const DELTA_DEPTH: f32 = 0.05;
+// how many times wider the sediment spreads in the shallow water than on land
+pub const DELTA_SPREAD: usize = 3;
+
+// precipitation of the driest cells of a rain shadow relative to the wettest
+const DRIEST_RAIN: f32 = 0.02;
+
+// frequency of the noise warping the coordinates the terrain is sampled at
+const WARP_FREQUENCY: f64 = 1.5;
+
+// the islands of an archipelago are centered within this distance of the middle
+const ARCHIPELAGO_SPREAD: f32 = 0.7;
+// open sea left between the shores of two islands
+const ARCHIPELAGO_GAP: f32 = 0.4;
+// attempts at placing each island away from the others before settling for the last one
+const ARCHIPELAGO_TRIES: usize = 30;
+
+impl Default for IslandShape {
+    fn default() -> Self {
+        IslandShape {
+            enabled: true,
+            strength: 1.,
+            radius: 0.5,
+            invert: false,
+            islands: 1,
+        }
+    }
+}
+
+impl IslandShape {
+    // islands of the given radius and strength, a single central one unless islands > 1
+    pub fn new(strength: f32, radius: f32, islands: usize) -> Self {
+        IslandShape {
+            strength: strength,
+            radius: radius,
+            islands: islands.max(1),
+            ..IslandShape::default()
+        }
+    }
+
+    // the single central island, or the islands scattered from seed, each a bit smaller
+    // or steeper than the others and apart enough for the sea to separate them
+    pub fn centers(&self, seed: u32) -> Vec<IslandCenter> {
+        if self.islands < 2 {
+            return vec![IslandCenter {
+                pos: Vec2::ZERO,
+                radius: self.radius,
+                strength: self.strength,
+            }];
+        }
+        let mut rng = StdRng::seed_from_u64(seed as u64);
+        // the islands share the area of the single one
+        let radius = self.radius / (self.islands as f32).sqrt();
+        let mut centers: Vec<IslandCenter> = Vec::with_capacity(self.islands);
+        for _ in 0..self.islands {
+            let mut center = IslandCenter {
+                pos: Vec2::ZERO,
+                radius: 0.,
+                strength: 0.,
+            };
+            for _ in 0..ARCHIPELAGO_TRIES {
+                center = IslandCenter {
+                    pos: Vec2::new(rng.gen::<f32>() * 2. - 1., rng.gen::<f32>() * 2. - 1.)
+                        * ARCHIPELAGO_SPREAD,
+                    radius: radius * (0.6 + 0.4 * rng.gen::<f32>()),
+                    strength: self.strength * (0.75 + 0.75 * rng.gen::<f32>()),
+                };
+                let apart = |other: &IslandCenter| {
+                    (center.pos - other.pos).length()
+                        >= center.radius + other.radius + ARCHIPELAGO_GAP
+                };
+                if centers.iter().all(apart) {
+                    break;
+                }
+            }
+            centers.push(center);
+        }
+        centers
+    }
+
+    // distance to the closest center shifted so its island's shore is at its radius,
+    // subtracted for islands and added for craters
+    fn falloff(&self, centers: &[IslandCenter], x: f32, y: f32) -> f32 {
+        if !self.enabled {
+            return 0.;
+        }
+        let sign = if self.invert { 1. } else { -1. };
+        // the highest of the islands' falloffs, or the deepest of the craters'
+        let distance = centers
+            .iter()
+            .map(|c| c.strength * ((Vec2::new(x, y) - c.pos).length() - c.radius))
+            .fold(f32::INFINITY, f32::min);
+        sign * distance
+    }
+}
+
+/// A grid of size x size heights, for analyses that don't need the rest of Elevation,
+/// cell i is at column i % size and row i / size.
+///
+/// The direction each cell faces, in degrees clockwise from north like `Elevation::aspect`:
+/// ```
+/// use terrain_gen::terrain::{Elevation, HeightField};
+///
+/// fn aspect(field: &impl HeightField) -> Vec<f32> {
+///     (0..field.size() * field.size())
+///         .map(|i| field.grad(i))
+///         .map(|g| (-g.x).atan2(g.y).to_degrees().rem_euclid(360.))
+///         .collect()
+/// }
+///
+/// // going down towards the east, the last column
+/// let size = 4;
+/// let plane = Elevation::from_data((0..size * size).map(|i| -((i % size) as f32)).collect(), size);
+/// assert!(aspect(&plane).iter().all(|a| (a - 90.).abs() < 1e-3));
+/// assert_eq!(aspect(&plane), plane.aspect());
+/// ```
+pub trait HeightField {
+    fn size(&self) -> usize;
+
+    // height of the cell at column x and row y
+    fn height(&self, x: usize, y: usize) -> f32;
+
+    // gradient at cell i
+    fn grad(&self, i: usize) -> Vec2;
+
+    // height interpolated between the cells around pos
+    fn height_bilinear(&self, pos: Vec2) -> f32;
+}
+
+impl HeightField for Elevation {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn height(&self, x: usize, y: usize) -> f32 {
+        self.data[x + y * self.size]
+    }
+
+    fn grad(&self, i: usize) -> Vec2 {
+        Elevation::grad(self, i)
+    }
+
+    fn height_bilinear(&self, pos: Vec2) -> f32 {
+        Elevation::height_bilinear(self, pos)
+    }
+}
+
+// summary of the heights of a terrain
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+pub struct TerrainStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    // fraction of the cells at or above the sea level
+    pub above_water_fraction: f32,
+}
+
+#[derive(Clone)]
+pub struct Elevation {
+    pub data: Vec<f32>,
+    // resistance of each cell to erosion in [0, 1], 1 doesn't erode at all
+    pub hardness: Vec<f32>,
+    // loose material deposited on top of the bedrock, included in data
+    pub sediment: Vec<f32>,
+    // mean distance in cells the sediment of each cell came from
+    pub transport: Vec<f32>,
+    // total height the droplets added or removed on each cell, only for display
+    pub erosion_accum: Vec<f32>,
+    // relative amount of rain falling on each cell
+    pub precipitation: Vec<f32>,
+    size: usize,
+    pub grid: Grid,
+    pub topology: Topology,
+    // changes made so far, and how many there were when each block of EDIT_BLOCK x EDIT_BLOCK
+    // cells was last changed
+    edits: u64,
+    block_edits: Vec<u64>,
+}
+
+// cells per side of the blocks the changes are tracked in, so the views only redraw those
+const EDIT_BLOCK: usize = 16;
+
+// number of blocks per side of a grid of size cells per side
+fn edit_blocks(size: usize) -> usize {
+    (size + EDIT_BLOCK - 1) / EDIT_BLOCK
+}
+
+impl Elevation {
+    // position of every cell mapped to [-1, 1]
+    fn coords(size: usize, grid: Grid) -> Vec<(f32, f32)> {
+        let sizef = size as f32;
+        match grid {
+            Grid::Square => (0..size * size)
+                .map(|i| coords(i, size))
+                .map(|(x, y)| (2. * (x as f32) / sizef - 1., 2. * (y as f32) / sizef - 1.))
+                .collect(),
+            // the hex grid is only sqrt(3)/2 as tall as it is wide, we center it vertically
+            Grid::Hex => (0..size * size)
+                .map(|i| grid.center(i, size))
+                .map(|p| (2. * p.x / sizef - 1., 2. * p.y / sizef - SQRT3_2))
+                .collect(),
+        }
+    }
+
+    // terrain sampled from the noise, seed places the islands of an archipelago
+    // and the noise warping the coordinates
+    pub fn new(config: &TerrainConfig, noise: &dyn TerrainNoise, seed: u32) -> Self {
+        check_layout(config.grid, config.topology);
+        let centers = config.island.centers(seed);
+        let (warp_x, warp_y) = (
+            Perlin::new().set_seed(seed.wrapping_add(1)),
+            Perlin::new().set_seed(seed.wrapping_add(2)),
+        );
+        // the coordinates pushed around by the warp noise so the shapes and coasts get swirly
+        let warp = |x: f32, y: f32| {
+            if config.warp_strength == 0. {
+                return (x as f64, y as f64);
+            }
+            let p = [x as f64 * WARP_FREQUENCY, y as f64 * WARP_FREQUENCY];
+            let strength = config.warp_strength as f64;
+            (
+                x as f64 + strength * warp_x.get(p),
+                y as f64 + strength * warp_y.get(p),
+            )
+        };
+        let sample = |x: f32, y: f32| match config.topology {
+            // the falloff is left unwarped so the islands keep their place
+            Topology::Clamped => {
+                let (wx, wy) = warp(x, y);
+                NoiseFn::<[f64; 2]>::get(noise, [wx, wy]) as f32
+                    + config.island.falloff(&centers, x, y)
+            }
+            // each axis is a circle of circumference 2 in 4D so the noise tiles,
+            // there is no island falloff or warp since they would show the seams
+            Topology::Toroidal => {
+                let (a, b) = (PI * (x as f64 + 1.), PI * (y as f64 + 1.));
+                let r = 1. / PI;
+                NoiseFn::<[f64; 4]>::get(
+                    noise,
+                    [r * a.cos(), r * a.sin(), r * b.cos(), r * b.sin()],
+                ) as f32
+            }
+        };
+        Elevation {
+            data: Elevation::coords(config.size, config.grid)
+                .into_iter()
+                .map(|(x, y)| sample(x, y))
+                //.map(|(x, y)| (x as f32 / sizef) * (y as f32 / sizef).max(0.5))
+                .collect(),
+            hardness: vec![0.; config.size * config.size],
+            sediment: vec![0.; config.size * config.size],
+            transport: vec![0.; config.size * config.size],
+            erosion_accum: vec![0.; config.size * config.size],
+            precipitation: vec![1.; config.size * config.size],
+            size: config.size,
+            grid: config.grid,
+            topology: config.topology,
+            edits: 0,
+            block_edits: vec![0; edit_blocks(config.size).pow(2)],
+        }
+    }
+
+    // square grid of size x size heights in row-major order
+    // the terrain laid out on another grid or topology, the cells are kept as they are
+    pub fn with_layout(mut self, grid: Grid, topology: Topology) -> Self {
+        check_layout(grid, topology);
+        self.grid = grid;
+        self.topology = topology;
+        self
+    }
+
+    pub fn from_data(data: Vec<f32>, size: usize) -> Self {
+        assert_eq!(data.len(), size * size);
+        Elevation {
+            data: data,
+            hardness: vec![0.; size * size],
+            sediment: vec![0.; size * size],
+            transport: vec![0.; size * size],
+            erosion_accum: vec![0.; size * size],
+            precipitation: vec![1.; size * size],
+            size: size,
+            grid: Grid::Square,
+            topology: Topology::Clamped,
+            edits: 0,
+            block_edits: vec![0; edit_blocks(size).pow(2)],
+        }
+    }
+
+    // heightmap from a grayscale image resized to size x size, black is -1 and white is 1
+    pub fn from_image(path: &Path, size: usize) -> image::ImageResult<Self> {
+        let img = image::open(path)?.to_luma16();
+        let img = imageops::resize(&img, size as u32, size as u32, FilterType::Triangle);
+        Ok(Elevation {
+            data: img
+                .pixels()
+                .map(|p| 2. * p.0[0] as f32 / u16::MAX as f32 - 1.)
+                .collect(),
+            hardness: vec![0.; size * size],
+            sediment: vec![0.; size * size],
+            transport: vec![0.; size * size],
+            erosion_accum: vec![0.; size * size],
+            precipitation: vec![1.; size * size],
+            size: size,
+            grid: Grid::Square,
+            topology: Topology::Clamped,
+            edits: 0,
+            block_edits: vec![0; edit_blocks(size).pow(2)],
+        })
+    }
+
+    // shapes the terrain into an island (or a crater) like the noise generation does
+    pub fn apply_falloff(&mut self, island: &IslandShape, seed: u32) {
+        let centers = island.centers(seed);
+        for (h, (x, y)) in self
+            .data
+            .iter_mut()
+            .zip(Elevation::coords(self.size, self.grid))
+        {
+            *h += island.falloff(&centers, x, y);
+        }
+    }
+
+    // flattens the land within width cells of the shore towards the sea level into beaches,
+    // less and less going inland so the slope stays continuous where the pass stops
+    pub fn generate_beaches(&mut self, width: f32, sea_level: f32) {
+        if width <= 0. {
+            return;
+        }
+        let land = |h: f32| h >= sea_level;
+        // distance of the land cells to the closest coast cell, the land next to the water
+        let mut distance = vec![f32::INFINITY; self.data.len()];
+        let mut queue = VecDeque::new();
+        for i in 0..self.data.len() {
+            let coast = land(self.data[i])
+                && self
+                    .neighbors(i)
+                    .into_iter()
+                    .any(|(n, _)| !land(self.data[n]));
+            if coast {
+                distance[i] = 0.;
+                queue.push_back(i);
+            }
+        }
+        while let Some(i) = queue.pop_front() {
+            for (n, d) in self.neighbors(i) {
+                let through = distance[i] + d;
+                if land(self.data[n]) && through < width && through < distance[n] {
+                    distance[n] = through;
+                    queue.push_back(n);
+                }
+            }
+        }
+        for (i, d) in distance.into_iter().enumerate() {
+            if d < width {
+                let t = d / width;
+                let keep = t * t * (3. - 2. * t);
+                let h = self.data[i];
+                self.change(i, (sea_level - h) * (1. - keep));
+            }
+        }
+    }
+
+    // pos brought back inside the grid on a torus, unchanged otherwise
+    pub fn wrap(&self, pos: Vec2) -> Vec2 {
+        match self.topology {
+            Topology::Clamped => pos,
+            Topology::Toroidal => {
+                let size = self.size as f32;
+                Vec2::new(pos.x.rem_euclid(size), pos.y.rem_euclid(size))
+            }
+        }
+    }
+
+    // cell at column x and row y, wrapped on a torus and None outside the grid otherwise
+    fn cell(&self, x: i32, y: i32) -> Option<usize> {
+        let size = self.size as i32;
+        match self.topology {
+            Topology::Toroidal => Some((x.rem_euclid(size) + y.rem_euclid(size) * size) as usize),
+            Topology::Clamped if x >= 0 && x < size && y >= 0 && y < size => {
+                Some((x + y * size) as usize)
+            }
+            Topology::Clamped => None,
+        }
+    }
+
+    // layers of harder and softer rock from a low frequency noise,
+    // strength is the hardness of the hardest rock
+    pub fn set_hardness(&mut self, seed: u32, strength: f32) {
+        let noise = Perlin::new().set_seed(seed);
+        self.hardness = Elevation::coords(self.size, self.grid)
+            .into_iter()
+            .map(|(x, y)| noise.get([2. * x as f64, 2. * y as f64]) as f32)
+            .map(|n| ((n + 1.) / 2. * strength).clamp(0., 1.))
+            .collect();
+    }
+
+    // wetter and drier regions from a low frequency noise, the driest cells get
+    // 1 - strength of the rain of the wettest ones
+    pub fn set_precipitation(&mut self, seed: u32, strength: f32) {
+        let strength = strength.clamp(0., 1.);
+        let noise = Perlin::new().set_seed(seed);
+        self.precipitation = Elevation::coords(self.size, self.grid)
+            .into_iter()
+            .map(|(x, y)| noise.get([1.5 * x as f64, 1.5 * y as f64]) as f32)
+            .map(|n| 1. - strength + strength * ((n + 1.) / 2.).clamp(0., 1.))
+            .collect();
+    }
+
+    // scales the precipitation by the rain a wind blowing towards azimuth (in degrees
+    // clockwise from north) leaves on each cell, the driest get DRIEST_RAIN of the wettest
+    pub fn set_rain_shadow(&mut self, azimuth: f32) {
+        let a = azimuth.to_radians();
+        let rain = rain_shadow(self, Vec2::new(a.sin(), -a.cos()), 1.);
+        let wettest = rain.iter().cloned().fold(0., f32::max);
+        if wettest <= 0. {
+            return;
+        }
+        for (p, r) in self.precipitation.iter_mut().zip(rain) {
+            *p *= (r / wettest).max(DRIEST_RAIN);
+        }
+    }
+
+    // height of the rock under the sediment of cell i
+    pub fn bedrock(&self, i: usize) -> f32 {
+        self.data[i] - self.sediment[i]
+    }
+
+    // added material raises the bedrock, removed material is taken from the sediment
+    // before the bedrock
+    fn change(&mut self, i: usize, v: f32) {
+        if v == 0. {
+            return;
+        }
+        self.data[i] += v;
+        self.sediment[i] = (self.sediment[i] + v.min(0.)).max(0.);
+        self.mark(i);
+    }
+
+    // like change but the added material is loose sediment
+    fn settle(&mut self, i: usize, v: f32) {
+        if v == 0. {
+            return;
+        }
+        self.data[i] += v;
+        self.sediment[i] = (self.sediment[i] + v).max(0.);
+        self.mark(i);
+    }
+
+    // puts back the height and sediment of cell i
+    pub fn set(&mut self, i: usize, height: f32, sediment: f32) {
+        self.data[i] = height;
+        self.sediment[i] = sediment;
+        self.mark(i);
+    }
+
+    // counts a change of cell i
+    fn mark(&mut self, i: usize) {
+        let (x, y) = coords(i, self.size);
+        self.edits += 1;
+        self.block_edits[x / EDIT_BLOCK + y / EDIT_BLOCK * edit_blocks(self.size)] = self.edits;
+    }
+
+    // number of changes made to the terrain so far
+    pub fn edits(&self) -> u64 {
+        self.edits
+    }
+
+    // whether a cell from column x0 and row y0 to column x1 and row y1 excluded may have
+    // changed after the given number of changes, the cells are checked by blocks
+    pub fn edited_since(&self, x0: usize, y0: usize, x1: usize, y1: usize, edits: u64) -> bool {
+        let blocks = edit_blocks(self.size);
+        let span = |c0: usize, c1: usize| c0 / EDIT_BLOCK..(c1 + EDIT_BLOCK - 1) / EDIT_BLOCK;
+        iproduct!(span(y0, y1), span(x0, x1))
+            .any(|(by, bx)| self.block_edits[bx + by * blocks] > edits)
+    }
+
+    // a change made by a droplet, counted in erosion_accum
+    fn droplet_change(&mut self, i: usize, v: f32) {
+        self.settle(i, v);
+        self.erosion_accum[i] += v.abs();
+    }
+
+    // index of the cell containing pos, clamped to the grid
+    pub fn index(&self, pos: Vec2) -> usize {
+        match self.grid {
+            Grid::Square => unroll(self.wrap(pos), self.size),
+            Grid::Hex => {
+                let size = self.size as i32;
+                // the closest center is on one of the 2 rows around pos
+                let row = (pos.y / SQRT3_2).floor() as i32;
+                let cell = |r: i32| {
+                    let r = r.clamp(0, size - 1);
+                    let c = ((pos.x - 0.5 * (r & 1) as f32).round() as i32).clamp(0, size - 1);
+                    (c, r)
+                };
+                let dist = |(c, r): (i32, i32)| (hex_center(c, r) - pos).length_squared();
+                let (below, above) = (cell(row), cell(row + 1));
+                let (col, row) = if dist(above) < dist(below) {
+                    above
+                } else {
+                    below
+                };
+                (col + row * size) as usize
+            }
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn stats(&self, sea_level: f32) -> TerrainStats {
+        let n = self.data.len().max(1) as f32;
+        TerrainStats {
+            min: self.data.iter().copied().fold(f32::INFINITY, f32::min),
+            max: self.data.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+            mean: (self.data.iter().map(|h| *h as f64).sum::<f64>() / n as f64) as f32,
+            above_water_fraction: self.data.iter().filter(|h| **h >= sea_level).count() as f32 / n,
+        }
+    }
+
+    // the heights mapped to [0, 1], a flat terrain is all 0
+    pub fn normalized(&self) -> Vec<f32> {
+        let stats = self.stats(0.);
+        let range = stats.max - stats.min;
+        if range <= 0. {
+            return vec![0.; self.data.len()];
+        }
+        self.data.iter().map(|h| (h - stats.min) / range).collect()
+    }
+
+    // every neighbor of cell i with the distance to it
+    pub fn neighbors(&self, i: usize) -> Vec<(usize, f32)> {
+        match self.grid {
+            Grid::Square => {
+                let size = self.size as i32;
+                let (x, y) = (i as i32 % size, i as i32 / size);
+                iproduct!(-1i32..=1, -1i32..=1)
+                    .filter(|&(dx, dy)| dx != 0 || dy != 0)
+                    .filter_map(|(dx, dy)| {
+                        self.cell(x + dx, y + dy)
+                            .map(|n| (n, ((dx * dx + dy * dy) as f32).sqrt()))
+                    })
+                    .collect()
+            }
+            Grid::Hex => self.hex_neighbors(i).map(|(n, _)| (n, 1.)).collect(),
+        }
+    }
+
+    // world position of the center of cell i
+    pub fn center(&self, i: usize) -> Vec2 {
+        self.grid.center(i, self.size)
+    }
+
+    // the neighbors of a hex cell with the unit direction towards them
+    fn hex_neighbors(&self, i: usize) -> impl Iterator<Item = (usize, Vec2)> {
+        let size = self.size as i32;
+        let (col, row) = (i as i32 % size, i as i32 / size);
+        let shift = row & 1;
+        IntoIterator::into_iter([
+            (1, 0, Vec2::new(1., 0.)),
+            (-1, 0, Vec2::new(-1., 0.)),
+            (shift, -1, Vec2::new(0.5, -SQRT3_2)),
+            (shift - 1, -1, Vec2::new(-0.5, -SQRT3_2)),
+            (shift, 1, Vec2::new(0.5, SQRT3_2)),
+            (shift - 1, 1, Vec2::new(-0.5, SQRT3_2)),
+        ])
+        .map(move |(dc, dr, dir)| (col + dc, row + dr, dir))
+        .filter(move |(c, r, _)| *c >= 0 && *c < size && *r >= 0 && *r < size)
+        .map(move |(c, r, dir)| ((c + r * size) as usize, dir))
+    }
+
+    // gradient at cell i, used for rendering
+    // the droplet simulation samples the smooth grad_bilinear instead
+    pub fn grad(&self, i: usize) -> Vec2 {
+        match self.grid {
+            Grid::Square => self.grad_square(i),
+            // least squares fit of a plane on the 6 neighbors
+            Grid::Hex => {
+                self.hex_neighbors(i)
+                    .map(|(n, dir)| dir * (self.data[n] - self.data[i]))
+                    .fold(Vec2::ZERO, |acc, v| acc + v)
+                    / 3.
+            }
+        }
+    }
+
+    // central differences, one-sided on the borders unless they wrap around
+    fn grad_square(&self, i: usize) -> Vec2 {
+        let size = self.size;
+        let (x, y) = coords(i, size);
+        // the cells before and after c along an axis and the distance between them
+        let around = |c: usize| match self.topology {
+            Topology::Toroidal => ((c + size - 1) % size, (c + 1) % size, 2.),
+            Topology::Clamped => {
+                let (before, after) = (c.saturating_sub(1), (c + 1).min(size - 1));
+                (before, after, (after - before) as f32)
+            }
+        };
+        let h = |x: usize, y: usize| self.data[x + y * size];
+        let (x0, x1, dx) = around(x);
+        let (y0, y1, dy) = around(y);
+        Vec2::new((h(x1, y) - h(x0, y)) / dx, (h(x, y1) - h(x, y0)) / dy)
+    }
+
+    // compass direction each cell faces, the way down, in degrees clockwise from north
+    // (towards the first row), 0 on flat ground
+    pub fn aspect(&self) -> Vec<f32> {
+        (0..self.data.len())
+            .map(|i| self.grad(i))
+            .map(|g| {
+                if g.length() > 0. {
+                    (-g.x).atan2(g.y).to_degrees().rem_euclid(360.)
+                } else {
+                    0.
+                }
+            })
+            .collect()
+    }
+
+    // laplacian of the heights, positive in valleys and pits and negative on ridges and peaks
+    pub fn curvature(&self) -> Vec<f32> {
+        (0..self.data.len())
+            .map(|i| match self.grid {
+                Grid::Square => self.curvature_square(i),
+                // the 6 neighbors at distance 1, the ones missing at the border are left out
+                Grid::Hex => {
+                    let diffs: Vec<f32> = self
+                        .hex_neighbors(i)
+                        .map(|(n, _)| self.data[n] - self.data[i])
+                        .collect();
+                    4. * diffs.iter().sum::<f32>() / diffs.len().max(1) as f32
+                }
+            })
+            .collect()
+    }
+
+    fn curvature_square(&self, i: usize) -> f32 {
+        let size = self.size;
+        let (x, y) = coords(i, size);
+        // three cells along an axis around c whose second difference is taken, shifted
+        // inwards at the borders for a one-sided stencil
+        let around = |c: usize| match self.topology {
+            Topology::Toroidal => ((c + size - 1) % size, c, (c + 1) % size),
+            Topology::Clamped if size < 3 => (c, c, c),
+            Topology::Clamped => {
+                let mid = c.clamp(1, size - 2);
+                (mid - 1, mid, mid + 1)
+            }
+        };
+        let h = |x: usize, y: usize| self.data[x + y * size];
+        let (x0, x1, x2) = around(x);
+        let (y0, y1, y2) = around(y);
+        (h(x0, y) - 2. * h(x1, y) + h(x2, y)) + (h(x, y0) - 2. * h(x, y1) + h(x, y2))
+    }
+
+    // 3x3 Sobel gradient, smoother and less biased towards the axes than grad,
+    // the border cells reuse their closest samples
+    pub fn grad_sobel(&self, i: usize) -> Vec2 {
+        if self.grid == Grid::Hex {
+            return self.grad(i);
+        }
+        let size = self.size as i32;
+        let (x, y) = coords(i, self.size);
+        let h = |dx: i32, dy: i32| {
+            let (cx, cy) = (x as i32 + dx, y as i32 + dy);
+            let i = self
+                .cell(cx, cy)
+                .unwrap_or_else(|| (cx.clamp(0, size - 1) + cy.clamp(0, size - 1) * size) as usize);
+            self.data[i]
+        };
+        Vec2::new(
+            (h(1, -1) + 2. * h(1, 0) + h(1, 1)) - (h(-1, -1) + 2. * h(-1, 0) + h(-1, 1)),
+            (h(-1, 1) + 2. * h(0, 1) + h(1, 1)) - (h(-1, -1) + 2. * h(0, -1) + h(1, -1)),
+        ) / 8.
+    }
+
+    // the 4 cells around pos and the fractional position of pos between them
+    fn bilinear_cell(&self, pos: Vec2) -> ([usize; 4], Vec2) {
+        let size = self.size;
+        match self.topology {
+            Topology::Clamped => {
+                let max = (size - 2) as f32;
+                let corner = Vec2::new(pos.x.floor().clamp(0., max), pos.y.floor().clamp(0., max));
+                let frac = (pos - corner).max(Vec2::ZERO).min(Vec2::ONE);
+                let i = corner.x as usize + corner.y as usize * size;
+                ([i, i + 1, i + size, i + 1 + size], frac)
+            }
+            Topology::Toroidal => {
+                let pos = self.wrap(pos);
+                let (x, y) = (
+                    (pos.x as usize).min(size - 1),
+                    (pos.y as usize).min(size - 1),
+                );
+                let frac = (pos - Vec2::new(x as f32, y as f32))
+                    .max(Vec2::ZERO)
+                    .min(Vec2::ONE);
+                let (x1, y1) = ((x + 1) % size, (y + 1) % size);
+                (
+                    [x + y * size, x1 + y * size, x + y1 * size, x1 + y1 * size],
+                    frac,
+                )
+            }
+        }
+    }
+
+    // height at pos interpolated between the 4 surrounding cells
+    pub fn height_bilinear(&self, pos: Vec2) -> f32 {
+        match self.grid {
+            Grid::Square => self.bilinear(&self.data, pos),
+            Grid::Hex => self.data[self.index(pos)],
+        }
+    }
+
+    // value of a per cell layer interpolated between the 4 cells around pos
+    fn bilinear(&self, layer: &[f32], pos: Vec2) -> f32 {
+        let ([i00, i10, i01, i11], f) = self.bilinear_cell(pos);
+        let (v00, v10) = (layer[i00], layer[i10]);
+        let (v01, v11) = (layer[i01], layer[i11]);
+        (v00 * (1. - f.x) + v10 * f.x) * (1. - f.y) + (v01 * (1. - f.x) + v11 * f.x) * f.y
+    }
+
+    // the terrain interpolated to new_size x new_size cells, up or down, the other layers
+    // are resampled along so they still line up with the heights
+    pub fn resample(&self, new_size: usize) -> Elevation {
+        let new_size = new_size.max(2);
+        let scale = self.size as f32 / new_size as f32;
+        // the cells of both grids cover the same square, so their centers are mapped
+        let positions: Vec<Vec2> = (0..new_size * new_size)
+            .map(|i| coords(i, new_size))
+            .map(|(x, y)| (Vec2::new(x as f32, y as f32) + Vec2::splat(0.5)) * scale)
+            .map(|p| p - Vec2::splat(0.5))
+            .collect();
+        let resample = |layer: &[f32]| -> Vec<f32> {
+            positions.iter().map(|p| self.bilinear(layer, *p)).collect()
+        };
+        Elevation {
+            data: resample(&self.data),
+            hardness: resample(&self.hardness),
+            sediment: resample(&self.sediment),
+            transport: resample(&self.transport),
+            erosion_accum: resample(&self.erosion_accum),
+            precipitation: resample(&self.precipitation),
+            size: new_size,
+            grid: self.grid,
+            topology: self.topology,
+            edits: 0,
+            block_edits: vec![0; edit_blocks(new_size).pow(2)],
+        }
+    }
+
+    // gradient of the bilinear interpolation at pos
+    pub fn grad_bilinear(&self, pos: Vec2) -> Vec2 {
+        match self.grid {
+            Grid::Square => {
+                let ([i00, i10, i01, i11], f) = self.bilinear_cell(pos);
+                let (h00, h10) = (self.data[i00], self.data[i10]);
+                let (h01, h11) = (self.data[i01], self.data[i11]);
+                Vec2::new(
+                    (h10 - h00) * (1. - f.y) + (h11 - h01) * f.y,
+                    (h01 - h00) * (1. - f.x) + (h11 - h10) * f.x,
+                )
+            }
+            Grid::Hex => self.grad(self.index(pos)),
+        }
+    }
+
+    // heights sampled every spacing cells along the segment from a to b, both ends included,
+    // a single sample when they're the same point
+    pub fn profile(&self, a: Vec2, b: Vec2, spacing: f32) -> Vec<f32> {
+        let samples = ((b - a).length() / spacing.max(f32::EPSILON)).ceil() as usize + 1;
+        if samples < 2 {
+            return vec![self.height_bilinear(a)];
+        }
+        (0..samples)
+            .map(|k| a + (b - a) * (k as f32 / (samples - 1) as f32))
+            .map(|p| self.height_bilinear(p))
+            .collect()
+    }
+
+    // moves material from cells steeper than the talus angle down to their neighbors
+    pub fn thermal_step(&mut self) {
+        let mut delta = vec![0.; self.data.len()];
+        for i in 0..self.data.len() {
+            let neighbors = self.neighbors(i);
+            let share = THERMAL_RATE / (2. * neighbors.len() as f32);
+            for (n, dist) in neighbors {
+                let excess = self.data[i] - self.data[n] - TALUS_ANGLE * dist;
+                if excess > 0. {
+                    delta[i] -= excess * share;
+                    delta[n] += excess * share;
+                }
+            }
+        }
+        for (i, d) in delta.into_iter().enumerate() {
+            self.change(i, d);
+        }
+    }
+
+    // separable gaussian blur, the weights falling outside a clamped grid are left out
+    // so the borders aren't pulled towards anything
+    pub fn smooth(&mut self, sigma: f32, iterations: u32) {
+        if sigma <= 0. {
+            return;
+        }
+        let r = (3. * sigma).ceil() as i32;
+        let weights: Vec<(i32, f32)> = (-r..=r)
+            .map(|d| (d, (-(d * d) as f32 / (2. * sigma * sigma)).exp()))
+            .collect();
+        let size = self.size as i32;
+        let mut data = self.data.clone();
+        for _ in 0..iterations {
+            for &(dx, dy) in [(1, 0), (0, 1)].iter() {
+                data = (0..data.len())
+                    .map(|i| {
+                        let (x, y) = (i as i32 % size, i as i32 / size);
+                        let (sum, total) = weights
+                            .iter()
+                            .filter_map(|(d, w)| self.cell(x + d * dx, y + d * dy).map(|n| (n, w)))
+                            .fold((0., 0.), |(sum, total), (n, w)| {
+                                (sum + data[n] * w, total + w)
+                            });
+                        sum / total
+                    })
+                    .collect();
+            }
+        }
+        for (i, h) in data.into_iter().enumerate() {
+            let d = h - self.data[i];
+            self.change(i, d);
+        }
+    }
+
+    // pulls the land towards the closest of `levels` heights evenly spaced between the sea
+    // and the highest point, strength 1 gives flat steps and 0 leaves the terrain as it is
+    pub fn terrace(&mut self, levels: u32, strength: f32, sea_level: f32) {
+        let top = self.data.iter().copied().fold(sea_level, f32::max);
+        if levels < 2 || top <= sea_level {
+            return;
+        }
+        let step = (top - sea_level) / (levels - 1) as f32;
+        let strength = strength.clamp(0., 1.);
+        for i in 0..self.data.len() {
+            let h = self.data[i];
+            if h < sea_level {
+                continue;
+            }
+            let band = sea_level + ((h - sea_level) / step).round() * step;
+            self.change(i, (band - h) * strength);
+        }
+    }
+
+    // the wind blows the top of the dry cells downwind, more of it on the slopes facing
+    // the wind so it piles up in ripples across the wind, the material is only moved around
+    pub fn wind_step(&mut self, wind: Vec2, dry: &[bool]) {
+        let wind = normalize_or_zero(wind);
+        if wind == Vec2::ZERO {
+            return;
+        }
+        let extent = self.grid.extent(self.size);
+        let inside = |p: Vec2| p.x >= 0. && p.y >= 0. && p.x < extent.x && p.y < extent.y;
+        let across = Vec2::new(-wind.y, wind.x);
+        let mut delta = vec![0.; self.data.len()];
+        for i in 0..self.data.len() {
+            if !dry[i] {
+                continue;
+            }
+            let pos = self.center(i);
+            let target = self.wrap(pos + wind * WIND_HOP);
+            if !inside(target) {
+                continue;
+            }
+            let rise = self.data[i] - self.height_bilinear(self.wrap(pos - wind));
+            let lifted = WIND_RATE * (1. + WINDWARD_BOOST * rise).max(0.);
+            delta[i] -= lifted;
+            // the share of the sides falling outside the grid lands on the target
+            let mut landed = lifted;
+            for side in [target - across, target + across].iter() {
+                let side = self.wrap(*side);
+                if inside(side) {
+                    delta[self.index(side)] += lifted * WIND_SPREAD;
+                    landed -= lifted * WIND_SPREAD;
+                }
+            }
+            delta[self.index(target)] += landed;
+        }
+        for (i, d) in delta.into_iter().enumerate() {
+            self.settle(i, d);
+        }
+    }
+
+    // the ice above altitude creeps down the steepest slope, carving rate * slope over a wide
+    // kernel from every cell it covers, and drops what it carried as a moraine where it melts
+    pub fn glacier_step(&mut self, altitude: f32, rate: f32) {
+        let kernel = Kernel::new(GLACIER_RADIUS);
+        // from the top down so every cell has received the ice of the cells above it
+        let mut ice: Vec<usize> = (0..self.data.len())
+            .filter(|i| self.data[*i] > altitude)
+            .collect();
+        ice.sort_by(|a, b| self.data[*b].total_cmp(&self.data[*a]));
+        let mut load = vec![0.; self.data.len()];
+        let mut carved = Vec::new();
+        let mut moraines = Vec::new();
+        for i in ice {
+            let steepest = self
+                .neighbors(i)
+                .into_iter()
+                .map(|(n, dist)| (n, (self.data[i] - self.data[n]) / dist))
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+            match steepest {
+                Some((n, slope)) if slope > 0. => {
+                    let amount = rate * slope * (1. - self.hardness[i]);
+                    carved.push((self.center(i), amount));
+                    load[n] += load[i] + amount;
+                }
+                // the ice stuck in a hollow melts there
+                _ => moraines.push(i),
+            }
+        }
+        moraines.extend((0..self.data.len()).filter(|i| self.data[*i] <= altitude));
+        let moraines: Vec<(Vec2, f32)> = moraines
+            .into_iter()
+            .filter(|i| load[*i] > 0.)
+            .map(|i| (self.center(i), load[i]))
+            .collect();
+        for (pos, amount) in carved {
+            self.add(pos, -amount, &kernel);
+        }
+        for (pos, amount) in moraines {
+            self.spread(pos, amount, &kernel, Self::settle);
+        }
+    }
+
+    // height a deposit at pos can add before its cell rises above the lowest of the neighbors
+    // higher than it, so pits are filled up to their rim and not into spikes, None when no
+    // neighbor is higher and there's no rim to stop at
+    pub fn fill_room(&self, pos: Vec2) -> Option<f32> {
+        let i = self.index(pos);
+        self.neighbors(i)
+            .into_iter()
+            .map(|(n, _)| self.data[n] - self.data[i])
+            .filter(|rise| *rise > 0.)
+            .reduce(f32::min)
+    }
+
+    // whether a deposit at pos falls in the shallow water along the coast, where it's spread
+    // wide so the river mouths build up fans instead of a single mound
+    pub fn in_delta(&self, pos: Vec2, sea_level: f32) -> bool {
+        let h = self.data[self.index(pos)];
+        h < sea_level && h > sea_level - DELTA_DEPTH
+    }
+
+    // mixes the distance traveled by a deposit of v at pos into the sediment of its cell,
+    // called before the deposit is added
+    pub fn record_transport(&mut self, pos: Vec2, v: f32, distance: f32) {
+        let i = self.index(pos);
+        let total = self.sediment[i] + v;
+        if v > 0. && total > 0. {
+            self.transport[i] = (self.transport[i] * self.sediment[i] + distance * v) / total;
+        }
+    }
+
+    // raises or lowers the bedrock around pos, for the edits that don't come from the droplets
+    pub fn add(&mut self, pos: Vec2, v: f32, kernel: &Kernel) {
+        self.spread(pos, v, kernel, Self::change);
+    }
+
+    // the sediment a droplet drops around pos, or picks up when v is negative
+    pub fn deposit(&mut self, pos: Vec2, v: f32, kernel: &Kernel) {
+        self.spread(pos, v, kernel, Self::droplet_change);
+    }
+
+    // applies v around pos with the weights of the kernel
+    fn spread(&mut self, pos: Vec2, v: f32, kernel: &Kernel, apply: fn(&mut Self, usize, f32)) {
+        match self.grid {
+            Grid::Square => self.spread_square(pos, v, kernel, apply),
+            // hex cells always spread over their direct neighbors
+            Grid::Hex => self.spread_hex(pos, v, apply),
+        }
+    }
+
+    fn spread_hex(&mut self, pos: Vec2, v: f32, apply: fn(&mut Self, usize, f32)) {
+        let i = self.index(pos);
+        let neighbors: Vec<usize> = self.hex_neighbors(i).map(|(n, _)| n).collect();
+        for n in neighbors.iter() {
+            apply(self, *n, v * 0.1);
+        }
+        // the share of missing neighbors at the border stays in the center
+        apply(self, i, v * (1. - 0.1 * neighbors.len() as f32));
+    }
+
+    fn spread_square(
+        &mut self,
+        pos: Vec2,
+        v: f32,
+        kernel: &Kernel,
+        apply: fn(&mut Self, usize, f32),
+    ) {
+        let size = self.size as f32;
+        let toroidal = self.topology == Topology::Toroidal;
+        let inside = |p: Vec2| toroidal || (p.x >= 0. && p.y >= 0. && p.x < size && p.y < size);
+        // the weights falling outside the grid are given to the cells inside
+        // so the whole amount is added, instead of piling up on the border cells
+        let total: f32 = kernel
+            .weights
+            .iter()
+            .filter(|(delta, _)| inside(pos + *delta))
+            .map(|(_, w)| w)
+            .sum();
+        if total <= 0. {
+            let i = unroll(self.wrap(pos), self.size);
+            apply(self, i, v);
+            return;
+        }
+        for (delta, w) in kernel.weights.iter() {
+            if inside(pos + *delta) {
+                let i = unroll(self.wrap(pos + *delta), self.size);
+                apply(self, i, v * w / total);
+            }
+        }
+    }
+}
+
+// on flat ground with no speed there is no direction to go, the droplet stays put
+// instead of getting a NaN position
+fn normalize_or_zero(v: Vec2) -> Vec2 {
+    let length = v.length();
+    if length > f32::EPSILON {
+        v / length
+    } else {
+        Vec2::ZERO
+    }
+}
+
+// moves the droplet one step downhill, returning the sediment to add to the terrain where it was
+pub fn step(
+    droplet: &mut Droplet,
+    elevation: &Elevation,
+    params: &ErosionParams,
+    meander: &Meander,
+    sea_level: f32,
+) -> Option<(Vec2, f32)> {
+    let g = elevation.grad_bilinear(droplet.pos);
+    droplet.dir = normalize_or_zero(
+        droplet.dir * params.inertia * droplet.vel - g * (1. - params.inertia * droplet.vel),
+    );
+    if meander.amplitude > 0. {
+        droplet.dir =
+            normalize_or_zero(droplet.dir + meander.offset(droplet.pos, droplet.dir, droplet.vel));
+    }
+    let old_pos: Vec2 = droplet.pos;
+    // on a torus droplets leaving an edge come back from the opposite one
+    droplet.pos = elevation.wrap(droplet.pos + droplet.dir);
+    let h = elevation.height_bilinear(droplet.pos);
+    // if we're below water level we immediatly deposit all sediment and water
+    let hdif = elevation.height_bilinear(old_pos) - h;
+    let cdif = f32::max(hdif, params.min_slope) * droplet.vel * droplet.water * params.capacity
+        - droplet.sediment;
+    let mut delta = None;
+    if cdif < 0. {
+        // we deposit sediment, what would overfill the cell stays on the droplet
+        let deposit = -cdif * params.deposition;
+        let deposit = elevation
+            .fill_room(old_pos)
+            .map_or(deposit, |room| deposit.min(room));
+        droplet.sediment = droplet.sediment - deposit;
+        delta = Some((old_pos, deposit));
+    } else if h >= sea_level {
+        // we draw sediment if we're above water
+        let i = elevation.index(old_pos);
+        let erosion = f32::min(cdif * params.erosion, hdif);
+        // loose sediment goes first, the hardness only slows down the bedrock underneath
+        let loose = elevation.sediment[i];
+        let erosion = erosion.min(loose) + (erosion - loose).max(0.) * (1. - elevation.hardness[i]);
+        droplet.sediment = droplet.sediment + erosion;
+        if erosion > droplet.pickup {
+            droplet.origin = old_pos;
+            droplet.pickup = erosion;
+        }
+        delta = Some((old_pos, -erosion));
+    }
+    droplet.vel = (droplet.vel.powi(2) + hdif)
+        .max(0.)
+        .sqrt()
+        .min(params.max_velocity);
+    // faster than 1 the evaporation term would add water
+    droplet.water = (droplet.water * (1. - params.evaporation * (1. - droplet.vel)))
+        .max(0.)
+        .min(params.max_water);
+    droplet.steps += 1;
+    delta
+}
+
+// steps every droplet on the terrain as it is, returning their changes in the order of the droplets
+pub fn step_sequential<D: DerefMut<Target = Droplet>>(
+    droplets: &mut [D],
+    elevation: &Elevation,
+    params: &ErosionParams,
+    meander: &Meander,
+    sea_level: f32,
+) -> Vec<Option<(Vec2, f32)>> {
+    droplets
+        .iter_mut()
+        .map(|droplet| step(droplet, elevation, params, meander, sea_level))
+        .collect()
+}
+
+// step_sequential spread over the threads, the changes come out in the same order
+// so applying them doesn't depend on the threads
+pub fn step_parallel<D: DerefMut<Target = Droplet> + Send>(
+    droplets: &mut [D],
+    elevation: &Elevation,
+    params: &ErosionParams,
+    meander: &Meander,
+    sea_level: f32,
+) -> Vec<Option<(Vec2, f32)>> {
+    droplets
+        .par_iter_mut()
+        .map(|droplet| step(droplet, elevation, params, meander, sea_level))
+        .collect()
+}
+
+// runs droplets one after the other on the terrain until each one dries up or leaves it,
+// the same way the simulation does but without the frame by frame scheduling
+pub fn erode(
+    elevation: &mut Elevation,
+    droplets: usize,
+    params: &ErosionParams,
+    sea_level: f32,
+    rng: &mut impl Rng,
+) {
+    erode_batch(elevation, droplets, params, sea_level, rng);
+}
+
+// droplets per batch of erode_until_stable
+const STABLE_BATCH: usize = 1000;
+
+// erodes batches of droplets until one changes the heights by less than tolerance in total,
+// returns the number of batches, at most max_iters
+pub fn erode_until_stable(
+    elevation: &mut Elevation,
+    params: &ErosionParams,
+    sea_level: f32,
+    max_iters: usize,
+    tolerance: f32,
+    rng: &mut impl Rng,
+) -> usize {
+    for iter in 0..max_iters {
+        if erode_batch(elevation, STABLE_BATCH, params, sea_level, rng) < tolerance {
+            return iter + 1;
+        }
+    }
+    max_iters
+}
+
+// total height eroded and deposited by the droplets
+fn erode_batch(
+    elevation: &mut Elevation,
+    droplets: usize,
+    params: &ErosionParams,
+    sea_level: f32,
+    rng: &mut impl Rng,
+) -> f32 {
+    let kernel = Kernel::new(params.radius);
+    let delta_kernel = Kernel::new(params.radius * DELTA_SPREAD);
+    let meander = Meander::default();
+    let extent = elevation.grid.extent(elevation.size());
+    let mut moved = 0.;
+    for order in 0..droplets {
+        let mut droplet = Droplet::new(
+            Vec2::new(rng.gen::<f32>() * extent.x, rng.gen::<f32>() * extent.y),
+            order as u64,
+        );
+        while !droplet.finished(extent) {
+            if let Some((pos, v)) = step(&mut droplet, elevation, params, &meander, sea_level) {
+                moved += v.abs();
+                elevation.record_transport(pos, v, droplet.transport(pos));
+                if v > 0. && elevation.in_delta(pos, sea_level) {
+                    elevation.deposit(pos, v, &delta_kernel);
+                } else {
+                    elevation.deposit(pos, v, &kernel);
+                }
+            }
+        }
+    }
+    moved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // heights in [-1, 1] drawn at random
+    fn random_terrain(size: usize, rng: &mut StdRng) -> Elevation {
+        let data = (0..size * size)
+            .map(|_| rng.gen::<f32>() * 2. - 1.)
+            .collect();
+        Elevation::from_data(data, size)
+    }
+
+    fn total(elevation: &Elevation) -> f64 {
+        elevation.data.iter().map(|h| *h as f64).sum()
+    }
+
+    // float rounding of the many small deposits, over heights in [-1, 1]
+    const MASS_TOLERANCE: f64 = 1e-3;
+
+    #[test]
+    fn kernel_weights_sum_to_one() {
+        for radius in 1..=3 {
+            let total: f32 = Kernel::new(radius).weights.iter().map(|(_, w)| w).sum();
+            assert!((total - 1.).abs() < 1e-6, "radius {}: {}", radius, total);
+        }
+        // the default one keeps the original weights
+        for (d, w) in Kernel::default().weights {
+            let expected = match d.x.abs() + d.y.abs() {
+                l if l < 1. => 0.4,
+                l if l < 2. => 0.1,
+                _ => 0.05,
+            };
+            assert!((w - expected).abs() < 1e-6, "{:?}: {}", d, w);
+        }
+    }
+
+    #[test]
+    fn erosion_conserves_mass() {
+        for case in 0..50 {
+            let mut rng = StdRng::seed_from_u64(case);
+            let size = rng.gen_range(4..16);
+            let mut elevation = random_terrain(size, &mut rng);
+            let params = ErosionParams {
+                radius: rng.gen_range(0..3),
+                ..ErosionParams::default()
+            };
+            let (kernel, delta_kernel) = (
+                Kernel::new(params.radius),
+                Kernel::new(params.radius * DELTA_SPREAD),
+            );
+            let meander = Meander::default();
+            let extent = elevation.grid.extent(size);
+            let mut droplets: Vec<Droplet> = (0..rng.gen_range(1..50))
+                .map(|order| Droplet::new(rand_pos_in(extent, &mut rng), order))
+                .collect();
+            let initial = total(&elevation);
+            // the sediment the finished droplets carried off the terrain
+            let mut lost = 0.;
+            while !droplets.is_empty() {
+                for droplet in droplets.iter_mut() {
+                    if let Some((pos, v)) = step(droplet, &elevation, &params, &meander, 0.) {
+                        if v > 0. && elevation.in_delta(pos, 0.) {
+                            elevation.deposit(pos, v, &delta_kernel);
+                        } else {
+                            elevation.deposit(pos, v, &kernel);
+                        }
+                    }
+                }
+                let carried: f64 = droplets.iter().map(|d| d.sediment as f64).sum();
+                let error = (total(&elevation) + carried + lost - initial).abs();
+                assert!(
+                    error < MASS_TOLERANCE,
+                    "case {}: {} unaccounted",
+                    case,
+                    error
+                );
+                lost += droplets
+                    .iter()
+                    .filter(|d| d.finished(extent))
+                    .map(|d| d.sediment as f64)
+                    .sum::<f64>();
+                droplets.retain(|d| !d.finished(extent));
+            }
+        }
+    }
+
+    fn rand_pos_in(extent: Vec2, rng: &mut StdRng) -> Vec2 {
+        Vec2::new(rng.gen::<f32>() * extent.x, rng.gen::<f32>() * extent.y)
+    }
+
+    #[test]
+    fn parallel_steps_match_sequential_ones() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let params = ErosionParams::default();
+        let (kernel, meander) = (Kernel::default(), Meander::default());
+        let mut elevations = vec![random_terrain(32, &mut rng); 2];
+        let extent = elevations[0].grid.extent(32);
+        let positions: Vec<Vec2> = (0..500).map(|_| rand_pos_in(extent, &mut rng)).collect();
+        let mut droplets: Vec<Vec<Droplet>> = (0..2)
+            .map(|_| {
+                positions
+                    .iter()
+                    .enumerate()
+                    .map(|(order, pos)| Droplet::new(*pos, order as u64))
+                    .collect()
+            })
+            .collect();
+        for _ in 0..MAX_LIFETIME {
+            for (k, (elevation, droplets)) in
+                elevations.iter_mut().zip(droplets.iter_mut()).enumerate()
+            {
+                let mut refs: Vec<&mut Droplet> = droplets.iter_mut().collect();
+                let deltas = if k == 0 {
+                    step_sequential(&mut refs, elevation, &params, &meander, 0.)
+                } else {
+                    step_parallel(&mut refs, elevation, &params, &meander, 0.)
+                };
+                for (pos, v) in deltas.into_iter().flatten() {
+                    elevation.deposit(pos, v, &kernel);
+                }
+            }
+        }
+        assert_eq!(elevations[0].data, elevations[1].data);
+        for (a, b) in droplets[0].iter().zip(droplets[1].iter()) {
+            assert_eq!((a.pos, a.sediment), (b.pos, b.sediment));
+        }
+    }
+
+    #[test]
+    fn only_droplets_lay_sediment() {
+        let kernel = Kernel::new(1);
+        let pos = Vec2::new(4.5, 4.5);
+        let mut elevation = Elevation::from_data(vec![0.; 64], 8);
+        elevation.add(pos, 0.1, &kernel);
+        assert!(elevation.sediment.iter().all(|s| *s == 0.));
+        let bedrock: Vec<f32> = (0..64).map(|i| elevation.bedrock(i)).collect();
+        elevation.deposit(pos, 0.1, &kernel);
+        for i in 0..64 {
+            assert!((elevation.bedrock(i) - bedrock[i]).abs() < 1e-6);
+        }
+        // eroding the fresh deposit takes its sediment before the bedrock under it
+        elevation.deposit(pos, -0.05, &kernel);
+        for i in 0..64 {
+            assert!((elevation.bedrock(i) - bedrock[i]).abs() < 1e-6);
+        }
+        assert!(elevation.sediment.iter().sum::<f32>() > 0.);
+    }
+
+    #[test]
+    fn erosion_accum_counts_the_droplets_only() {
+        let kernel = Kernel::new(2);
+        let pos = Vec2::new(8., 8.);
+        let mut elevation = Elevation::from_data(vec![0.; 256], 16);
+        elevation.add(pos, 0.1, &kernel);
+        assert!(elevation.erosion_accum.iter().all(|a| *a == 0.));
+        elevation.deposit(pos, -0.1, &kernel);
+        let mut expected = vec![0.; 256];
+        for (delta, w) in kernel.weights.iter() {
+            expected[unroll(pos + *delta, 16)] += 0.1 * w;
+        }
+        for (a, e) in elevation.erosion_accum.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6);
+            assert_eq!(*a > 0., e > 0.);
+        }
+    }
+
+    // a V valley 48 cells wide running down towards y = 47
+    fn v_valley() -> Elevation {
+        let data = (0..48 * 48)
+            .map(|i| coords(i, 48))
+            .map(|(x, y)| 1. + 0.02 * (x as f32 - 24.).abs() + 0.01 * (47 - y) as f32)
+            .collect();
+        Elevation::from_data(data, 48)
+    }
+
+    // height removed from each cell of the row crossing the middle of the valley
+    fn removed_across(before: &Elevation, after: &Elevation) -> Vec<f32> {
+        (24 * 48..25 * 48)
+            .map(|i| before.data[i] - after.data[i])
+            .collect()
+    }
+
+    // cells where at least half the deepest removal happened, the measure doesn't depend
+    // on how much was removed so the two kinds of erosion compare at any rate
+    fn carved_width(removed: &[f32]) -> usize {
+        let deepest = removed.iter().copied().fold(0., f32::max);
+        removed.iter().filter(|r| **r >= 0.5 * deepest).count()
+    }
+
+    #[test]
+    fn glaciers_carve_wider_and_flatter_valleys_than_rivers() {
+        let before = v_valley();
+        let mut glacier = before.clone();
+        for _ in 0..10 {
+            glacier.glacier_step(0., 0.01);
+        }
+        let mut river = before.clone();
+        let params = ErosionParams::default();
+        let (kernel, meander) = (Kernel::new(params.radius), Meander::default());
+        let extent = river.grid.extent(48);
+        for order in 0..200 {
+            let mut droplet = Droplet::new(Vec2::new(24., 1.), order);
+            while !droplet.finished(extent) {
+                if let Some((pos, v)) = step(&mut droplet, &river, &params, &meander, 0.) {
+                    river.deposit(pos, v, &kernel);
+                }
+            }
+        }
+        let (glacier, river) = (
+            removed_across(&before, &glacier),
+            removed_across(&before, &river),
+        );
+        assert!(glacier.iter().any(|r| *r > 0.) && river.iter().any(|r| *r > 0.));
+        assert!(carved_width(&glacier) > carved_width(&river));
+        // 3 cells off the deepest point, the floor is lowered about as much under the ice,
+        // much less along a river
+        let flatness = |removed: &[f32]| {
+            let (x, deepest) =
+                removed
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .fold((0, 0.), |a, b| if b.1 > a.1 { b } else { a });
+            let off = |dx: i32| removed[(x as i32 + dx).clamp(0, 47) as usize];
+            off(-3).min(off(3)) / deepest
+        };
+        assert!(flatness(&glacier) > flatness(&river));
+    }
+
+    #[test]
+    fn filling_a_pit_stops_at_its_rim() {
+        let mut data = vec![1.; 81];
+        data[4 + 4 * 9] = 0.5;
+        let mut elevation = Elevation::from_data(data, 9);
+        let (params, meander) = (ErosionParams::default(), Meander::default());
+        // all of every deposit on the pit itself
+        let kernel = Kernel::new(0);
+        // once it's as high as its rim it's flat ground that takes whatever comes
+        for order in 0..50 {
+            if elevation.data[4 + 4 * 9] >= 1. - 1e-6 {
+                break;
+            }
+            let mut droplet = Droplet::new(Vec2::new(4., 4.), order);
+            droplet.sediment = 10.;
+            if let Some((pos, v)) = step(&mut droplet, &elevation, &params, &meander, 0.) {
+                elevation.deposit(pos, v, &kernel);
+            }
+            assert!(elevation.data[4 + 4 * 9] <= 1. + 1e-6);
+        }
+        assert!((elevation.data[4 + 4 * 9] - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sediment_is_conserved_on_flat_ground() {
+        let mut elevation = Elevation::from_data(vec![0.5; 81], 9);
+        let params = ErosionParams {
+            evaporation: 0.,
+            ..ErosionParams::default()
+        };
+        let (meander, kernel) = (Meander::default(), Kernel::new(1));
+        let mut droplet = Droplet::new(Vec2::new(4.5, 4.5), 0);
+        droplet.sediment = 0.3;
+        let before = total(&elevation) + 0.3;
+        let mut deposited = false;
+        while !droplet.finished(elevation.grid.extent(9)) {
+            if let Some((pos, v)) = step(&mut droplet, &elevation, &params, &meander, 0.) {
+                deposited |= v > 0.;
+                elevation.deposit(pos, v, &kernel);
+            }
+            let after = total(&elevation) + droplet.sediment as f64;
+            assert!(
+                (after - before).abs() < MASS_TOLERANCE,
+                "{} became {}",
+                before,
+                after
+            );
+        }
+        // a flat cell has no rim to stop at and takes the sediment
+        assert!(deposited);
+    }
+
+    #[test]
+    fn wind_builds_ripples_across_a_dry_plain() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let data = (0..48 * 48).map(|_| rng.gen::<f32>() * 1e-3).collect();
+        let mut elevation = Elevation::from_data(data, 48);
+        let before = total(&elevation);
+        let dry = vec![true; 48 * 48];
+        for _ in 0..1500 {
+            elevation.wind_step(Vec2::new(1., 0.), &dry);
+        }
+        assert!((total(&elevation) - before).abs() < MASS_TOLERANCE);
+        // away from the edges the wind blows in and out from
+        let h = |x: usize, y: usize| elevation.data[x + y * 48] as f64;
+        let inner = || iproduct!(6..42, 6..42);
+        let along: Vec<f64> = inner().map(|(y, x)| h(x + 1, y) - h(x, y)).collect();
+        let across: Vec<f64> = inner().map(|(y, x)| h(x, y + 1) - h(x, y)).collect();
+        let mean_abs = |d: &[f64]| d.iter().map(|d| d.abs()).sum::<f64>() / d.len() as f64;
+        // the crests run across the wind so the heights change along it, not across it
+        assert!(mean_abs(&along) > 10. * mean_abs(&across));
+        // long gentle slopes facing the wind and short steep ones behind the crests
+        let mean = along.iter().sum::<f64>() / along.len() as f64;
+        let moment = |k: i32| along.iter().map(|d| (d - mean).powi(k)).sum::<f64>();
+        assert!(moment(3) < 0.);
+        let crests = (7..41)
+            .filter(|x| h(*x, 24) > h(x - 1, 24) && h(*x, 24) > h(x + 1, 24))
+            .count();
+        assert!(crests >= 2);
+    }
+
+    // the steepest drop from a cell to one of its neighbors, per unit of distance
+    fn steepest(elevation: &Elevation) -> f32 {
+        (0..elevation.data.len())
+            .flat_map(|i| {
+                elevation
+                    .neighbors(i)
+                    .into_iter()
+                    .map(move |(n, dist)| (elevation.data[i] - elevation.data[n]) / dist)
+            })
+            .fold(0., f32::max)
+    }
+
+    #[test]
+    fn thermal_erosion_relaxes_the_steep_slopes() {
+        let mut data = vec![0.; 64];
+        data[3 + 3 * 8] = 1.;
+        let mut elevation = Elevation::from_data(data, 8);
+        let (before, slope) = (total(&elevation), steepest(&elevation));
+        elevation.thermal_step();
+        assert!(elevation.data[3 + 3 * 8] < 1.);
+        assert!(steepest(&elevation) < slope);
+        assert!((total(&elevation) - before).abs() < MASS_TOLERANCE);
+        // slopes under the talus angle stay put
+        let gentle: Vec<f32> = (0..64)
+            .map(|i| 0.5 * TALUS_ANGLE * coords(i, 8).0 as f32)
+            .collect();
+        let mut elevation = Elevation::from_data(gentle.clone(), 8);
+        elevation.thermal_step();
+        assert_eq!(elevation.data, gentle);
+    }
+
+    #[test]
+    fn bilinear_sampling_matches_the_cells_and_clamps_at_the_edges() {
+        let elevation = random_terrain(8, &mut StdRng::seed_from_u64(2));
+        let h = |x: usize, y: usize| elevation.data[x + y * 8];
+        for i in 0..64 {
+            let (x, y) = coords(i, 8);
+            let sampled = elevation.height_bilinear(Vec2::new(x as f32, y as f32));
+            assert!((sampled - h(x, y)).abs() < 1e-6);
+        }
+        // halfway between 4 cells
+        let mid = (h(2, 3) + h(3, 3) + h(2, 4) + h(3, 4)) / 4.;
+        assert!((elevation.height_bilinear(Vec2::new(2.5, 3.5)) - mid).abs() < 1e-6);
+        // past the edges, the closest border cell
+        assert!((elevation.height_bilinear(Vec2::new(-3., 5.)) - h(0, 5)).abs() < 1e-6);
+        assert!((elevation.height_bilinear(Vec2::new(20., 20.)) - h(7, 7)).abs() < 1e-6);
+        // the gradient of a plane is its slope everywhere
+        let plane = (0..64)
+            .map(|i| coords(i, 8))
+            .map(|(x, y)| 0.1 * x as f32 + 0.2 * y as f32)
+            .collect();
+        let plane = Elevation::from_data(plane, 8);
+        for pos in [Vec2::new(0., 0.), Vec2::new(3.3, 5.7), Vec2::new(7., 7.)].iter() {
+            assert!((plane.grad_bilinear(*pos) - Vec2::new(0.1, 0.2)).length() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn a_4x4_image_loads_from_black_at_minus_1_to_white_at_1() {
+        let img = image::GrayImage::from_fn(4, 4, |x, y| image::Luma([(17 * (x + 4 * y)) as u8]));
+        let name = format!("terrain_gen_from_image_{}.png", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        img.save(&path).unwrap();
+        let elevation = Elevation::from_image(&path, 4).unwrap();
+        let resized = Elevation::from_image(&path, 8).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((elevation.size(), elevation.data.len()), (4, 16));
+        for (i, h) in elevation.data.iter().enumerate() {
+            let expected = 2. * i as f32 / 15. - 1.;
+            assert!((h - expected).abs() < 1e-3, "cell {}: {}", i, h);
+        }
+        assert_eq!(resized.data.len(), 64);
+        assert!(resized.data.iter().all(|h| (-1. ..=1.).contains(h)));
+    }
+
+    #[test]
+    fn the_terrain_takes_the_size_of_its_config() {
+        for size in [2, 17, 64].iter() {
+            let config = TerrainConfig::new(*size);
+            let noise = config.noise.build(1, &config.fractal);
+            let elevation = Elevation::new(&config, noise.as_ref(), 1);
+            assert_eq!(elevation.size(), *size);
+            for layer in [
+                &elevation.data,
+                &elevation.hardness,
+                &elevation.sediment,
+                &elevation.precipitation,
+            ]
+            .iter()
+            {
+                assert_eq!(layer.len(), size * size);
+            }
+        }
+        // a single cell has no slope to erode
+        assert_eq!(TerrainConfig::new(0).size, 2);
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_terrain() {
+        // every draw from the seed: the noise, the warp and the islands
+        let mut config = TerrainConfig::new(32);
+        config.warp_strength = 0.3;
+        config.island.islands = 3;
+        let generate = |seed: u32| {
+            let noise = config.noise.build(seed, &config.fractal);
+            Elevation::new(&config, noise.as_ref(), seed).data
+        };
+        assert_eq!(generate(7), generate(7));
+        assert_ne!(generate(7), generate(8));
+    }
+
+    #[test]
+    fn a_higher_sea_floods_more_cells() {
+        let config = TerrainConfig::new(32);
+        let noise = config.noise.build(5, &config.fractal);
+        let elevation = Elevation::new(&config, noise.as_ref(), 5);
+        let land = |sea_level: f32| elevation.stats(sea_level).above_water_fraction;
+        assert!(land(-0.25) > land(0.));
+        assert!(land(0.) > land(0.25));
+    }
+
+    #[test]
+    fn each_noise_gives_its_own_terrain() {
+        let kinds = [
+            NoiseKind::Perlin,
+            NoiseKind::Fbm,
+            NoiseKind::RidgedMulti,
+            NoiseKind::Worley,
+        ];
+        let mut config = TerrainConfig::new(32);
+        config.island.enabled = false;
+        let stats: Vec<TerrainStats> = kinds
+            .iter()
+            .map(|kind| {
+                let noise = kind.build(1, &config.fractal);
+                Elevation::new(&config, noise.as_ref(), 1).stats(0.)
+            })
+            .collect();
+        for (a, b) in iproduct!(0..kinds.len(), 0..kinds.len()).filter(|(a, b)| a < b) {
+            assert_ne!(stats[a], stats[b], "{:?} and {:?}", kinds[a], kinds[b]);
+        }
+    }
+
+    // mean squared height difference between the cells and their right neighbors
+    fn local_variance(elevation: &Elevation) -> f32 {
+        let size = elevation.size();
+        let diffs: Vec<f32> = (0..size * size)
+            .filter(|i| i % size < size - 1)
+            .map(|i| elevation.data[i + 1] - elevation.data[i])
+            .collect();
+        diffs.iter().map(|d| d * d).sum::<f32>() / diffs.len() as f32
+    }
+
+    #[test]
+    fn more_octaves_add_finer_detail() {
+        let mut config = TerrainConfig::new(64);
+        config.island.enabled = false;
+        let mut variance = |octaves: usize| {
+            config.fractal.octaves = Some(octaves);
+            let noise = NoiseKind::Fbm.build(3, &config.fractal);
+            local_variance(&Elevation::new(&config, noise.as_ref(), 3))
+        };
+        assert!(variance(8) > variance(1));
+    }
+
+    #[test]
+    fn coords_and_unroll_are_inverses() {
+        let size = 7;
+        for i in 0..size * size {
+            let (x, y) = coords(i, size);
+            assert_eq!(x + y * size, i);
+            assert_eq!(unroll(Vec2::new(x as f32 + 0.5, y as f32 + 0.5), size), i);
+        }
+        // positions off the grid are clamped to the border cells
+        assert_eq!(unroll(Vec2::new(-2., 3.), size), 3 * size);
+        assert_eq!(unroll(Vec2::new(9., 20.), size), size * size - 1);
+    }
+
+    #[test]
+    fn droplets_stay_finite_on_flat_ground() {
+        assert_eq!(normalize_or_zero(Vec2::ZERO), Vec2::ZERO);
+        assert_eq!(normalize_or_zero(Vec2::new(f32::NAN, 1.)), Vec2::ZERO);
+        assert_eq!(normalize_or_zero(Vec2::new(3., 4.)), Vec2::new(0.6, 0.8));
+        let elevation = Elevation::from_data(vec![0.5; 64], 8);
+        let (params, meander) = (ErosionParams::default(), Meander::default());
+        let mut droplet = Droplet::new(Vec2::new(3.5, 4.5), 0);
+        while !droplet.finished(elevation.grid.extent(8)) {
+            step(&mut droplet, &elevation, &params, &meander, 0.);
+            assert!(droplet.pos.is_finite() && droplet.dir.is_finite());
+        }
+        assert_eq!(droplet.pos, Vec2::new(3.5, 4.5));
+    }
+
+    #[test]
+    fn droplets_end_when_too_old_or_off_the_grid() {
+        // a flat floor the droplet can't roll off, with water that doesn't evaporate
+        let basin = (0..64)
+            .map(|i| match coords(i, 8) {
+                (1..=6, 1..=6) => 0.5,
+                _ => 1.,
+            })
+            .collect();
+        let basin = Elevation::from_data(basin, 8);
+        let params = ErosionParams {
+            evaporation: 0.,
+            ..ErosionParams::default()
+        };
+        let meander = Meander::default();
+        let extent = basin.grid.extent(8);
+        let mut droplet = Droplet::new(Vec2::new(3.5, 3.5), 0);
+        for _ in 0..MAX_LIFETIME {
+            step(&mut droplet, &basin, &params, &meander, 0.);
+        }
+        assert_eq!(droplet.pos, Vec2::new(3.5, 3.5));
+        assert!(!droplet.finished(extent));
+        step(&mut droplet, &basin, &params, &meander, 0.);
+        assert!(droplet.finished(extent));
+        // down a plane towards the first column and over its edge
+        let plane = Elevation::from_data((0..64).map(|i| 0.1 * (i % 8) as f32).collect(), 8);
+        let mut droplet = Droplet::new(Vec2::new(1.5, 4.5), 0);
+        let mut steps = 0;
+        while !droplet.finished(extent) {
+            step(&mut droplet, &plane, &params, &meander, 0.);
+            steps += 1;
+            assert!(steps < MAX_LIFETIME);
+        }
+        assert!(droplet.pos.x < 0.);
+    }
+
+    #[test]
+    fn deposits_at_the_corners_stay_whole_and_inside() {
+        let kernel = Kernel::new(2);
+        for pos in [Vec2::new(0., 0.), Vec2::new(7.9, 7.9), Vec2::new(0.2, 7.5)].iter() {
+            let mut elevation = Elevation::from_data(vec![0.; 64], 8);
+            elevation.add(*pos, 0.5, &kernel);
+            assert!((total(&elevation) - 0.5).abs() < 1e-6);
+            // only the cells within the radius of the corner
+            let (cx, cy) = coords(elevation.index(*pos), 8);
+            for i in 0..64 {
+                let (x, y) = coords(i, 8);
+                let near = (x as i32 - cx as i32).abs() <= 2 && (y as i32 - cy as i32).abs() <= 2;
+                assert!(near || elevation.data[i] == 0.);
+            }
+        }
+    }
+
+    #[test]
+    fn sobel_gradient_points_up_a_cone() {
+        let center = Vec2::splat(16.);
+        let cone = (0..33 * 33)
+            .map(|i| coords(i, 33))
+            .map(|(x, y)| -(Vec2::new(x as f32, y as f32) - center).length())
+            .collect();
+        let elevation = Elevation::from_data(cone, 33);
+        for i in 0..33 * 33 {
+            let (x, y) = coords(i, 33);
+            let out = Vec2::new(x as f32, y as f32) - center;
+            if out.length() < 3. || x == 0 || y == 0 || x == 32 || y == 32 {
+                continue;
+            }
+            let grad = elevation.grad_sobel(i);
+            assert!(
+                grad.normalize().dot(-out.normalize()) > 0.999,
+                "{:?}",
+                (x, y)
+            );
+        }
+        // the slope is the same all around, the sobel gradient strays less from it on a ring
+        let spread = |grad: &dyn Fn(usize) -> Vec2| {
+            let ring = (0..33 * 33).filter(|i| {
+                let (x, y) = coords(*i, 33);
+                ((Vec2::new(x as f32, y as f32) - center).length() - 8.).abs() < 0.5
+            });
+            let lengths: Vec<f32> = ring.map(|i| grad(i).length()).collect();
+            let max = lengths.iter().copied().fold(f32::MIN, f32::max);
+            max - lengths.iter().copied().fold(f32::MAX, f32::min)
+        };
+        let (sobel, central) = (
+            spread(&|i| elevation.grad_sobel(i)),
+            spread(&|i| elevation.grad(i)),
+        );
+        assert!(sobel < central, "{} against {}", sobel, central);
+    }
+
+    #[test]
+    fn toroidal_gradient_wraps_around_the_edges() {
+        let ramp = (0..64).map(|i| coords(i, 8).0 as f32).collect();
+        let mut elevation = Elevation::from_data(ramp, 8);
+        // one-sided on a clamped grid
+        assert_eq!(elevation.grad(2 * 8), Vec2::new(1., 0.));
+        assert_eq!(elevation.grad(7 + 2 * 8), Vec2::new(1., 0.));
+        // the left column's left neighbor is the right column on a torus
+        elevation.topology = Topology::Toroidal;
+        assert_eq!(elevation.grad(2 * 8), Vec2::new((1. - 7.) / 2., 0.));
+        assert_eq!(elevation.grad(7 + 2 * 8), Vec2::new((0. - 6.) / 2., 0.));
+        assert_eq!(elevation.grad(3 + 2 * 8), Vec2::new(1., 0.));
+    }
+
+    #[test]
+    #[should_panic(expected = "the toroidal topology only works on the square grid")]
+    fn a_hex_torus_isnt_generated() {
+        let mut config = TerrainConfig::new(8);
+        config.grid = Grid::Hex;
+        config.topology = Topology::Toroidal;
+        Elevation::new(&config, &Slope, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "the toroidal topology only works on the square grid")]
+    fn a_loaded_terrain_isnt_laid_out_on_a_hex_torus() {
+        Elevation::from_data(vec![0.; 64], 8).with_layout(Grid::Hex, Topology::Toroidal);
+    }
+
+    #[test]
+    fn the_island_falloff_peaks_in_the_middle() {
+        let size = 32;
+        let edge_mean = |elevation: &Elevation| {
+            let edge: Vec<f32> = (0..size * size)
+                .filter(|i| {
+                    let (x, y) = coords(*i, size);
+                    x == 0 || y == 0 || x == size - 1 || y == size - 1
+                })
+                .map(|i| elevation.data[i])
+                .collect();
+            edge.iter().sum::<f32>() / edge.len() as f32
+        };
+        let flat = Elevation::from_data(vec![0.; size * size], size);
+        let mut island = flat.clone();
+        island.apply_falloff(&IslandShape::default(), 0);
+        assert!(edge_mean(&island) < edge_mean(&flat));
+        let peak = (0..size * size)
+            .max_by(|a, b| island.data[*a].total_cmp(&island.data[*b]))
+            .unwrap();
+        assert_eq!(coords(peak, size), (size / 2, size / 2));
+        // a stronger falloff sinks the edges deeper
+        let mut steep = flat.clone();
+        steep.apply_falloff(&IslandShape::new(2., 0.5, 1), 0);
+        assert!(edge_mean(&steep) < edge_mean(&island));
+        // and none when it's disabled
+        let mut disabled = flat.clone();
+        let shape = IslandShape {
+            enabled: false,
+            ..IslandShape::default()
+        };
+        disabled.apply_falloff(&shape, 0);
+        assert_eq!(disabled.data, flat.data);
+    }
+
+    #[test]
+    fn droplets_down_a_cliff_keep_to_the_speed_limit() {
+        // dropping a whole unit of height per cell
+        let cliff = (0..256).map(|i| -(coords(i, 16).0 as f32)).collect();
+        let mut elevation = Elevation::from_data(cliff, 16);
+        let before = elevation.data.clone();
+        let params = ErosionParams {
+            max_velocity: 0.5,
+            max_water: 0.8,
+            ..ErosionParams::default()
+        };
+        let (meander, kernel) = (Meander::default(), Kernel::new(params.radius));
+        let mut droplet = Droplet::new(Vec2::new(1., 8.), 0);
+        let mut changed = Vec::new();
+        while !droplet.finished(elevation.grid.extent(16)) {
+            if let Some((pos, v)) = step(&mut droplet, &elevation, &params, &meander, -100.) {
+                changed.push(coords(elevation.index(pos), 16));
+                elevation.deposit(pos, v, &kernel);
+            }
+            assert!(droplet.vel <= params.max_velocity);
+            assert!(droplet.water <= params.max_water);
+        }
+        assert!(droplet.steps > 1);
+        let radius = params.radius as i32;
+        for i in 0..256 {
+            let (x, y) = coords(i, 16);
+            // the crater is no deeper than the cliff drops from one cell to the next
+            assert!(elevation.data[i] >= before[i] - 1.);
+            let near = changed.iter().any(|(cx, cy)| {
+                (x as i32 - *cx as i32).abs() <= radius && (y as i32 - *cy as i32).abs() <= radius
+            });
+            if !near {
+                assert_eq!(elevation.data[i], before[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn smoothing_blurs_the_bumps_and_keeps_the_rest() {
+        let mut flat = Elevation::from_data(vec![0.3; 64], 8);
+        flat.smooth(1.5, 2);
+        assert!(flat.data.iter().all(|h| (h - 0.3).abs() < 1e-6));
+        // on a torus no weight is left out so the mean stays
+        let checkerboard = (0..256)
+            .map(|i| coords(i, 16))
+            .map(|(x, y)| if (x + y) % 2 == 0 { 1. } else { -1. })
+            .collect();
+        let mut elevation = Elevation::from_data(checkerboard, 16);
+        elevation.topology = Topology::Toroidal;
+        let before = total(&elevation);
+        elevation.smooth(1., 1);
+        assert!((total(&elevation) - before).abs() < MASS_TOLERANCE);
+        let variance = elevation.data.iter().map(|h| h * h).sum::<f32>() / 256.;
+        assert!(variance < 0.1);
+    }
+
+    #[test]
+    fn full_terraces_leave_as_many_levels_as_asked() {
+        let mut elevation = random_terrain(16, &mut StdRng::seed_from_u64(4));
+        let sea: Vec<(usize, f32)> = (0..256)
+            .map(|i| (i, elevation.data[i]))
+            .filter(|(_, h)| *h < 0.)
+            .collect();
+        elevation.terrace(4, 1., 0.);
+        let mut levels: Vec<f32> = elevation
+            .data
+            .iter()
+            .copied()
+            .filter(|h| *h >= 0.)
+            .collect();
+        levels.sort_by(f32::total_cmp);
+        levels.dedup_by(|a, b| (*a - *b).abs() < 1e-5);
+        assert!(levels.len() <= 4, "{:?}", levels);
+        // the sea floor is left alone
+        assert!(sea.iter().all(|(i, h)| elevation.data[*i] == *h));
+    }
+
+    #[test]
+    fn stats_and_normalized_heights_of_small_grids() {
+        let elevation = Elevation::from_data(vec![0., 1., 2., 3.], 2);
+        assert_eq!(
+            elevation.stats(1.5),
+            TerrainStats {
+                min: 0.,
+                max: 3.,
+                mean: 1.5,
+                above_water_fraction: 0.5,
+            }
+        );
+        assert_eq!(elevation.stats(0.).above_water_fraction, 1.);
+        let normalized = elevation.normalized();
+        for (n, expected) in normalized.iter().zip([0., 1. / 3., 2. / 3., 1.].iter()) {
+            assert!((n - expected).abs() < 1e-6);
+        }
+        // a flat terrain has no range to spread over
+        let flat = Elevation::from_data(vec![0.7; 4], 2);
+        assert_eq!(flat.normalized(), vec![0.; 4]);
+    }
+
+    #[test]
+    fn the_gradient_is_flat_on_a_ridge_crest() {
+        // a ridge running along the column x = 8
+        let ridge = (0..17 * 17)
+            .map(|i| -(coords(i, 17).0 as f32 - 8.).abs())
+            .collect();
+        let elevation = Elevation::from_data(ridge, 17);
+        for y in 0..17 {
+            assert_eq!(elevation.grad(8 + y * 17), Vec2::ZERO);
+            assert_eq!(elevation.grad_sobel(8 + y * 17), Vec2::ZERO);
+            // the central difference of the flanks is their slope
+            assert_eq!(elevation.grad(4 + y * 17), Vec2::new(1., 0.));
+            assert_eq!(elevation.grad(12 + y * 17), Vec2::new(-1., 0.));
+        }
+    }
+
+    #[test]
+    fn a_hard_stripe_loses_less_bedrock_than_soft_ground() {
+        // down a slope along the rows, from the first columns to the last
+        let plane: Vec<f32> = (0..256).map(|i| 1. - 0.05 * (i % 16) as f32).collect();
+        let soft = Elevation::from_data(plane.clone(), 16);
+        let mut striped = Elevation::from_data(plane, 16);
+        for (i, hardness) in striped.hardness.iter_mut().enumerate() {
+            if (6..10).contains(&(i % 16)) {
+                *hardness = 0.8;
+            }
+        }
+        let (params, meander) = (ErosionParams::default(), Meander::default());
+        // bedrock taken from the stripe's columns by the droplet rolling down the terrain
+        let eroded = |elevation: &Elevation| {
+            let mut droplet = Droplet::new(Vec2::new(1.5, 8.5), 0);
+            let mut eroded = 0.;
+            while !droplet.finished(elevation.grid.extent(16)) {
+                if let Some((pos, v)) = step(&mut droplet, elevation, &params, &meander, 0.) {
+                    if (6. ..10.).contains(&pos.x) && v < 0. {
+                        eroded -= v;
+                    }
+                }
+            }
+            eroded
+        };
+        let (soft, striped) = (eroded(&soft), eroded(&striped));
+        assert!(soft > 0.);
+        assert!(striped < soft, "{} against {}", striped, soft);
+    }
+
+    #[test]
+    fn transport_is_the_distance_the_sediment_traveled() {
+        let mut elevation = Elevation::from_data(vec![0.; 256], 16);
+        let kernel = Kernel::new(0);
+        let (a, b) = (Vec2::new(2.5, 3.5), Vec2::new(10.5, 9.5));
+        let deposit = |elevation: &mut Elevation, from: Vec2| {
+            let droplet = Droplet::new(from, 0);
+            elevation.record_transport(b, 0.1, droplet.transport(b));
+            elevation.deposit(b, 0.1, &kernel);
+        };
+        deposit(&mut elevation, a);
+        let i = elevation.index(b);
+        assert!((elevation.transport[i] - (b - a).length()).abs() < 1e-5);
+        // mixed with as much sediment that came from twice as far
+        deposit(&mut elevation, b + 2. * (a - b));
+        assert!((elevation.transport[i] - 1.5 * (b - a).length()).abs() < 1e-5);
+        assert_eq!(elevation.transport.iter().filter(|t| **t > 0.).count(), 1);
+    }
+
+    // mean height difference between the cells and their right and lower neighbors
+    fn roughness(elevation: &Elevation) -> f32 {
+        let size = elevation.size();
+        let diffs: Vec<f32> = iproduct!(0..size - 1, 0..size - 1)
+            .map(|(y, x)| x + y * size)
+            .flat_map(|i| IntoIterator::into_iter([i + 1, i + size]).map(move |n| (i, n)))
+            .map(|(i, n)| (elevation.data[n] - elevation.data[i]).abs())
+            .collect();
+        diffs.iter().sum::<f32>() / diffs.len() as f32
+    }
+
+    #[test]
+    fn eroding_until_stable_stops_and_smooths() {
+        let config = TerrainConfig::new(32);
+        let noise = config.noise.build(6, &config.fractal);
+        let elevation = Elevation::new(&config, noise.as_ref(), 6);
+        let params = ErosionParams::default();
+        let mut rng = StdRng::seed_from_u64(6);
+        // any batch is stable enough under an infinite tolerance, none under 0
+        let mut eroded = elevation.clone();
+        let batches = erode_until_stable(&mut eroded, &params, 0., 5, f32::INFINITY, &mut rng);
+        assert_eq!(batches, 1);
+        let batches = erode_until_stable(&mut eroded, &params, 0., 10, 0., &mut rng);
+        assert_eq!(batches, 10);
+        assert!(roughness(&eroded) < roughness(&elevation));
+    }
+
+    #[test]
+    fn profiles_sample_every_spacing_and_both_ends() {
+        let elevation = random_terrain(16, &mut StdRng::seed_from_u64(8));
+        let (a, b) = (Vec2::new(1., 2.), Vec2::new(11., 2.));
+        assert_eq!(elevation.profile(a, b, 1.).len(), 11);
+        // spread evenly, a bit closer than the spacing
+        let profile = elevation.profile(a, b, 3.);
+        assert_eq!(profile.len(), 5);
+        assert_eq!(profile[0], elevation.height_bilinear(a));
+        assert_eq!(profile[4], elevation.height_bilinear(b));
+        assert_eq!(
+            elevation.profile(a, a, 1.),
+            vec![elevation.height_bilinear(a)]
+        );
+    }
+
+    // groups of land cells connected through their neighbors
+    fn land_components(elevation: &Elevation, sea_level: f32) -> usize {
+        let land = |i: usize| elevation.data[i] >= sea_level;
+        let mut seen = vec![false; elevation.data.len()];
+        let mut components = 0;
+        for start in 0..elevation.data.len() {
+            if seen[start] || !land(start) {
+                continue;
+            }
+            components += 1;
+            seen[start] = true;
+            let mut stack = vec![start];
+            while let Some(i) = stack.pop() {
+                for (n, _) in elevation.neighbors(i) {
+                    if !seen[n] && land(n) {
+                        seen[n] = true;
+                        stack.push(n);
+                    }
+                }
+            }
+        }
+        components
+    }
+
+    #[test]
+    fn an_archipelago_has_separate_islands() {
+        for seed in 0..5 {
+            let mut elevation = Elevation::from_data(vec![0.; 64 * 64], 64);
+            elevation.apply_falloff(&IslandShape::new(1., 0.5, 3), seed);
+            assert!(land_components(&elevation, 0.) >= 2, "seed {}", seed);
+        }
+    }
+
+    // a plane going up towards the east, its coast is a straight line down the middle
+    struct Slope;
+
+    impl NoiseFn<[f64; 2]> for Slope {
+        fn get(&self, point: [f64; 2]) -> f64 {
+            point[0]
+        }
+    }
+
+    impl NoiseFn<[f64; 4]> for Slope {
+        fn get(&self, point: [f64; 4]) -> f64 {
+            point[0]
+        }
+    }
+
+    // land cells next to the water
+    fn coast_length(elevation: &Elevation, sea_level: f32) -> usize {
+        let land = |i: usize| elevation.data[i] >= sea_level;
+        (0..elevation.data.len())
+            .filter(|i| land(*i) && elevation.neighbors(*i).iter().any(|(n, _)| !land(*n)))
+            .count()
+    }
+
+    #[test]
+    fn warping_makes_the_coast_longer() {
+        let mut config = TerrainConfig::new(64);
+        config.island.enabled = false;
+        let straight = coast_length(&Elevation::new(&config, &Slope, 9), 0.);
+        assert_eq!(straight, 64);
+        config.warp_strength = 0.5;
+        assert!(coast_length(&Elevation::new(&config, &Slope, 9), 0.) > straight);
+    }
+
+    #[test]
+    fn resampling_keeps_the_heights_and_ramps() {
+        let elevation = random_terrain(8, &mut StdRng::seed_from_u64(10));
+        let same = elevation.resample(8);
+        for (a, b) in same.data.iter().zip(elevation.data.iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+        // a ramp stays a ramp wherever the new cells fall between the old ones
+        let ramp = (0..64)
+            .map(|i| coords(i, 8))
+            .map(|(x, y)| x as f32 + 2. * y as f32)
+            .collect();
+        let ramp = Elevation::from_data(ramp, 8);
+        for new_size in [4, 16].iter() {
+            let resampled = ramp.resample(*new_size);
+            let scale = 8. / *new_size as f32;
+            for i in 0..new_size * new_size {
+                let (x, y) = coords(i, *new_size);
+                let p =
+                    (Vec2::new(x as f32, y as f32) + Vec2::splat(0.5)) * scale - Vec2::splat(0.5);
+                if p.min_element() >= 0. && p.max_element() <= 7. {
+                    assert!((resampled.data[i] - (p.x + 2. * p.y)).abs() < 1e-4);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resampling_up_and_back_down_keeps_smooth_heights() {
+        let data = (0..16 * 16)
+            .map(|i| coords(i, 16))
+            .map(|(x, y)| 0.5 * (0.3 * x as f32).sin() * (0.2 * y as f32).cos())
+            .collect();
+        let elevation = Elevation::from_data(data, 16);
+        let doubled = elevation.resample(32);
+        assert_eq!(doubled.data.len(), 32 * 32);
+        let back = doubled.resample(16);
+        assert_eq!(back.data.len(), 16 * 16);
+        // the bilinear steps only blur the heights a bit
+        for (a, b) in back.data.iter().zip(elevation.data.iter()) {
+            assert!((a - b).abs() < 0.03, "{} against {}", a, b);
+        }
+    }
+
+    #[test]
+    fn rivers_build_deltas_in_the_shallow_water() {
+        // land sloping down to the sea from the west, then a flat shelf just under the water
+        let data = (0..32 * 32)
+            .map(|i| coords(i, 32).0)
+            .map(|x| {
+                if x <= 20 {
+                    0.5 - 0.025 * x as f32
+                } else {
+                    -0.03
+                }
+            })
+            .collect();
+        let mut elevation = Elevation::from_data(data, 32);
+        let shelf = |elevation: &Elevation| -> f32 {
+            (0..32 * 32)
+                .filter(|i| coords(*i, 32).0 > 20)
+                .map(|i| elevation.data[i])
+                .sum()
+        };
+        let before = shelf(&elevation);
+        assert!(elevation.in_delta(Vec2::new(21.5, 10.5), 0.));
+        assert!(!elevation.in_delta(Vec2::new(19.5, 10.5), 0.));
+        let params = ErosionParams::default();
+        erode(
+            &mut elevation,
+            2000,
+            &params,
+            0.,
+            &mut StdRng::seed_from_u64(11),
+        );
+        assert!(shelf(&elevation) > before);
+    }
+
+    #[test]
+    fn beaches_flatten_the_shore() {
+        // a steep ramp out of the sea, the shore runs down the column x = 10
+        let ramp: Vec<f32> = (0..32 * 32)
+            .map(|i| 0.1 * (coords(i, 32).0 as f32 - 10.))
+            .collect();
+        let mut elevation = Elevation::from_data(ramp.clone(), 32);
+        elevation.generate_beaches(5., 0.);
+        let h = |data: &[f32], x: usize| data[x + 16 * 32];
+        assert!(h(&elevation.data, 10).abs() < 1e-6);
+        assert!(
+            h(&elevation.data, 11) - h(&elevation.data, 10) < 0.5 * (h(&ramp, 11) - h(&ramp, 10))
+        );
+        for i in 0..32 * 32 {
+            let x = coords(i, 32).0;
+            // the beach is only lowered towards the sea, the sea and the land past it stay
+            if (10..15).contains(&x) {
+                assert!(elevation.data[i] <= ramp[i] + 1e-6 && elevation.data[i] >= -1e-6);
+            } else {
+                assert_eq!(elevation.data[i], ramp[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn aspect_and_curvature_of_simple_shapes() {
+        let plane = |slope: Vec2| {
+            let data = (0..64)
+                .map(|i| coords(i, 8))
+                .map(|(x, y)| slope.dot(Vec2::new(x as f32, y as f32)))
+                .collect();
+            Elevation::from_data(data, 8)
+        };
+        // facing down its slope, clockwise from north towards the first row
+        for (slope, aspect) in [
+            (Vec2::new(-1., 0.), 90.),
+            (Vec2::new(1., 0.), 270.),
+            (Vec2::new(0., 1.), 0.),
+            (Vec2::new(0., -1.), 180.),
+        ]
+        .iter()
+        {
+            let aspects = plane(*slope).aspect();
+            assert!(
+                aspects.iter().all(|a| (a - aspect).abs() < 1e-3),
+                "{:?}",
+                slope
+            );
+        }
+        assert!(plane(Vec2::ZERO).aspect().iter().all(|a| *a == 0.));
+        let bowl = |sign: f32| {
+            let data = (0..81)
+                .map(|i| coords(i, 9))
+                .map(|(x, y)| {
+                    sign * (Vec2::new(x as f32, y as f32) - Vec2::splat(4.)).length_squared()
+                })
+                .collect();
+            Elevation::from_data(data, 9).curvature()
+        };
+        assert!(bowl(1.).iter().all(|c| *c > 0.));
+        assert!(bowl(-1.).iter().all(|c| *c < 0.));
+    }
+}
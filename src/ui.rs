@@ -0,0 +1,220 @@
+use crate::brush::SourceBrush;
+use crate::draw3d::{FogSettings, MeshStats, Sun};
+use crate::erosion::{DropletBudget, ErosionBudget, ErosionStats, Source, TerrainId};
+use crate::input::{Action, KeyBindings};
+use crate::pick::QueriedCell;
+use bevy::diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin};
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext, EguiPlugin};
+use terrain_gen::config::TerrainConfig;
+use terrain_gen::presets::Preset;
+use terrain_gen::terrain::{Droplet, ErosionParams};
+
+// whether the stats overlay is shown
+#[derive(Default)]
+pub struct Hud {
+    pub visible: bool,
+}
+
+fn erosion_panel(
+    egui_context: Res<EguiContext>,
+    mut params: ResMut<ErosionParams>,
+    mut budget: ResMut<ErosionBudget>,
+    mut source_brush: ResMut<SourceBrush>,
+    mut terrain: ResMut<TerrainConfig>,
+) {
+    egui::Window::new("Erosion").show(egui_context.ctx(), |ui| {
+        // the erosion follows right away, the terrain shape on the next reset
+        let mut preset = terrain.preset;
+        egui::ComboBox::from_label("preset")
+            .selected_text(preset.map_or("custom", |preset| preset.name()))
+            .show_ui(ui, |ui| {
+                for p in Preset::ALL.iter() {
+                    ui.selectable_value(&mut preset, Some(*p), p.name());
+                }
+            });
+        if let (Some(p), true) = (preset, preset != terrain.preset) {
+            *params = p.params();
+            p.shape(&mut terrain);
+        }
+        // the widgets edit copies, the resources are only touched when a value changes
+        // so the systems watching them don't see a change every frame
+        let mut edited = params.clone();
+        let sliders = [
+            ui.add(egui::Slider::new(&mut edited.evaporation, 0.0..=0.5).text("evaporation")),
+            ui.add(egui::Slider::new(&mut edited.inertia, 0.0..=1.0).text("inertia")),
+            ui.add(egui::Slider::new(&mut edited.min_slope, 0.0..=0.05).text("min slope")),
+            ui.add(egui::Slider::new(&mut edited.capacity, 0.0..=2000.0).text("capacity")),
+            ui.add(egui::Slider::new(&mut edited.deposition, 0.0..=1.0).text("deposition")),
+            ui.add(egui::Slider::new(&mut edited.erosion, 0.0..=0.1).text("erosion")),
+            ui.add(egui::Slider::new(&mut edited.radius, 0..=8).text("radius")),
+            ui.add(egui::Slider::new(&mut edited.max_velocity, 0.1..=10.0).text("max velocity")),
+            ui.add(egui::Slider::new(&mut edited.max_water, 0.1..=1.0).text("max water")),
+        ];
+        if sliders.iter().any(|slider| slider.changed()) {
+            *params = edited;
+        }
+        let mut max_steps = budget.max_steps_per_frame;
+        ui.horizontal(|ui| {
+            let drag = ui.add(
+                egui::DragValue::new(&mut max_steps)
+                    .speed(100.)
+                    .clamp_range(1..=u32::MAX),
+            );
+            ui.label("max steps per frame");
+            if drag.changed() {
+                budget.max_steps_per_frame = max_steps;
+            }
+        });
+        let mut flux = source_brush.flux;
+        if ui
+            .add(egui::Slider::new(&mut flux, 0.001..=1.0).text("painted source flux"))
+            .changed()
+        {
+            source_brush.flux = flux;
+        }
+        if ui.button("reset to defaults").clicked() {
+            *params = ErosionParams::default();
+        }
+    });
+}
+
+fn fog_panel(egui_context: Res<EguiContext>, mut fog: ResMut<FogSettings>) {
+    egui::Window::new("Fog").show(egui_context.ctx(), |ui| {
+        let (mut color, mut density) = (fog.color, fog.density);
+        let picked = ui
+            .horizontal(|ui| {
+                let picked = ui.color_edit_button_rgb(&mut color);
+                ui.label("color");
+                picked
+            })
+            .inner;
+        let slid = ui.add(egui::Slider::new(&mut density, 0.0..=0.01).text("density"));
+        if picked.changed() || slid.changed() {
+            fog.color = color;
+            fog.density = density;
+        }
+    });
+}
+
+fn sun_panel(egui_context: Res<EguiContext>, mut sun: ResMut<Sun>) {
+    egui::Window::new("Sun").show(egui_context.ctx(), |ui| {
+        let (mut azimuth, mut altitude, mut color) = (sun.azimuth, sun.altitude, sun.color);
+        let (mut ambient, mut day_length) = (sun.ambient, sun.day_length);
+        let mut changed = ui
+            .add(egui::Slider::new(&mut azimuth, 0.0..=360.0).text("azimuth"))
+            .changed();
+        changed |= ui
+            .add(egui::Slider::new(&mut altitude, 0.0..=360.0).text("altitude"))
+            .changed();
+        changed |= ui
+            .horizontal(|ui| {
+                let picked = ui.color_edit_button_rgb(&mut color);
+                ui.label("color");
+                picked
+            })
+            .inner
+            .changed();
+        changed |= ui
+            .add(egui::Slider::new(&mut ambient, 0.0..=1.0).text("ambient"))
+            .changed();
+        changed |= ui
+            .add(egui::Slider::new(&mut day_length, 0.0..=120.0).text("day length (s)"))
+            .changed();
+        if changed {
+            sun.azimuth = azimuth;
+            sun.altitude = altitude;
+            sun.color = color;
+            sun.ambient = ambient;
+            sun.day_length = day_length;
+        }
+    });
+}
+
+// the terrain at the last cell clicked
+fn cell_panel(egui_context: Res<EguiContext>, queried: Res<QueriedCell>) {
+    let cell = match &queried.0 {
+        Some(cell) => cell,
+        None => return,
+    };
+    egui::Window::new("Cell").show(egui_context.ctx(), |ui| {
+        ui.label(format!(
+            "cell {} at ({:.1}, {:.1})",
+            cell.index, cell.pos.x, cell.pos.y
+        ));
+        ui.label(format!("height: {:.4}", cell.height));
+        ui.label(format!(
+            "gradient: ({:.4}, {:.4})",
+            cell.grad.x, cell.grad.y
+        ));
+        ui.label(format!("slope: {:.4}", cell.grad.length()));
+    });
+}
+
+fn toggle_hud(keys: Res<Input<KeyCode>>, bindings: Res<KeyBindings>, mut hud: ResMut<Hud>) {
+    if bindings.just_pressed(&keys, Action::ToggleStats) {
+        hud.visible = !hud.visible;
+    }
+}
+
+// performance and activity of the simulation, to see when the erosion settles down
+fn stats_panel(
+    egui_context: Res<EguiContext>,
+    hud: Res<Hud>,
+    diagnostics: Res<Diagnostics>,
+    stats: Res<ErosionStats>,
+    terrain: Res<TerrainConfig>,
+    droplet_budget: Res<DropletBudget>,
+    mesh_stats: Res<MeshStats>,
+    query_droplet: Query<&Droplet>,
+    query_source: Query<&Source>,
+) {
+    if !hud.visible {
+        return;
+    }
+    let fps = diagnostics
+        .get(FrameTimeDiagnosticsPlugin::FPS)
+        .and_then(|fps| fps.average())
+        .unwrap_or(0.);
+    egui::Window::new("Stats").show(egui_context.ctx(), |ui| {
+        ui.label(format!("fps: {:.0}", fps));
+        let droplets = query_droplet.iter().count();
+        let saturated = if droplets >= droplet_budget.max_droplets {
+            " (capped)"
+        } else {
+            ""
+        };
+        ui.label(format!(
+            "droplets: {} / {}{}",
+            droplets, droplet_budget.max_droplets, saturated
+        ));
+        ui.label(format!("sources: {}", query_source.iter().count()));
+        ui.label(format!(
+            "sediment moved: {:.2}",
+            stats.terrain(TerrainId(0)).sediment_moved
+        ));
+        if terrain.compare {
+            ui.label(format!(
+                "compared sediment moved: {:.2}",
+                stats.terrain(TerrainId(1)).sediment_moved
+            ));
+        }
+        ui.label(format!("3D vertices: {}", mesh_stats.vertices));
+    });
+}
+
+pub struct Ui;
+
+impl Plugin for Ui {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_plugin(EguiPlugin)
+            .add_plugin(FrameTimeDiagnosticsPlugin::default())
+            .init_resource::<Hud>()
+            .add_system(erosion_panel.system())
+            .add_system(fog_panel.system())
+            .add_system(sun_panel.system())
+            .add_system(cell_panel.system())
+            .add_system(toggle_hud.system())
+            .add_system(stats_panel.system());
+    }
+}
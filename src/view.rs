@@ -0,0 +1,67 @@
+use crate::input::{Action, KeyBindings};
+use bevy::prelude::*;
+use bevy::render::camera::ActiveCameras;
+use bevy::render::render_graph::base::camera::{CAMERA_2D, CAMERA_3D};
+
+// which of the 2D texture or the 3D mesh is shown, the simulation runs in both
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ViewMode {
+    TwoD,
+    ThreeD,
+}
+
+impl ViewMode {
+    // name of the render graph camera the view is drawn with
+    fn camera(&self) -> &'static str {
+        match self {
+            ViewMode::TwoD => CAMERA_2D,
+            ViewMode::ThreeD => CAMERA_3D,
+        }
+    }
+}
+
+// a camera drawing one of the views, only the cameras of the current view are active
+pub struct ViewCamera(pub ViewMode);
+
+// a camera without a name isn't picked by bevy, and forgetting the active one leaves its pass
+// with nothing to draw until the camera gets its name back
+fn activate_cameras(
+    view: Res<State<ViewMode>>,
+    mut active_cameras: ResMut<ActiveCameras>,
+    mut query: Query<(&ViewCamera, &mut Camera)>,
+) {
+    for (ViewCamera(mode), mut camera) in query.iter_mut() {
+        let active = mode == view.current();
+        if active && camera.name.is_none() {
+            camera.name = Some(mode.camera().to_string());
+        } else if !active && camera.name.is_some() {
+            camera.name = None;
+            active_cameras.add(mode.camera());
+        }
+    }
+}
+
+fn toggle_view(
+    keys: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    mut state: ResMut<State<ViewMode>>,
+) {
+    if bindings.just_pressed(&keys, Action::ToggleView) {
+        let next = match state.current() {
+            ViewMode::TwoD => ViewMode::ThreeD,
+            ViewMode::ThreeD => ViewMode::TwoD,
+        };
+        // fails if a transition is already queued this frame, the next press will go through
+        let _ = state.set(next);
+    }
+}
+
+pub struct View;
+
+impl Plugin for View {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_state(ViewMode::ThreeD)
+            .add_system(toggle_view.system())
+            .add_system(activate_cameras.system());
+    }
+}
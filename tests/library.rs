@@ -0,0 +1,38 @@
+use rand::{rngs::StdRng, SeedableRng};
+use terrain_gen::config::TerrainConfig;
+use terrain_gen::terrain::{erode, Elevation, ErosionParams};
+
+// mean height difference between the cells and their right and bottom neighbors
+fn roughness(elevation: &Elevation) -> f32 {
+    let size = elevation.size();
+    let h = |x: usize, y: usize| elevation.data[x + y * size];
+    let mut total = 0.;
+    for y in 0..size - 1 {
+        for x in 0..size - 1 {
+            total += (h(x + 1, y) - h(x, y)).abs() + (h(x, y + 1) - h(x, y)).abs();
+        }
+    }
+    total / (2 * (size - 1) * (size - 1)) as f32
+}
+
+#[test]
+fn erosion_smooths_noise_terrain() {
+    let config = TerrainConfig::new(32);
+    let noise = config.noise.build(42, &config.fractal);
+    let mut elevation = Elevation::new(&config, noise.as_ref(), 42);
+    let before = roughness(&elevation);
+    erode(
+        &mut elevation,
+        20_000,
+        &ErosionParams::default(),
+        config.sea_level,
+        &mut StdRng::seed_from_u64(42),
+    );
+    let after = roughness(&elevation);
+    assert!(
+        after < before,
+        "roughness went from {} to {}",
+        before,
+        after
+    );
+}